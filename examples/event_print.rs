@@ -1,22 +1,19 @@
+use std::time::Duration;
 use std::{env, thread};
-use tuxdrive::watcher::{WatchEventKind, Watcher};
+use tuxdrive::watcher::Watcher;
 
+/// Prints the events of a directory as JSON lines, for other tools to consume.
+/// `is_dir` tells whether a path is a directory, e.g. to create it before its files.
 fn main() {
     let args = env::args().collect::<Vec<_>>();
-    const POLL_INTERVAL: u64 = 1;
-    let (mut file_watcher, event_recv) = Watcher::<{ POLL_INTERVAL }>::new().unwrap();
+    let poll_interval = Duration::from_secs(1);
+    let (mut file_watcher, event_recv) = Watcher::new(poll_interval).unwrap();
     file_watcher.add_directory(&args[1], true).unwrap();
     thread::spawn(move || file_watcher.start_polling());
     while let Ok(ev) = event_recv.recv() {
-        println!("{},{}", ev.path.display(), event_kind_to_string(ev.kind));
-    }
-}
-
-fn event_kind_to_string(kind: WatchEventKind) -> &'static str {
-    match kind {
-        WatchEventKind::Create => "Create",
-        WatchEventKind::Delete => "Delete",
-        WatchEventKind::Written => "Written",
-        WatchEventKind::Chmod => "Chmod",
+        match serde_json::to_string(&ev) {
+            Ok(line) => println!("{}", line),
+            Err(err) => eprintln!("Skipping event for {}: {}", ev.path.display(), err),
+        }
     }
 }
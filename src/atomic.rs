@@ -1,18 +1,145 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 #[derive(Debug)]
 pub struct AtomicIdGenerator {
-    curr_id: AtomicU32,
+    curr_id: AtomicU64,
 }
 
 impl AtomicIdGenerator {
     pub fn new() -> Self {
         Self {
-            curr_id: AtomicU32::new(1),
+            curr_id: AtomicU64::new(1),
         }
     }
 
-    pub fn next_id(&self) -> u32 {
-        self.curr_id.fetch_add(1, Ordering::SeqCst)
+    /// Ids start at 1, as 0 marks events not tied to a path.
+    /// Past `u64::MAX`, which takes centuries to get to, they start over at 1.
+    pub fn next_id(&self) -> u64 {
+        let id = self.curr_id.fetch_add(1, Ordering::SeqCst);
+        if id == 0 {
+            log::warn!("Event ids wrapped around");
+            return self.next_id();
+        }
+        id
+    }
+}
+
+impl Default for AtomicIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Consumer side of `AtomicIdGenerator`: spots gaps in the ids of received events.
+/// Trees are polled in parallel, so ids can arrive slightly out of order.
+/// A gap may thus get filled by a late event, and `missing` only reports
+/// what is still missing.
+/// Id 0 is not generated (it marks events not tied to a path) and is ignored.
+#[derive(Debug)]
+pub struct SequenceTracker {
+    next_id: u64,
+    /// Start -> end (exclusive) of the gaps seen so far
+    gaps: BTreeMap<u64, u64>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            gaps: BTreeMap::new(),
+        }
+    }
+
+    /// Records a received id.
+    /// Returns the ids newly found missing, if this id skipped over any.
+    pub fn observe(&mut self, id: u64) -> Option<Range<u64>> {
+        if id == 0 {
+            return None;
+        }
+        if id >= self.next_id {
+            let gap = self.next_id..id;
+            self.next_id = id + 1;
+            if gap.is_empty() {
+                return None;
+            }
+            self.gaps.insert(gap.start, gap.end);
+            return Some(gap);
+        }
+        // A late id, it might fill (part of) a gap
+        if let Some((&start, &end)) = self.gaps.range(..=id).next_back() {
+            if id < end {
+                self.gaps.remove(&start);
+                if start < id {
+                    self.gaps.insert(start, id);
+                }
+                if id + 1 < end {
+                    self.gaps.insert(id + 1, end);
+                }
+            }
+        }
+        None
+    }
+
+    /// Ids skipped so far and not received since.
+    pub fn missing(&self) -> impl Iterator<Item = u64> + '_ {
+        self.gaps.iter().flat_map(|(&start, &end)| start..end)
+    }
+
+    pub fn missing_count(&self) -> u64 {
+        self.gaps.iter().map(|(&start, &end)| end - start).sum()
+    }
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn ids_skip_zero_on_wraparound() {
+        let id_gen = AtomicIdGenerator {
+            curr_id: AtomicU64::new(u64::MAX - 1),
+        };
+        let ids = (0..4).map(|_| id_gen.next_id()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![u64::MAX - 1, u64::MAX, 1, 2]);
+    }
+
+    #[test]
+    pub fn no_gaps() {
+        let mut tracker = SequenceTracker::new();
+        for id in 1..10 {
+            assert_eq!(tracker.observe(id), None);
+        }
+        assert_eq!(tracker.missing_count(), 0);
+    }
+
+    #[test]
+    pub fn gap_reported() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(1), None);
+        assert_eq!(tracker.observe(4), Some(2..4));
+        assert_eq!(tracker.observe(0), None);
+        assert_eq!(tracker.observe(5), None);
+        assert_eq!(tracker.missing().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    pub fn late_id_fills_gap() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(6), Some(1..6));
+        assert_eq!(tracker.observe(3), None);
+        assert_eq!(tracker.observe(1), None);
+        assert_eq!(tracker.observe(3), None);
+        assert_eq!(tracker.missing().collect::<Vec<_>>(), vec![2, 4, 5]);
+        assert_eq!(tracker.missing_count(), 3);
     }
 }
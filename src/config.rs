@@ -1,64 +1,438 @@
 use std::{
+    env,
+    ffi::OsString,
+    fmt,
     fs::File,
     io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use serde::Deserialize;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
 
 use crate::error::{TuxDriveError, TuxDriveResult};
+use crate::forest::{depth_below, DirectoryAddOptions};
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
-pub struct Config(Vec<PathConfig>);
+/// The config file is either just the list of paths,
+/// or an object holding the paths along with the other settings.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(remote = "Self", deny_unknown_fields)]
+pub struct Config {
+    paths: Vec<PathConfig>,
+    #[serde(default)]
+    poll_interval_secs: Option<u64>,
+    #[serde(default)]
+    debounce_ms: Option<u64>,
+    #[serde(default)]
+    stream_above_bytes: Option<u64>,
+    #[serde(default)]
+    stream_chunk_bytes: Option<usize>,
+    #[serde(default)]
+    read_on_close: bool,
+    #[serde(default)]
+    channel_capacity: Option<usize>,
+    #[serde(default)]
+    max_file_size_bytes: Option<u64>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    poll_jitter_percent: Option<u32>,
+}
+
+/// Takes either form, so that the errors of the object one name the bad key
+/// (an untagged enum only says that nothing matched)
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConfigVisitor;
+
+        impl<'de> Visitor<'de> for ConfigVisitor {
+            type Value = Config;
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of paths, or an object with the paths and other settings")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Config, A::Error> {
+                let paths = Vec::deserialize(SeqAccessDeserializer::new(seq))?;
+                Ok(Config {
+                    paths,
+                    ..Config::default()
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Config, A::Error> {
+                Config::deserialize(MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer.deserialize_any(ConfigVisitor)
+    }
+}
+
+/// Changes between the old and the new config after a reload.
+/// A path whose `recursive` flag changed shows up in both lists.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub added: Vec<PathConfig>,
+    pub removed: Vec<PathConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct PathConfig {
     path: PathBuf,
+    /// Whether subdirectories are tracked too. Has no effect for a file.
+    #[serde(default)]
     recursive: bool,
+    /// Only files with one of these extensions are tracked (case-sensitive,
+    /// without the leading dot). All files are tracked when absent.
+    #[serde(default)]
+    extensions: Option<Vec<String>>,
+    /// Globs of paths to leave out, see `DirectoryAddOptions::ignore`
+    #[serde(default)]
+    ignore: Option<Vec<String>>,
+    /// Paths in the same group get their events coalesced together
+    #[serde(default)]
+    group: Option<String>,
+    /// Only report that something below the path changed, not what
+    #[serde(default)]
+    summary_only: bool,
+    /// Track what symlinks point to, instead of skipping them
+    #[serde(default)]
+    follow_symlinks: bool,
+    /// How many levels below the path are tracked, all when absent
+    #[serde(default)]
+    max_depth: Option<usize>,
+    /// Leave out what `.gitignore` files below the path exclude
+    #[serde(default)]
+    gitignore: bool,
+    /// Polled this often instead of at the global poll interval
+    #[serde(default)]
+    poll_interval_secs: Option<u64>,
+    /// Report files that were read, see `DirectoryAddOptions::report_access`
+    #[serde(default)]
+    report_access: bool,
+    /// Leave out files and directories whose name starts with a `.`
+    #[serde(default)]
+    skip_hidden: bool,
+    #[serde(default)]
+    track: TrackKind,
+}
+
+/// What is synced for the files below a path
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub enum TrackKind {
+    /// File contents and permissions
+    #[default]
+    ContentAndPerms,
+    /// Permissions, but never the contents
+    PermsOnly,
+    /// Only which files exist
+    Existence,
+}
+
+impl TrackKind {
+    pub fn reads_content(self) -> bool {
+        self == TrackKind::ContentAndPerms
+    }
+
+    pub fn reads_permissions(self) -> bool {
+        self != TrackKind::Existence
+    }
 }
 
 impl Config {
+    /// Reads the first config file found.
+    /// Without a home directory (e.g. as a system service) only the
+    /// system-wide and current directory locations are looked at.
     pub fn read() -> TuxDriveResult<Self> {
-        let home_dir = dirs::home_dir().ok_or(TuxDriveError::HomeDirNotFound)?;
-        let config_dir = dirs::config_dir().ok_or(TuxDriveError::ConfigDirNotFound)?;
-        let config_paths = vec![
-            path![home_dir, ".tuxdrive.json"],
-            path![config_dir, ".tuxdrive.json"],
-            path![config_dir, ".config", "tuxdirve", "tuxdrive.json"],
-            path!["tuxdrive.json"],
-        ];
+        let home_dir = dirs::home_dir();
+        let config_dir = dirs::config_dir();
+        let config_paths = config_paths(home_dir.as_deref(), config_dir.as_deref());
         if let Some(config_path) = config_paths
             .into_iter()
             .find(|path| path.exists() && path.is_file())
         {
-            let file = File::open(&config_path)?;
+            let file =
+                File::open(&config_path).map_err(|err| TuxDriveError::io_at(&config_path, err))?;
             Config::from_reader(file)
+        } else if home_dir.is_none() {
+            Err(TuxDriveError::HomeDirNotFound)
+        } else if config_dir.is_none() {
+            Err(TuxDriveError::ConfigDirNotFound)
         } else {
             Err(TuxDriveError::ConfigFileNotFound)
         }
     }
 
+    /// Reads exactly the config file at `path`, with no fallback
+    pub fn read_from<P: AsRef<Path>>(path: P) -> TuxDriveResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => TuxDriveError::ConfigFileMissing(path.to_path_buf()),
+            _ => TuxDriveError::io_at(path, err),
+        })?;
+        Config::from_reader(file)
+    }
+
     fn from_reader<R: io::Read>(rdr: R) -> TuxDriveResult<Self> {
         let config: Config = serde_json::from_reader(rdr)?;
-        if let Some(path_cfg) = config
-            .0
+        config.validate()
+    }
+
+    /// Builds a config in code, without going through JSON.
+    /// The paths are validated the same way as a config file.
+    pub fn from_paths(paths: Vec<PathConfig>) -> TuxDriveResult<Self> {
+        Config {
+            paths,
+            ..Config::default()
+        }
+        .validate()
+    }
+
+    /// Also expands the paths, and canonicalizes those that exist
+    fn validate(mut self) -> TuxDriveResult<Self> {
+        if self.stream_chunk_bytes == Some(0) {
+            return Err(TuxDriveError::InvalidConfig(
+                "stream_chunk_bytes must not be 0".to_string(),
+            ));
+        }
+        if self.channel_capacity == Some(0) {
+            return Err(TuxDriveError::InvalidConfig(
+                "channel_capacity must not be 0".to_string(),
+            ));
+        }
+        if self
+            .poll_jitter_percent
+            .is_some_and(|percent| percent >= 100)
+        {
+            return Err(TuxDriveError::InvalidConfig(
+                "poll_jitter_percent must be less than 100".to_string(),
+            ));
+        }
+        if self
+            .paths
+            .iter()
+            .any(|path_cfg| path_cfg.poll_interval_secs == Some(0))
+        {
+            return Err(TuxDriveError::InvalidConfig(
+                "poll_interval_secs must not be 0".to_string(),
+            ));
+        }
+        for path_cfg in &mut self.paths {
+            path_cfg.path = expand_path(&path_cfg.path)?;
+        }
+        if let Some(path_cfg) = self
+            .paths
             .iter()
             .find(|path_cfg| !path_cfg.path.is_absolute())
         {
-            Err(TuxDriveError::PathNotAbs(
-                path_cfg.path.display().to_string(),
-            ))
-        } else {
-            Ok(config)
+            return Err(TuxDriveError::PathNotAbs(path_cfg.path.clone()));
         }
+        for path_cfg in &mut self.paths {
+            if let Ok(path) = path_cfg.path.canonicalize() {
+                path_cfg.path = path;
+            }
+        }
+        // Events of a path watched twice would be reported twice
+        for (idx, outer) in self.paths.iter().enumerate() {
+            for (other_idx, inner) in self.paths.iter().enumerate() {
+                if idx != other_idx && outer.tracks(&inner.path) {
+                    return Err(TuxDriveError::OverlappingPaths(
+                        outer.path.clone(),
+                        inner.path.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(self)
     }
 
     pub fn paths(&self) -> &[PathConfig] {
-        &self.0
+        &self.paths
+    }
+
+    /// Time between two poll cycles, if set
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_secs.map(Duration::from_secs)
+    }
+
+    /// Window to collapse bursts of modifications of a path in, if set
+    pub fn debounce(&self) -> Option<Duration> {
+        self.debounce_ms.map(Duration::from_millis)
+    }
+
+    /// Size from which files are streamed in chunks rather than read whole,
+    /// along with the chunk size, if set
+    pub fn streaming(&self) -> Option<(u64, usize)> {
+        let chunk_size = self.stream_chunk_bytes.unwrap_or(DEFAULT_STREAM_CHUNK_SIZE);
+        self.stream_above_bytes
+            .map(|threshold| (threshold, chunk_size))
+    }
+
+    /// Only read a file's content once the writer closes it, rather than on
+    /// every write. Uses inotify to find out about changes.
+    pub fn read_on_close(&self) -> bool {
+        self.read_on_close
+    }
+
+    /// Most events and read commands queued at once, if set.
+    /// Producers wait for room rather than queueing without bound.
+    pub fn channel_capacity(&self) -> Option<usize> {
+        self.channel_capacity
+    }
+
+    /// Files larger than this many bytes are skipped rather than read, if set
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size_bytes
+    }
+
+    /// Fraction of the poll interval the time between polls randomly
+    /// varies by, if set
+    pub fn poll_jitter(&self) -> Option<f64> {
+        self.poll_jitter_percent
+            .map(|percent| f64::from(percent) / 100.0)
     }
+
+    /// Log the reads changes would lead to, instead of doing them
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Sets `dry_run`, e.g. on a config built with `from_paths`
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Re-reads the config from the usual locations.
+    /// The new config is validated in full before anything is swapped in,
+    /// so on error `self` is left untouched.
+    pub fn reload(&mut self) -> TuxDriveResult<ConfigDiff> {
+        let new_config = Config::read()?;
+        Ok(self.replace(new_config))
+    }
+
+    /// Same as `reload`, but reads the new config from `rdr`.
+    pub fn reload_from_reader<R: io::Read>(&mut self, rdr: R) -> TuxDriveResult<ConfigDiff> {
+        let new_config = Config::from_reader(rdr)?;
+        Ok(self.replace(new_config))
+    }
+
+    fn replace(&mut self, new_config: Config) -> ConfigDiff {
+        let added = new_config
+            .paths
+            .iter()
+            .filter(|path_cfg| !self.paths.contains(path_cfg))
+            .cloned()
+            .collect();
+        let removed = self
+            .paths
+            .iter()
+            .filter(|path_cfg| !new_config.paths.contains(path_cfg))
+            .cloned()
+            .collect();
+        *self = new_config;
+        ConfigDiff { added, removed }
+    }
+}
+
+const DEFAULT_STREAM_CHUNK_SIZE: usize = 1 << 20;
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/tuxdrive/tuxdrive.json";
+
+/// Where to look for the config file, in order
+fn config_paths(home_dir: Option<&Path>, config_dir: Option<&Path>) -> Vec<PathBuf> {
+    let mut config_paths = Vec::new();
+    if let Some(home_dir) = home_dir {
+        config_paths.push(path![home_dir, ".tuxdrive.json"]);
+    }
+    if let Some(config_dir) = config_dir {
+        config_paths.push(path![config_dir, ".tuxdrive.json"]);
+        config_paths.push(path![config_dir, ".config", "tuxdirve", "tuxdrive.json"]);
+    }
+    config_paths.push(PathBuf::from(SYSTEM_CONFIG_PATH));
+    config_paths.push(path!["tuxdrive.json"]);
+    config_paths
 }
 
 impl PathConfig {
+    pub fn new<P: AsRef<Path>>(path: P, recursive: bool) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            recursive,
+            extensions: None,
+            ignore: None,
+            group: None,
+            summary_only: false,
+            follow_symlinks: false,
+            max_depth: None,
+            gitignore: false,
+            poll_interval_secs: None,
+            report_access: false,
+            skip_hidden: false,
+            track: TrackKind::default(),
+        }
+    }
+
+    pub fn with_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    pub fn with_ignore(mut self, ignore: Vec<String>) -> Self {
+        self.ignore = Some(ignore);
+        self
+    }
+
+    pub fn with_group(mut self, group: String) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn with_summary_only(mut self, summary_only: bool) -> Self {
+        self.summary_only = summary_only;
+        self
+    }
+
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    pub fn with_poll_interval_secs(mut self, secs: u64) -> Self {
+        self.poll_interval_secs = Some(secs);
+        self
+    }
+
+    pub fn with_report_access(mut self, report_access: bool) -> Self {
+        self.report_access = report_access;
+        self
+    }
+
+    pub fn with_skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.skip_hidden = skip_hidden;
+        self
+    }
+
+    pub fn with_track(mut self, track: TrackKind) -> Self {
+        self.track = track;
+        self
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
@@ -66,6 +440,130 @@ impl PathConfig {
     pub fn recursive(&self) -> bool {
         self.recursive
     }
+
+    /// Whether `path` is watched as part of this one, being it or below it
+    fn tracks(&self, path: &Path) -> bool {
+        if !path.starts_with(&self.path) {
+            return false;
+        }
+        let levels = if self.recursive { usize::MAX } else { 1 };
+        let levels = self
+            .max_depth
+            .map_or(levels, |max_depth| levels.min(max_depth));
+        depth_below(&self.path, path) <= levels
+    }
+
+    pub fn extensions(&self) -> Option<&[String]> {
+        self.extensions.as_deref()
+    }
+
+    pub fn ignore(&self) -> Option<&[String]> {
+        self.ignore.as_deref()
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn summary_only(&self) -> bool {
+        self.summary_only
+    }
+
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn report_access(&self) -> bool {
+        self.report_access
+    }
+
+    pub fn gitignore(&self) -> bool {
+        self.gitignore
+    }
+
+    pub fn skip_hidden(&self) -> bool {
+        self.skip_hidden
+    }
+
+    pub fn track(&self) -> TrackKind {
+        self.track
+    }
+
+    /// The options to add the path to a watcher or forest with
+    pub fn add_options(&self) -> TuxDriveResult<DirectoryAddOptions> {
+        let mut options = DirectoryAddOptions::new();
+        if let Some(extensions) = self.extensions() {
+            options = options.extensions(extensions.to_vec());
+        }
+        if let Some(ignore) = self.ignore() {
+            options = options.ignore(ignore)?;
+        }
+        if let Some(group) = self.group() {
+            options = options.group(group);
+        }
+        if let Some(max_depth) = self.max_depth() {
+            options = options.max_depth(max_depth);
+        }
+        if let Some(interval) = self.poll_interval() {
+            options = options.poll_interval(interval);
+        }
+        Ok(options
+            .summary_only(self.summary_only())
+            .follow_symlinks(self.follow_symlinks())
+            .gitignore(self.gitignore())
+            .report_access(self.report_access())
+            .skip_hidden(self.skip_hidden()))
+    }
+}
+
+/// Expands a leading `~` to the home directory, and `$VAR` or `${VAR}`
+/// to the value of the environment variable
+fn expand_path(path: &Path) -> TuxDriveResult<PathBuf> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut expanded = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    if rest == b"~" || rest.starts_with(b"~/") {
+        let home_dir = dirs::home_dir().ok_or(TuxDriveError::HomeDirNotFound)?;
+        expanded.extend_from_slice(home_dir.as_os_str().as_bytes());
+        rest = &rest[1..];
+    }
+    while let Some(pos) = rest.iter().position(|&byte| byte == b'$') {
+        expanded.extend_from_slice(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let (name, after) = match rest.strip_prefix(b"{") {
+            Some(braced) => match braced.iter().position(|&byte| byte == b'}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => (&braced[..0], rest),
+            },
+            None => {
+                let end = rest
+                    .iter()
+                    .position(|&byte| !(byte.is_ascii_alphanumeric() || byte == b'_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        if name.is_empty() {
+            // Not a variable after all
+            expanded.push(b'$');
+            continue;
+        }
+        let name = String::from_utf8_lossy(name).into_owned();
+        let value = env::var_os(&name)
+            .ok_or_else(|| TuxDriveError::UnsetVariable(name, path.to_path_buf()))?;
+        expanded.extend_from_slice(value.as_bytes());
+        rest = after;
+    }
+    expanded.extend_from_slice(rest);
+    Ok(PathBuf::from(OsString::from_vec(expanded)))
 }
 
 #[cfg(test)]
@@ -89,17 +587,13 @@ mod test {
 ]
 "#;
         let config = Config::from_reader(Cursor::new(config_text)).unwrap();
-        let expected_config = Config(vec![
-            PathConfig {
-                path: PathBuf::from("/home/foo/rec_dir"),
-                recursive: true,
-            },
-            PathConfig {
-                path: PathBuf::from("/home/foo/non_rec_dir"),
-                recursive: false,
-            },
-        ]);
+        let expected_config = Config::from_paths(vec![
+            PathConfig::new("/home/foo/rec_dir", true),
+            PathConfig::new("/home/foo/non_rec_dir", false),
+        ])
+        .unwrap();
         assert_eq!(config, expected_config);
+        assert_eq!(config.poll_interval(), None);
     }
 
     #[test]
@@ -119,6 +613,22 @@ mod test {
         assert!(config.is_err());
     }
 
+    #[test]
+    pub fn test_unknown_key() {
+        let config_text = r#"
+{
+    "paths": [{ "path": "/home/foo/rec_dir", "recursive": true }],
+    "poll_intervall_secs": 5
+}
+"#;
+        let err = Config::from_reader(Cursor::new(config_text)).unwrap_err();
+        assert!(err.to_string().contains("poll_intervall_secs"), "{}", err);
+
+        let config_text = r#"[{ "path": "/home/foo/rec_dir", "recursve": true }]"#;
+        let err = Config::from_reader(Cursor::new(config_text)).unwrap_err();
+        assert!(err.to_string().contains("recursve"), "{}", err);
+    }
+
     #[test]
     pub fn test_no_recursive() {
         let config_text = r#"
@@ -127,13 +637,147 @@ mod test {
         "path": "/home/foo/rec_dir",
         "recursive": true
     },
+    {
+        "path": "/home/foo/.bashrc"
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        assert!(!config.paths()[1].recursive());
+    }
+
+    #[test]
+    pub fn test_nested_recursive_paths() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/projects/tuxdrive",
+        "recursive": false
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text));
+        assert!(matches!(
+            config,
+            Err(TuxDriveError::OverlappingPaths(outer, inner))
+                if outer == Path::new("/home/foo")
+                    && inner == Path::new("/home/foo/projects/tuxdrive")
+        ));
+    }
+
+    #[test]
+    pub fn test_non_overlapping_paths() {
+        let config_text = r#"
+[
     {
         "path": "/home/foo/rec_dir",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/rec_dir_2",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/non_rec_dir",
+        "recursive": false
+    },
+    {
+        "path": "/home/foo/non_rec_dir/sub/deeper",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/shallow",
+        "recursive": true,
+        "max_depth": 1
+    },
+    {
+        "path": "/home/foo/shallow/sub/deeper",
+        "recursive": true
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        assert_eq!(config.paths().len(), 6);
+    }
+
+    #[test]
+    pub fn test_duplicate_paths() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo/non_rec_dir",
+        "recursive": false
+    },
+    {
+        "path": "/home/foo/non_rec_dir/",
+        "recursive": false
     }
 ]
 "#;
         let config = Config::from_reader(Cursor::new(config_text));
-        assert!(config.is_err());
+        assert!(matches!(config, Err(TuxDriveError::OverlappingPaths(..))));
+    }
+
+    #[test]
+    pub fn test_tilde_expansion() {
+        let config_text = r#"[{ "path": "~/tuxdrive-test-projects", "recursive": true }]"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        assert_eq!(
+            config.paths()[0].path(),
+            dirs::home_dir().unwrap().join("tuxdrive-test-projects")
+        );
+    }
+
+    #[test]
+    pub fn test_variable_expansion() {
+        env::set_var("TUXDRIVE_TEST_ROOT", "/home/foo");
+        let config_text = r#"
+[
+    { "path": "$TUXDRIVE_TEST_ROOT/rec_dir", "recursive": true },
+    { "path": "${TUXDRIVE_TEST_ROOT}_other/dir", "recursive": true },
+    { "path": "/home/foo/$/price", "recursive": true }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        let paths = config
+            .paths()
+            .iter()
+            .map(|path_cfg| path_cfg.path())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                Path::new("/home/foo/rec_dir"),
+                Path::new("/home/foo_other/dir"),
+                Path::new("/home/foo/$/price"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_unset_variable() {
+        let config_text = r#"[{ "path": "$TUXDRIVE_TEST_UNSET/dir", "recursive": true }]"#;
+        let config = Config::from_reader(Cursor::new(config_text));
+        assert!(matches!(
+            config,
+            Err(TuxDriveError::UnsetVariable(name, _)) if name == "TUXDRIVE_TEST_UNSET"
+        ));
+    }
+
+    #[test]
+    pub fn test_read_from_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.json");
+        assert!(matches!(
+            Config::read_from(&path),
+            Err(TuxDriveError::ConfigFileMissing(missing)) if missing == path
+        ));
+        std::fs::write(&path, r#"[{ "path": "/home/foo/rec_dir" }]"#).unwrap();
+        assert_eq!(Config::read_from(&path).unwrap().paths().len(), 1);
     }
 
     #[test]
@@ -150,4 +794,243 @@ mod test {
         assert!(config.is_err());
         assert!(matches!(config, Err(TuxDriveError::PathNotAbs(_))));
     }
+
+    #[test]
+    pub fn test_reload_diff() {
+        let old_text = r#"
+[
+    {
+        "path": "/home/foo/rec_dir",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/non_rec_dir",
+        "recursive": false
+    }
+]
+"#;
+        let new_text = r#"
+[
+    {
+        "path": "/home/foo/rec_dir",
+        "recursive": true
+    },
+    {
+        "path": "/home/foo/new_dir",
+        "recursive": false
+    }
+]
+"#;
+        let mut config = Config::from_reader(Cursor::new(old_text)).unwrap();
+        let diff = config.reload_from_reader(Cursor::new(new_text)).unwrap();
+        let expected_diff = ConfigDiff {
+            added: vec![PathConfig::new("/home/foo/new_dir", false)],
+            removed: vec![PathConfig::new("/home/foo/non_rec_dir", false)],
+        };
+        assert_eq!(diff, expected_diff);
+        assert_eq!(config, Config::from_reader(Cursor::new(new_text)).unwrap());
+    }
+
+    #[test]
+    pub fn test_reload_invalid_keeps_old() {
+        let old_text = r#"
+[
+    {
+        "path": "/home/foo/rec_dir",
+        "recursive": true
+    }
+]
+"#;
+        let new_text = r#"
+[
+    {
+        "path": "/home/foo/other_dir",
+        "recursive": true
+    },
+    {
+        "path": "foo/rec_dir",
+        "recursive": true
+    }
+]
+"#;
+        let mut config = Config::from_reader(Cursor::new(old_text)).unwrap();
+        let res = config.reload_from_reader(Cursor::new(new_text));
+        assert!(matches!(res, Err(TuxDriveError::PathNotAbs(_))));
+        assert_eq!(config, Config::from_reader(Cursor::new(old_text)).unwrap());
+    }
+
+    #[test]
+    pub fn test_from_paths() {
+        let config = Config::from_paths(vec![
+            PathConfig::new("/home/foo/rec_dir", true),
+            PathConfig::new("/home/foo/non_rec_dir", false),
+        ])
+        .unwrap();
+        assert_eq!(config.paths().len(), 2);
+        assert_eq!(config.paths()[0].path(), Path::new("/home/foo/rec_dir"));
+        assert!(!config.paths()[1].recursive());
+        assert!(!config.dry_run());
+        assert!(config.with_dry_run(true).dry_run());
+
+        let config = Config::from_paths(vec![PathConfig::new("foo/rec_dir", true)]);
+        assert!(matches!(config, Err(TuxDriveError::PathNotAbs(_))));
+    }
+
+    #[test]
+    pub fn test_extensions() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo/logs",
+        "recursive": true,
+        "extensions": ["log", "txt"]
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        let expected_config = Config::from_paths(vec![PathConfig::new("/home/foo/logs", true)
+            .with_extensions(vec![String::from("log"), String::from("txt")])])
+        .unwrap();
+        assert_eq!(config, expected_config);
+        assert_eq!(
+            config.paths()[0].extensions(),
+            Some(&[String::from("log"), String::from("txt")][..])
+        );
+    }
+
+    #[test]
+    pub fn test_path_setters() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo/src",
+        "recursive": true,
+        "group": "code",
+        "summary_only": true,
+        "follow_symlinks": true,
+        "max_depth": 3,
+        "gitignore": true,
+        "poll_interval_secs": 5,
+        "report_access": true,
+        "skip_hidden": true,
+        "track": "PermsOnly"
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        let path_config = PathConfig::new("/home/foo/src", true)
+            .with_group(String::from("code"))
+            .with_summary_only(true)
+            .with_follow_symlinks(true)
+            .with_max_depth(3)
+            .with_gitignore(true)
+            .with_poll_interval_secs(5)
+            .with_report_access(true)
+            .with_skip_hidden(true)
+            .with_track(TrackKind::PermsOnly);
+        assert_eq!(config.paths(), &[path_config]);
+    }
+
+    #[test]
+    pub fn test_object_form() {
+        let config_text = r#"
+{
+    "poll_interval_secs": 10,
+    "debounce_ms": 500,
+    "stream_above_bytes": 1048576,
+    "channel_capacity": 1000,
+    "max_file_size_bytes": 1073741824,
+    "dry_run": true,
+    "poll_jitter_percent": 10,
+    "paths": [
+        {
+            "path": "/home/foo/rec_dir",
+            "recursive": true
+        }
+    ]
+}
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        assert_eq!(config.poll_interval(), Some(Duration::from_secs(10)));
+        assert_eq!(config.debounce(), Some(Duration::from_millis(500)));
+        assert_eq!(
+            config.streaming(),
+            Some((1 << 20, DEFAULT_STREAM_CHUNK_SIZE))
+        );
+        assert_eq!(config.channel_capacity(), Some(1000));
+        assert_eq!(config.max_file_size(), Some(1 << 30));
+        assert!(config.dry_run());
+        assert_eq!(config.poll_jitter(), Some(0.1));
+        assert_eq!(
+            config.paths(),
+            &[PathConfig::new("/home/foo/rec_dir", true)]
+        );
+    }
+
+    #[test]
+    pub fn test_path_poll_interval() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo/busy",
+        "recursive": true,
+        "poll_interval_secs": 1
+    },
+    {
+        "path": "/home/foo/quiet",
+        "recursive": true
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        assert_eq!(
+            config.paths()[0].poll_interval(),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(config.paths()[1].poll_interval(), None);
+
+        let zero_text =
+            config_text.replace("\"poll_interval_secs\": 1", "\"poll_interval_secs\": 0");
+        assert!(matches!(
+            Config::from_reader(Cursor::new(zero_text)),
+            Err(TuxDriveError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_track() {
+        let config_text = r#"
+[
+    {
+        "path": "/home/foo/secrets",
+        "recursive": true,
+        "track": "PermsOnly"
+    }
+]
+"#;
+        let config = Config::from_reader(Cursor::new(config_text)).unwrap();
+        let track = config.paths()[0].track();
+        assert_eq!(track, TrackKind::PermsOnly);
+        assert!(!track.reads_content());
+        assert!(track.reads_permissions());
+    }
+
+    #[test]
+    pub fn system_config_without_home() {
+        let system_paths = config_paths(None, None);
+        assert_eq!(
+            system_paths,
+            vec![
+                PathBuf::from("/etc/tuxdrive/tuxdrive.json"),
+                PathBuf::from("tuxdrive.json")
+            ]
+        );
+        let user_paths = config_paths(Some(Path::new("/home/foo")), Some(Path::new("/cfg")));
+        assert_eq!(user_paths[0], Path::new("/home/foo/.tuxdrive.json"));
+        assert_eq!(
+            user_paths[3],
+            Path::new("/etc/tuxdrive/tuxdrive.json"),
+            "the user's config comes first"
+        );
+    }
 }
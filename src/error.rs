@@ -1,11 +1,17 @@
 use std::io;
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
+use crate::display_path;
+
 #[derive(Debug, Error)]
 pub enum TuxDriveError {
-    #[error("{0} is not a directory")]
-    NotDirectory(String),
+    #[error("{} is not a directory", display_path(.0))]
+    NotDirectory(PathBuf),
+
+    #[error("{} is not a regular file", display_path(.0))]
+    NotFile(PathBuf),
 
     #[error("Home directory not found")]
     HomeDirNotFound,
@@ -16,20 +22,112 @@ pub enum TuxDriveError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 
+    #[error("IO error on {}: {source}", display_path(path))]
+    IoAt { path: PathBuf, source: io::Error },
+
     #[error("Failed to parse config: {0}")]
     DeserializeFailed(#[from] serde_json::Error),
 
     #[error("Failed to find config file")]
     ConfigFileNotFound,
 
-    #[error("{0} is not an absolute path")]
-    PathNotAbs(String),
+    #[error("Config file {} not found", display_path(.0))]
+    ConfigFileMissing(PathBuf),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("{} is not an absolute path", display_path(.0))]
+    PathNotAbs(PathBuf),
+
+    #[error("Environment variable {0} used in {} is not set", display_path(.1))]
+    UnsetVariable(String, PathBuf),
+
+    #[error("{} is already watched as part of {}", display_path(.1), display_path(.0))]
+    OverlappingPaths(PathBuf, PathBuf),
 
     #[error("Platform error: {0}")]
     NixError(#[from] nix::errno::Errno),
 
+    #[error("Platform error on {}: {source}", display_path(path))]
+    NixAt {
+        path: PathBuf,
+        source: nix::errno::Errno,
+    },
+
     #[error("Thread pool build error: {0}")]
     ThreadPoolBuildError(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("Timed out waiting for {} to respond", display_path(.0))]
+    ProbeTimeout(PathBuf),
+
+    #[error("Not enough free space to write {}", display_path(.0))]
+    InsufficientSpace(PathBuf),
+
+    #[error("{} cannot be read", display_path(.0))]
+    UnreadableFile(PathBuf),
+
+    #[error("Channel closed: the receiving end was dropped")]
+    ChannelClosed,
+
+    #[error("Invalid block size {0}")]
+    InvalidBlockSize(usize),
+
+    #[error("Failed to parse saved state {}: {1}", display_path(.0))]
+    CorruptState(PathBuf, serde_json::Error),
+
+    #[error("Invalid glob pattern: {0}")]
+    InvalidGlob(#[from] globset::Error),
+}
+
+impl TuxDriveError {
+    /// `err`, which happened on `path`
+    pub(crate) fn io_at<P: AsRef<Path>>(path: P, err: io::Error) -> Self {
+        Self::IoAt {
+            path: path.as_ref().to_path_buf(),
+            source: err,
+        }
+    }
+
+    /// `err`, which happened on `path`
+    pub(crate) fn nix_at<P: AsRef<Path>>(path: P, err: nix::errno::Errno) -> Self {
+        Self::NixAt {
+            path: path.as_ref().to_path_buf(),
+            source: err,
+        }
+    }
 }
 
 pub type TuxDriveResult<T> = Result<T, TuxDriveError>;
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    pub fn non_utf8_path_kept() {
+        let path = PathBuf::from(OsStr::from_bytes(b"/tmp/caf\xe9"));
+        let err = TuxDriveError::NotDirectory(path.clone());
+        assert_eq!(err.to_string(), "/tmp/caf\u{fffd} is not a directory");
+        assert!(matches!(err, TuxDriveError::NotDirectory(err_path) if err_path == path));
+    }
+
+    #[test]
+    pub fn errors_name_their_path() {
+        let err = TuxDriveError::io_at(
+            "/srv/data/locked",
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        assert_eq!(
+            err.to_string(),
+            "IO error on /srv/data/locked: permission denied"
+        );
+        let err = TuxDriveError::nix_at("/srv/data/gone", nix::errno::Errno::ESTALE);
+        assert!(err
+            .to_string()
+            .starts_with("Platform error on /srv/data/gone: ESTALE"));
+    }
+}
@@ -1,24 +1,45 @@
 use std::{
-    collections::{HashMap, HashSet},
-    ffi::OsString,
-    io::ErrorKind,
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, ErrorKind},
+    mem,
+    os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use crate::error::TuxDriveResult;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use nix::errno::Errno;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 
+use crate::display_path;
+use crate::error::{TuxDriveError, TuxDriveResult};
+
+use self::gitignore::GitIgnores;
+use self::info::{new_info, NodeInfo};
+use self::intern::NamePool;
+
+mod gitignore;
 pub mod info;
+mod intern;
+mod vfs;
 
 #[derive(Debug)]
 pub struct PathTree<T> {
     parent_path: Option<PathBuf>,
     node: PathNode<T>,
+    names: NamePool,
+    options: DirectoryAddOptions,
 }
 
 #[derive(Debug)]
 struct PathNode<T> {
-    name: Option<OsString>,
-    children: HashMap<OsString, PathNode<T>>,
+    name: Option<Arc<OsStr>>,
+    children: HashMap<Arc<OsStr>, PathNode<T>>,
     info: T,
     is_dir: bool,
 }
@@ -27,20 +48,63 @@ struct PathNode<T> {
 pub struct PathForest<T> {
     /// Map from root path to corresponding tree
     trees: HashMap<PathBuf, PathTree<T>>,
+    names: NamePool,
 }
 
 impl<T> PathForest<T> {
     pub fn new() -> Self {
         Self {
             trees: HashMap::new(),
+            names: NamePool::new(),
+        }
+    }
+
+    /// Low-memory mode: path component names are interned,
+    /// so a name repeated all over the forest is stored only once.
+    pub fn with_interning() -> Self {
+        Self {
+            trees: HashMap::new(),
+            names: NamePool::interning(),
         }
     }
+
+    /// An empty forest, whose trees can be moved into this one
+    /// with `insert_tree` without losing the interning of their names
+    pub(crate) fn sharing_names(&self) -> Self {
+        Self {
+            trees: HashMap::new(),
+            names: self.names.clone(),
+        }
+    }
+}
+
+impl<T> Default for PathForest<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DirectoryAddOptions {
     ignore_not_found: bool,
     ignore_no_access: bool,
+    follow_symlinks: bool,
+    /// Unset until given, so that the watcher's setting only fills it in
+    skip_virtual_fs: Option<bool>,
+    extensions: Option<Arc<[String]>>,
+    ignore: Option<Arc<GlobSet>>,
+    /// Shared by the clones, so that the watcher sees what the scan read
+    gitignores: Option<Arc<GitIgnores>>,
+    group: Option<Arc<str>>,
+    summary_only: bool,
+    case_collisions: bool,
+    fifos: bool,
+    max_depth: Option<usize>,
+    poll_interval: Option<Duration>,
+    report_access: bool,
+    report_access_regained: bool,
+    skip_hidden: bool,
+    skip_unchanged_writes: bool,
 }
 
 impl DirectoryAddOptions {
@@ -48,33 +112,317 @@ impl DirectoryAddOptions {
         Self {
             ignore_not_found: true,
             ignore_no_access: true,
+            follow_symlinks: false,
+            skip_virtual_fs: None,
+            extensions: None,
+            ignore: None,
+            gitignores: None,
+            group: None,
+            summary_only: false,
+            case_collisions: false,
+            fifos: false,
+            max_depth: None,
+            poll_interval: None,
+            report_access: false,
+            report_access_regained: true,
+            skip_hidden: false,
+            skip_unchanged_writes: false,
         }
     }
+
+    /// Track what symlinks point to instead of skipping them.
+    /// A symlink to a directory already descended into (e.g. one above it)
+    /// is skipped, so that loops end.
+    /// Default: false
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    pub(crate) fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Don't descend into virtual filesystems like /proc and /sys.
+    /// The added directory itself is never skipped.
+    /// Default: true, or as set with `WatcherBuilder::skip_virtual_filesystems`
+    pub fn skip_virtual_fs(mut self, skip: bool) -> Self {
+        self.skip_virtual_fs = Some(skip);
+        self
+    }
+
+    /// Like `skip_virtual_fs`, unless it was set already
+    pub(crate) fn skip_virtual_fs_unless_set(mut self, skip: bool) -> Self {
+        self.skip_virtual_fs.get_or_insert(skip);
+        self
+    }
+
+    /// Skip files and directories whose name starts with a `.`, along with
+    /// everything below them. The added directory itself is never skipped.
+    /// Default: false
+    pub fn skip_hidden(mut self, skip: bool) -> Self {
+        self.skip_hidden = skip;
+        self
+    }
+
+    /// Whether the directory at `path` is not to be descended into
+    pub(crate) fn skips_dir(&self, path: &Path) -> bool {
+        self.skip_virtual_fs.unwrap_or(true) && vfs::is_virtual_fs(path)
+    }
+
+    /// Only track files with one of these extensions (given without the
+    /// leading dot). Directories are always traversed.
+    /// The match is case-sensitive: "log" doesn't match "app.LOG".
+    pub fn extensions(mut self, extensions: Vec<String>) -> Self {
+        self.extensions = Some(extensions.into());
+        self
+    }
+
+    /// Name of the group the directory belongs to. Only used by the watcher:
+    /// with `OverflowPolicy::Coalesce`, events of all directories in a group
+    /// are held back and coalesced together.
+    pub fn group(mut self, group: &str) -> Self {
+        self.group = Some(Arc::from(group));
+        self
+    }
+
+    pub(crate) fn group_name(&self) -> Option<&Arc<str>> {
+        self.group.as_ref()
+    }
+
+    /// Only used by the watcher: instead of tracking every path, only report
+    /// that something in the directory changed, with one `RootChanged` event
+    /// per poll cycle. Much cheaper for huge trees.
+    pub fn summary_only(mut self, summary_only: bool) -> Self {
+        self.summary_only = summary_only;
+        self
+    }
+
+    pub(crate) fn is_summary_only(&self) -> bool {
+        self.summary_only
+    }
+
+    /// Only used by the watcher: report new entries whose name only differs
+    /// by case from another one in the same directory, as they would clash
+    /// when synced to a case-insensitive target.
+    /// Default: false
+    pub fn detect_case_collisions(mut self, detect: bool) -> Self {
+        self.case_collisions = detect;
+        self
+    }
+
+    pub(crate) fn detects_case_collisions(&self) -> bool {
+        self.case_collisions
+    }
+
+    /// Also track named pipes, like files. The watcher reports a `Written`
+    /// when data is written to one, but a pipe must not be read to find out
+    /// what: reading blocks, and takes the data from its actual reader.
+    /// Default: false
+    pub fn track_fifos(mut self, track: bool) -> Self {
+        self.fifos = track;
+        self
+    }
+
+    /// Only used by the polling watcher: report files that were read but
+    /// are otherwise unchanged, going by their atime, with an `Accessed` event.
+    /// Never fires on `noatime` mounts. On `relatime` mounts, the usual
+    /// default, the atime only moves on the first read after a change or
+    /// once a day, so this fires rarely. Reads by the `FileReader` count too.
+    /// Default: false
+    pub fn report_access(mut self, report: bool) -> Self {
+        self.report_access = report;
+        self
+    }
+
+    pub(crate) fn reports_access(&self) -> bool {
+        self.report_access
+    }
+
+    /// Only used by the polling watcher: report an `AccessRegained` when a
+    /// path that had become inaccessible can be looked at again. Whatever
+    /// appeared below it meanwhile is reported as created either way.
+    /// Default: true
+    pub fn report_access_regained(mut self, report: bool) -> Self {
+        self.report_access_regained = report;
+        self
+    }
+
+    pub(crate) fn reports_access_regained(&self) -> bool {
+        self.report_access_regained
+    }
+
+    /// Only used by the polling watcher: keep a hash of each file's content,
+    /// and leave out the `Written` for a file rewritten with the same content,
+    /// as build tools often do. Every file is read when the directory is
+    /// added, and again on each change, so this costs IO.
+    /// Default: false
+    pub fn skip_unchanged_writes(mut self, skip: bool) -> Self {
+        self.skip_unchanged_writes = skip;
+        self
+    }
+
+    pub(crate) fn skips_unchanged_writes(&self) -> bool {
+        self.skip_unchanged_writes
+    }
+
+    /// Only track entries up to `max_depth` levels below the added directory:
+    /// 1 is its direct entries, like a non-recursive add.
+    /// Directories at the limit are tracked, but not what they contain.
+    /// Default: no limit
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only used by the polling watcher: poll the directory every `interval`
+    /// instead of at the watcher's poll interval, with every check each time.
+    /// Has no effect on summary-only directories.
+    /// Default: the watcher's poll interval
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    pub(crate) fn own_poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    /// Whether entries `depth` levels below the added directory are tracked
+    pub(crate) fn allows_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth <= max_depth)
+    }
+
+    /// Whether entries of this type are tracked at all
+    pub(crate) fn tracks_file_type(&self, file_type: fs::FileType) -> bool {
+        use std::os::unix::fs::FileTypeExt;
+
+        file_type.is_dir() || file_type.is_file() || (self.fifos && file_type.is_fifo())
+    }
+
+    /// Skip files and directories matching any of these globs, along with
+    /// everything below them. A glob is matched against the path relative
+    /// to the added directory and against the entry's own name, so both
+    /// `node_modules` and `build/*.o` work.
+    pub fn ignore<S: AsRef<str>>(mut self, patterns: &[S]) -> TuxDriveResult<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern.as_ref())?);
+        }
+        self.ignore = Some(Arc::new(builder.build()?));
+        Ok(self)
+    }
+
+    /// Also skip what the `.gitignore` files at or below the added
+    /// directory exclude, following git's rules: patterns are relative to
+    /// the directory of their file, deeper files take precedence, and `!`
+    /// re-includes. `.gitignore` files above the added directory are not read.
+    /// The watcher picks up `.gitignore` files created or changed later on,
+    /// for the paths it finds from then on.
+    /// Default: false
+    pub fn gitignore(mut self, honor: bool) -> Self {
+        self.gitignores = honor.then(Default::default);
+        self
+    }
+
+    /// Reads the `.gitignore` in `dir`, if honoring them, before its entries
+    /// are checked with `is_ignored`
+    pub(crate) fn load_gitignore(&self, dir: &Path) {
+        if let Some(gitignores) = &self.gitignores {
+            gitignores.load(dir);
+        }
+    }
+
+    /// Whether `path`, somewhere below `root_path`, is hidden and hidden
+    /// entries are skipped, matches an ignore glob or is excluded by a `.gitignore`
+    pub(crate) fn is_ignored(&self, root_path: &Path, path: &Path, is_dir: bool) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+
+        if self.skip_hidden
+            && path
+                .file_name()
+                .is_some_and(|name| name.as_bytes().starts_with(b"."))
+        {
+            return true;
+        }
+        if let Some(ignore) = &self.ignore {
+            let relative = path.strip_prefix(root_path).unwrap_or(path);
+            if ignore.is_match(relative)
+                || path.file_name().is_some_and(|name| ignore.is_match(name))
+            {
+                return true;
+            }
+        }
+        self.gitignores
+            .as_ref()
+            .is_some_and(|gitignores| gitignores.is_ignored(root_path, path, is_dir))
+    }
+
+    /// Whether a file at `path` passes the extension allowlist
+    pub(crate) fn tracks_file(&self, path: &Path) -> bool {
+        match (&self.extensions, path.extension()) {
+            (None, _) => true,
+            (Some(extensions), Some(ext)) => {
+                extensions.iter().any(|allowed| ext == allowed.as_str())
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    fn is_ignorable(&self, err: &io::Error) -> bool {
+        (self.ignore_not_found && err.kind() == ErrorKind::NotFound)
+            || (self.ignore_no_access && err.kind() == ErrorKind::PermissionDenied)
+    }
+}
+
+impl Default for DirectoryAddOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> PathForest<T>
 where
-    T: Default,
+    T: NodeInfo,
 {
     pub fn add_path<P: AsRef<Path>>(&mut self, root_path: P, path: P, info: T, is_dir: bool) {
         let root_path = root_path.as_ref();
         if let Some(tree) = self.trees.get_mut(root_path) {
             tree.add_path(path, info, is_dir);
         } else {
-            let mut new_tree = PathTree::new(root_path);
+            let mut new_tree = PathTree::new(root_path, self.names.clone());
             new_tree.add_path(path, info, is_dir);
             self.trees.insert(PathBuf::from(root_path), new_tree);
         }
     }
 
     /// root_path: Must belong to tree
+    /// Removing the root path itself removes the whole tree.
     pub fn remove_path<P: AsRef<Path>>(&mut self, root_path: P, path: P) -> bool {
+        if root_path.as_ref() == path.as_ref() {
+            let removed = self.trees.remove(root_path.as_ref()).is_some();
+            self.names.prune();
+            return removed;
+        }
         self.trees
             .get_mut(root_path.as_ref())
             .unwrap()
             .remove_path(path)
     }
 
+    /// Removes the directories that are gone from disk and have no tracked
+    /// entries left, along with parents left empty by that. Directories
+    /// still on disk are kept, empty or not. Returns how many were removed.
+    pub fn prune_empty(&mut self) -> usize {
+        let mut pruned = self.trees.values_mut().map(|tree| tree.prune_empty()).sum();
+        let trees_before = self.trees.len();
+        self.trees.retain(|root_path, tree| {
+            !(tree.node.is_dir && tree.node.children.is_empty() && is_gone(root_path))
+        });
+        pruned += trees_before - self.trees.len();
+        pruned
+    }
+
     pub fn add_dir_recursively<P: AsRef<Path>>(
         &mut self,
         dir_path: P,
@@ -82,128 +430,225 @@ where
     ) -> TuxDriveResult<()> {
         let dir_path = dir_path.as_ref();
         assert!(dir_path.is_dir());
-        self.add_path(dir_path, dir_path, T::default(), true);
-        match self.add_dir_rec_intern(dir_path, dir_path, options)? {
-            RecursiveBehaviour::Nothing => {}
-            RecursiveBehaviour::Delete => {
-                self.remove_path(dir_path, dir_path);
-            }
-        };
-        Ok(())
+        self.add_path(dir_path, dir_path, new_info(dir_path, true), true);
+        self.set_tree_options(dir_path, &options);
+        let mut visited = HashSet::new();
+        if options.follow_symlinks {
+            visited.insert(file_id(&dir_path.metadata()?));
+        }
+        self.add_dir_rec_intern(dir_path, &options, &mut visited)
     }
 
-    pub fn add_dir_non_recursively<P: AsRef<Path>>(&mut self, dir_path: P) -> TuxDriveResult<()> {
+    pub fn add_dir_non_recursively<P: AsRef<Path>>(
+        &mut self,
+        dir_path: P,
+        options: DirectoryAddOptions,
+    ) -> TuxDriveResult<()> {
         let dir_path = dir_path.as_ref();
         assert!(dir_path.is_dir());
         let entries = match dir_path.read_dir() {
             Ok(v) => {
-                self.add_path(dir_path, dir_path, T::default(), true);
+                self.add_path(dir_path, dir_path, new_info(dir_path, true), true);
+                self.set_tree_options(dir_path, &options);
+                options.load_gitignore(dir_path);
                 v
             }
             Err(err) => {
-                if err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied {
+                if options.is_ignorable(&err) {
                     return Ok(());
                 } else {
-                    return Err(err.into());
+                    return Err(TuxDriveError::io_at(dir_path, err));
                 }
             }
         };
+        if !options.allows_depth(1) {
+            return Ok(());
+        }
         for entry in entries {
             let entry = match entry {
                 Ok(v) => v,
                 Err(err) => {
-                    if err.kind() == ErrorKind::NotFound
-                        || err.kind() == ErrorKind::PermissionDenied
-                    {
+                    if options.is_ignorable(&err) {
                         continue;
                     } else {
-                        return Err(err.into());
+                        return Err(TuxDriveError::io_at(dir_path, err));
                     }
                 }
             };
-            let is_dir = match entry.file_type() {
+            let file_type = match entry.file_type() {
+                Ok(v) if v.is_symlink() && options.follow_symlinks => {
+                    entry.path().metadata().map(|metadata| metadata.file_type())
+                }
+                res => res,
+            };
+            let is_dir = match file_type {
                 Ok(v) => {
-                    if !v.is_dir() && !v.is_file() {
+                    if !options.tracks_file_type(v) {
                         continue;
                     }
                     v.is_dir()
                 }
                 Err(err) => {
-                    if err.kind() == ErrorKind::NotFound
-                        || err.kind() == ErrorKind::PermissionDenied
-                    {
+                    if options.is_ignorable(&err) {
                         continue;
                     } else {
-                        return Err(err.into());
+                        return Err(TuxDriveError::io_at(entry.path(), err));
                     }
                 }
             };
             let path = entry.path();
-            let info = T::default();
+            if options.is_ignored(dir_path, &path, is_dir)
+                || (!is_dir && !options.tracks_file(&path))
+            {
+                continue;
+            }
+            let info = new_info(&path, is_dir);
             self.add_path(dir_path, &path, info, is_dir);
         }
         Ok(())
     }
 
+    /// Adds a tree made of the single file `file_path`.
+    /// Fails with `NotFile` if it is anything else, or gone by now.
+    pub fn add_file<P: AsRef<Path>>(
+        &mut self,
+        file_path: P,
+        options: DirectoryAddOptions,
+    ) -> TuxDriveResult<()> {
+        let file_path = file_path.as_ref();
+        if !file_path.is_file() {
+            return Err(TuxDriveError::NotFile(file_path.to_path_buf()));
+        }
+        self.add_path(file_path, file_path, new_info(file_path, false), false);
+        self.set_tree_options(file_path, &options);
+        Ok(())
+    }
+
+    fn set_tree_options(&mut self, root_path: &Path, options: &DirectoryAddOptions) {
+        if let Some(tree) = self.trees.get_mut(root_path) {
+            tree.options = options.clone();
+        }
+    }
+
+    /// Adds everything below `root_path`, which is already added.
+    /// Iterative rather than recursive, so that deep trees can't overflow the stack.
     fn add_dir_rec_intern(
+        &mut self,
+        root_path: &Path,
+        options: &DirectoryAddOptions,
+        visited: &mut HashSet<(u64, u64)>,
+    ) -> TuxDriveResult<()> {
+        // The directories left to read, with their depth below the root
+        let mut stack = vec![(root_path.to_path_buf(), 0)];
+        while let Some((dir_path, depth)) = stack.pop() {
+            self.add_dir_entries(root_path, &dir_path, depth, options, visited, &mut stack)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entries of `dir_path`, pushing the directories among them on
+    /// `stack`. A directory that can't be read is left out (the whole tree,
+    /// for the root).
+    fn add_dir_entries(
         &mut self,
         root_path: &Path,
         dir_path: &Path,
-        options: DirectoryAddOptions,
-    ) -> TuxDriveResult<RecursiveBehaviour> {
+        depth: usize,
+        options: &DirectoryAddOptions,
+        visited: &mut HashSet<(u64, u64)>,
+        stack: &mut Vec<(PathBuf, usize)>,
+    ) -> TuxDriveResult<()> {
+        if !options.allows_depth(depth + 1) {
+            return Ok(());
+        }
         let entries = match dir_path.read_dir() {
             Ok(v) => v,
             Err(err) => {
-                if err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied {
-                    return Ok(RecursiveBehaviour::Delete);
+                if options.is_ignorable(&err) {
+                    self.remove_path(root_path, dir_path);
+                    return Ok(());
                 } else {
-                    return Err(err.into());
+                    return Err(TuxDriveError::io_at(dir_path, err));
                 }
             }
         };
+        options.load_gitignore(dir_path);
         for entry in entries {
             let entry = match entry {
                 Ok(v) => v,
                 Err(err) => {
-                    if err.kind() == ErrorKind::NotFound
-                        || err.kind() == ErrorKind::PermissionDenied
-                    {
+                    if options.is_ignorable(&err) {
                         continue;
                     } else {
-                        return Err(err.into());
+                        return Err(TuxDriveError::io_at(dir_path, err));
                     }
                 }
             };
-            let is_dir = match entry.file_type() {
-                Ok(v) => {
-                    if !v.is_dir() && !v.is_file() {
-                        continue;
-                    }
-                    v.is_dir()
-                }
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(v) => v,
                 Err(err) => {
-                    if err.kind() == ErrorKind::NotFound
-                        || err.kind() == ErrorKind::PermissionDenied
-                    {
+                    if options.is_ignorable(&err) {
                         continue;
                     } else {
-                        return Err(err.into());
+                        return Err(TuxDriveError::io_at(&path, err));
                     }
                 }
             };
-            let path = entry.path();
-            let info = T::default();
-            self.add_path(root_path, &path, info, is_dir);
-            if is_dir {
-                match self.add_dir_rec_intern(root_path, &path, options)? {
-                    RecursiveBehaviour::Nothing => {}
-                    RecursiveBehaviour::Delete => {
-                        self.remove_path(root_path, &path);
+            let is_link = file_type.is_symlink();
+            let file_type = if is_link && options.follow_symlinks {
+                match resolve_symlink(&path).and_then(|target| target.metadata()) {
+                    Ok(v) => v.file_type(),
+                    Err(err) => {
+                        // A link that resolves to itself is a cycle too
+                        let is_loop = err.raw_os_error() == Some(Errno::ELOOP as i32);
+                        if is_loop || options.is_ignorable(&err) {
+                            continue;
+                        } else {
+                            return Err(TuxDriveError::io_at(&path, err));
+                        }
                     }
                 }
+            } else {
+                file_type
+            };
+            if !options.tracks_file_type(file_type) {
+                continue;
+            }
+            let is_dir = file_type.is_dir();
+            if options.is_ignored(root_path, &path, is_dir) {
+                continue;
+            }
+            if !is_dir && !options.tracks_file(&path) {
+                continue;
+            }
+            if is_dir && options.skips_dir(&path) {
+                continue;
+            }
+            if is_dir && options.follow_symlinks {
+                // Linked directories that were already descended into are
+                // skipped, which is what breaks symlink cycles.
+                let metadata = match path.metadata() {
+                    Ok(v) => v,
+                    Err(err) => {
+                        if options.is_ignorable(&err) {
+                            continue;
+                        } else {
+                            return Err(TuxDriveError::io_at(&path, err));
+                        }
+                    }
+                };
+                if !visited.insert(file_id(&metadata)) && is_link {
+                    continue;
+                }
+            }
+            let info = new_info(&path, is_dir);
+            self.add_path(root_path, &path, info, is_dir);
+            if is_dir {
+                stack.push((path, depth + 1));
             }
         }
-        Ok(RecursiveBehaviour::Nothing)
+        Ok(())
     }
 
     /// If func returns true, then recurse further, otherwise not.
@@ -225,43 +670,337 @@ where
         Ok(())
     }
 
+    pub(crate) fn into_trees(self) -> HashMap<PathBuf, PathTree<T>> {
+        self.trees
+    }
+
+    /// Removes the tree rooted at `root_path`, and returns it
+    pub(crate) fn take_tree(&mut self, root_path: &Path) -> Option<PathTree<T>> {
+        self.trees.remove(root_path)
+    }
+
+    /// Adds a tree taken from another forest, replacing one with the same root
+    pub(crate) fn insert_tree(&mut self, tree: PathTree<T>) {
+        self.trees.insert(tree.root_path(), tree);
+    }
+
+    pub(crate) fn has_root(&self, root_path: &Path) -> bool {
+        self.trees.contains_key(root_path)
+    }
+
+    /// The tree `path` belongs to. With nested trees, the innermost one.
+    pub(crate) fn tree_containing_mut(&mut self, path: &Path) -> Option<&mut PathTree<T>> {
+        self.trees
+            .iter_mut()
+            .filter(|(root_path, _)| path.starts_with(root_path))
+            .max_by_key(|(root_path, _)| root_path.components().count())
+            .map(|(_, tree)| tree)
+    }
+
+    pub fn trees(&self) -> impl Iterator<Item = &PathTree<T>> {
+        self.trees.values()
+    }
+
     pub fn trees_mut(&mut self) -> impl Iterator<Item = &mut PathTree<T>> {
         self.trees.iter_mut().map(|(_, tree)| tree)
     }
+
+    /// Number of trees
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+}
+
+/// Read-only queries, which need no `T: NodeInfo`
+impl<T> PathForest<T> {
+    /// Every tracked path over all trees, the roots included, in no
+    /// particular order
+    pub fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.trees
+            .values()
+            .flat_map(|tree| tree.paths().into_iter().map(|(path, _)| path))
+    }
+
+    /// Number of tracked paths over all trees, the roots included
+    pub fn node_count(&self) -> usize {
+        self.trees.values().map(|tree| tree.node_count()).sum()
+    }
+
+    /// Visits every tracked path breadth-first: a tree's root, then its
+    /// entries, then theirs, and so on. Entries of a directory are visited
+    /// by name. Trees are visited one after the other, by root path.
+    pub fn bfs<F: FnMut(&Path, &T)>(&self, mut func: F) {
+        let mut trees = self.trees.iter().collect::<Vec<_>>();
+        trees.sort_by_key(|(root_path, _)| *root_path);
+        for (root_path, tree) in trees {
+            let mut queue = VecDeque::new();
+            queue.push_back((root_path.clone(), &tree.node));
+            while let Some((path, node)) = queue.pop_front() {
+                func(&path, &node.info);
+                let mut children = node.children.iter().collect::<Vec<_>>();
+                children.sort_by_key(|(name, _)| *name);
+                for (name, child) in children {
+                    queue.push_back((path.join(&**name), child));
+                }
+            }
+        }
+    }
+
+    /// The tracked paths drawn like `tree(1)` does, a tree per root in
+    /// order of root path, for debugging. Directories end with a `/`.
+    pub fn render_tree(&self) -> String {
+        let mut trees = self.trees.iter().collect::<Vec<_>>();
+        trees.sort_by_key(|(root_path, _)| *root_path);
+        let mut out = String::new();
+        for (root_path, tree) in trees {
+            out.push_str(&display_path(root_path));
+            if tree.node.is_dir {
+                out.push('/');
+            }
+            out.push('\n');
+            tree.node.render_children("", &mut out);
+        }
+        out
+    }
+
+    /// Every tracked path, over all trees, for which `pred` holds.
+    /// In no particular order.
+    pub fn find_all<F: Fn(&Path, &T) -> bool>(&self, pred: F) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for tree in self.trees.values() {
+            tree.node.visit(&mut tree.root_path(), &mut |path, node| {
+                if pred(path, &node.info) {
+                    found.push(path.to_path_buf());
+                }
+            });
+        }
+        found
+    }
+
+    /// Info of a tracked `path`, `None` if no tree tracks it.
+    /// `path` must be canonical, like the roots.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&T> {
+        let path = path.as_ref();
+        self.tree_containing(path)?
+            .node_info(path)
+            .map(|(info, _)| info)
+    }
+
+    /// Whether some tree tracks `path`
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// The tree `path` belongs to. With nested trees, the innermost one.
+    fn tree_containing(&self, path: &Path) -> Option<&PathTree<T>> {
+        self.trees
+            .iter()
+            .filter(|(root_path, _)| path.starts_with(root_path))
+            .max_by_key(|(root_path, _)| root_path.components().count())
+            .map(|(_, tree)| tree)
+    }
+}
+
+impl<T: Serialize> PathForest<T> {
+    /// Nested JSON mirroring the directories, for inspection by humans and tools.
+    /// Maps each root path to its root node, where a node looks like
+    /// `{ "name": ..., "is_dir": ..., "info": ..., "children": [...] }`.
+    /// Children are sorted by name. Info which can't be represented as JSON is `null`.
+    pub fn to_json_tree(&self) -> Value {
+        let trees = self
+            .trees
+            .iter()
+            .map(|(root_path, tree)| (display_path(root_path).into_owned(), tree.node.to_json()))
+            .collect::<Map<_, _>>();
+        Value::Object(trees)
+    }
+}
+
+impl<T: Serialize> PathForest<T> {
+    /// Saves the paths along with their info to `path`, for `load` to
+    /// restore them, e.g. on the next start. The options are not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> TuxDriveResult<()> {
+        let trees = self
+            .trees
+            .values()
+            .map(|tree| {
+                let mut nodes = Vec::new();
+                tree.node.visit(&mut tree.root_path(), &mut |path, node| {
+                    nodes.push((path_bytes(path), &node.info, node.is_dir));
+                });
+                SavedTree {
+                    root_path: path_bytes(&tree.root_path()),
+                    nodes,
+                }
+            })
+            .collect();
+        let contents = serde_json::to_vec(&SavedForest { trees })?;
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| TuxDriveError::io_at(parent, err))?;
+        }
+        // Written aside first, so that a crash midway leaves the last state whole
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).map_err(|err| TuxDriveError::io_at(&tmp_path, err))?;
+        fs::rename(&tmp_path, path).map_err(|err| TuxDriveError::io_at(path, err))?;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned + NodeInfo> PathForest<T> {
+    /// Restores a forest saved with `save`, with the default options.
+    /// Trees whose root is gone or changed type are left out.
+    pub fn load<P: AsRef<Path>>(path: P) -> TuxDriveResult<Self> {
+        let path = path.as_ref();
+        let contents = fs::read(path).map_err(|err| TuxDriveError::io_at(path, err))?;
+        let saved: SavedForest<T> = serde_json::from_slice(&contents)
+            .map_err(|err| TuxDriveError::CorruptState(path.to_path_buf(), err))?;
+        let mut forest = Self::new();
+        for tree in saved.trees {
+            let root_path = PathBuf::from(OsString::from_vec(tree.root_path));
+            let root_is_dir = tree.nodes.first().is_none_or(|(_, _, is_dir)| *is_dir);
+            match root_path.metadata() {
+                Ok(metadata) if metadata.is_dir() == root_is_dir => {}
+                _ => continue,
+            }
+            // Parents come before their children
+            for (node_path, info, is_dir) in tree.nodes {
+                let node_path = Path::new(OsStr::from_bytes(&node_path));
+                forest.add_path(root_path.as_path(), node_path, info, is_dir);
+            }
+        }
+        Ok(forest)
+    }
+}
+
+/// A forest as written by `PathForest::save`
+#[derive(Serialize, Deserialize)]
+struct SavedForest<T> {
+    trees: Vec<SavedTree<T>>,
+}
+
+/// Paths are saved as bytes, as they need not be UTF-8
+#[derive(Serialize, Deserialize)]
+struct SavedTree<T> {
+    root_path: Vec<u8>,
+    /// Path, info and whether it's a directory, parents before children
+    nodes: Vec<(Vec<u8>, T, bool)>,
+}
+
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_bytes().to_vec()
 }
 
 impl<T> PathTree<T> {
-    /// Precondition: `root_path` must be cannonical
-    fn new<P: AsRef<Path>>(root_path: P) -> Self
+    /// Precondition: `root_path` must be cannonical.
+    /// The root is a directory unless it is a single watched file.
+    fn new<P: AsRef<Path>>(root_path: P, names: NamePool) -> Self
     where
-        T: Default,
+        T: NodeInfo,
     {
         let root_path = PathBuf::from(root_path.as_ref());
-        assert!(root_path.exists() && root_path.is_dir());
+        assert!(root_path.exists());
         let parent_path = root_path.parent().map(PathBuf::from);
-        let root_name = root_path.file_name().map(OsString::from);
-        let info = T::default();
+        let root_name = root_path.file_name().map(|name| names.intern(name));
+        let info = T::default().with_is_dir(true);
         let node = PathNode::new(root_name, info, true);
-        Self { parent_path, node }
+        Self {
+            parent_path,
+            node,
+            names,
+            options: DirectoryAddOptions::new(),
+        }
+    }
+
+    /// The options this tree was added with
+    pub fn options(&self) -> &DirectoryAddOptions {
+        &self.options
     }
 
     /// Precondition:
     /// - `path` must be cannonical
     /// - `path` must be compatible with this tree
-    fn add_path<P: AsRef<Path>>(&mut self, path: P, info: T, is_dir: bool)
+    pub(crate) fn add_path<P: AsRef<Path>>(&mut self, path: P, info: T, is_dir: bool)
     where
-        T: Default,
+        T: NodeInfo,
     {
         let residual_path_comps = self.strip_root(path.as_ref());
-        self.node.add_node_rec(&residual_path_comps, info, is_dir);
+        if residual_path_comps.is_empty() {
+            // The root itself
+            self.node.info = info;
+            self.node.is_dir = is_dir;
+        } else {
+            self.node
+                .add_node(&residual_path_comps, info, is_dir, &self.names);
+        }
     }
 
     /// Precondition:
     /// - `path` must be cannonical
     /// - `path` must be compatible with this tree
-    fn remove_path<P: AsRef<Path>>(&mut self, path: P) -> bool {
+    /// - `path` must not be the root path
+    pub(crate) fn remove_path<P: AsRef<Path>>(&mut self, path: P) -> bool {
         let residual_path_comps = self.strip_root(path.as_ref());
-        self.node.remove_node_rec(&residual_path_comps)
+        self.node.remove_node(&residual_path_comps).is_some()
+    }
+
+    /// Moves `from` to `to`, along with everything below it, in place of
+    /// whatever was there. Returns false, changing nothing, if `from` isn't
+    /// tracked or the parent of `to` isn't a tracked directory.
+    /// Precondition: both paths must be compatible with this tree
+    pub(crate) fn move_path(&mut self, from: &Path, to: &Path) -> bool {
+        let from_comps = self.strip_root(from);
+        let to_comps = self.strip_root(to);
+        let (to_name, to_parent) = match to_comps.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        let parent_is_dir = self
+            .node
+            .descendant_mut(to_parent)
+            .is_some_and(|node| node.is_dir);
+        if from_comps.is_empty() || to.starts_with(from) || !parent_is_dir {
+            return false;
+        }
+        let mut node = match self.node.remove_node(&from_comps) {
+            Some(node) => node,
+            None => return false,
+        };
+        let name = self.names.intern(to_name.as_os_str());
+        node.name = Some(Arc::clone(&name));
+        // Still there, as it isn't below `from`
+        let parent = self.node.descendant_mut(to_parent).unwrap();
+        parent.children.insert(name, node);
+        true
+    }
+
+    /// Removes the directories below the root that are gone from disk and
+    /// have no entries left. Deepest first, so that the parent of one
+    /// is empty by the time it is looked at if that was its last entry.
+    fn prune_empty(&mut self) -> usize {
+        let root_path = self.root_path();
+        let mut gone = self
+            .dir_paths()
+            .into_iter()
+            .filter(|path| *path != root_path && is_gone(path))
+            .collect::<Vec<_>>();
+        gone.sort_by_key(|path| Reverse(path.components().count()));
+        let mut pruned = 0;
+        for path in gone {
+            let residual_path_comps = self.strip_root(&path);
+            let mut node = Some(&self.node);
+            for comp in residual_path_comps {
+                node = node.and_then(|node| node.children.get(comp.as_os_str()));
+            }
+            if node.is_some_and(|node| node.children.is_empty()) && self.remove_path(&path) {
+                pruned += 1;
+            }
+        }
+        pruned
     }
 
     /// Precondition: `path` must be cannonical
@@ -269,35 +1008,83 @@ impl<T> PathTree<T> {
         path.as_ref().starts_with(self.root_path())
     }
 
-    fn root_path(&self) -> PathBuf {
+    /// Info and whether it's a directory, for a tracked `path`
+    /// Precondition: `path` must be compatible with this tree
+    pub(crate) fn node_info(&self, path: &Path) -> Option<(&T, bool)> {
+        let residual_path_comps = self.strip_root(path);
+        let mut node = &self.node;
+        for comp in residual_path_comps {
+            node = node.children.get(comp.as_os_str())?;
+        }
+        Some((&node.info, node.is_dir))
+    }
+
+    /// Info and whether it's a directory, for a tracked `path`
+    /// Precondition: `path` must be compatible with this tree
+    pub(crate) fn node_info_mut(&mut self, path: &Path) -> Option<(&mut T, bool)> {
+        let residual_path_comps = self.strip_root(path);
+        let mut node = &mut self.node;
+        for comp in residual_path_comps {
+            node = node.children.get_mut(comp.as_os_str())?;
+        }
+        Some((&mut node.info, node.is_dir))
+    }
+
+    /// Number of nodes in the tree, including the root
+    pub fn node_count(&self) -> usize {
+        self.node.node_count()
+    }
+
+    /// Paths of all directories in the tree, the root included
+    pub(crate) fn dir_paths(&self) -> Vec<PathBuf> {
+        self.paths()
+            .into_iter()
+            .filter_map(|(path, is_dir)| is_dir.then_some(path))
+            .collect()
+    }
+
+    /// All paths in the tree, the root included, and whether each is a directory
+    pub(crate) fn paths(&self) -> Vec<(PathBuf, bool)> {
+        let mut paths = Vec::new();
+        self.node.collect_paths(&mut self.root_path(), &mut paths);
+        paths
+    }
+
+    /// False for a single watched file
+    pub(crate) fn root_is_dir(&self) -> bool {
+        self.node.is_dir
+    }
+
+    pub(crate) fn root_path(&self) -> PathBuf {
         assert!(self.parent_path.is_none() == self.node.name.is_none());
         if let Some(parent_path) = &self.parent_path {
             let mut root_path = parent_path.clone();
-            root_path.push(self.node.name.as_ref().unwrap());
+            root_path.push(self.node.name.as_deref().unwrap());
             root_path
         } else {
             PathBuf::from("/")
         }
     }
 
+    /// Components of `path` below the root, empty for the root itself
     fn strip_root<'a>(&self, path: &'a Path) -> Vec<Component<'a>> {
-        assert!(self.is_path_compatible(&path));
+        assert!(self.is_path_compatible(path));
         let root_path_comps_len = self.root_path().components().count();
-        path.components().skip(root_path_comps_len - 1).collect()
+        path.components().skip(root_path_comps_len).collect()
     }
 
     pub fn dfs_mut<F>(&mut self, func: F) -> TuxDriveResult<RecursiveBehaviour>
     where
         F: FnMut(&Path, DfsMutInfo<T>) -> TuxDriveResult<DfsFuncBehaviour> + Copy,
-        T: Default,
+        T: NodeInfo,
     {
         let mut root_path = self.root_path();
-        self.node.dfs_mut(&mut root_path, func)
+        self.node.dfs_mut(&mut root_path, func, &self.names)
     }
 }
 
 impl<T> PathNode<T> {
-    fn new(name: Option<OsString>, info: T, is_dir: bool) -> Self {
+    fn new(name: Option<Arc<OsStr>>, info: T, is_dir: bool) -> Self {
         Self {
             name,
             info,
@@ -306,115 +1093,377 @@ impl<T> PathNode<T> {
         }
     }
 
-    fn add_node_rec(&mut self, comps: &[Component<'_>], info: T, is_dir: bool)
+    fn add_node(&mut self, comps: &[Component<'_>], info: T, is_dir: bool, names: &NamePool)
     where
-        T: Default,
+        T: NodeInfo,
     {
-        assert!(!comps.is_empty());
-        if comps.len() == 1 {
-            let name = comps[0].as_os_str().to_os_string();
-            let new_node = PathNode::new(Some(name.clone()), info, is_dir);
-            self.children.insert(name, new_node);
-        } else if let Some(child) = self.children.get_mut(comps[0].as_os_str()) {
-            child.add_node_rec(&comps[1..], info, is_dir);
-        } else {
-            let name = comps[0].as_os_str().to_os_string();
-            let mut new_node = PathNode::new(Some(name.clone()), T::default(), true);
-            new_node.add_node_rec(&comps[1..], info, is_dir);
-            self.children.insert(name, new_node);
+        let (last, parents) = comps.split_last().unwrap();
+        let mut node = self;
+        for comp in parents {
+            let name = comp.as_os_str();
+            if !node.children.contains_key(name) {
+                let name = names.intern(name);
+                let new_node = PathNode::new(
+                    Some(Arc::clone(&name)),
+                    T::default().with_is_dir(true),
+                    true,
+                );
+                node.children.insert(name, new_node);
+            }
+            node = node.children.get_mut(name).unwrap();
         }
+        let name = names.intern(last.as_os_str());
+        let new_node = PathNode::new(Some(Arc::clone(&name)), info, is_dir);
+        node.children.insert(name, new_node);
     }
 
-    fn remove_node_rec(&mut self, comps: &[Component<'_>]) -> bool {
-        assert!(!comps.is_empty());
-        if comps.len() == 1 {
-            let name = comps[0].as_os_str();
-            self.children.remove(name).is_some()
-        } else if let Some(child) = self.children.get_mut(comps[0].as_os_str()) {
-            child.remove_node_rec(&comps[1..])
-        } else {
-            false
+    fn collect_paths(&self, curr_path: &mut PathBuf, paths: &mut Vec<(PathBuf, bool)>) {
+        self.visit(curr_path, &mut |path, node| {
+            paths.push((path.to_path_buf(), node.is_dir))
+        });
+    }
+
+    /// Appends a line per child to `out`, each with its own children below
+    /// it, all lines starting with `prefix`
+    fn render_children(&self, prefix: &str, out: &mut String) {
+        // The prefix of a node's line, the node, and whether it is the last
+        // of its siblings. Children are pushed last first to come off in order.
+        let mut stack = Vec::new();
+        self.push_sorted_children(prefix, &mut stack);
+        while let Some((prefix, name, node, is_last)) = stack.pop() {
+            let (connector, indent) = if is_last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            out.push_str(&prefix);
+            out.push_str(connector);
+            out.push_str(&name.to_string_lossy());
+            if node.is_dir {
+                out.push('/');
+            }
+            out.push('\n');
+            node.push_sorted_children(&format!("{}{}", prefix, indent), &mut stack);
+        }
+    }
+
+    /// Pushes the children on `stack` for `render_children`, the first one by name on top
+    fn push_sorted_children<'a>(
+        &'a self,
+        prefix: &str,
+        stack: &mut Vec<(String, &'a OsStr, &'a Self, bool)>,
+    ) {
+        let mut children = self.children.iter().collect::<Vec<_>>();
+        children.sort_by_key(|(name, _)| *name);
+        let last_idx = children.len().saturating_sub(1);
+        for (idx, (name, child)) in children.into_iter().enumerate().rev() {
+            stack.push((prefix.to_string(), name, child, idx == last_idx));
+        }
+    }
+
+    /// Calls `func` on this node and all below it, parents before children
+    fn visit<'a, F: FnMut(&Path, &'a Self)>(&'a self, curr_path: &mut PathBuf, func: &mut F) {
+        // Depth below this node along with the node, so that `curr_path`
+        // can be brought back up before going down another branch
+        let mut stack = vec![(0, self)];
+        let mut depth = 0;
+        while let Some((node_depth, node)) = stack.pop() {
+            while depth >= node_depth && depth > 0 {
+                curr_path.pop();
+                depth -= 1;
+            }
+            if node_depth > 0 {
+                curr_path.push(node.name.as_deref().unwrap());
+                depth = node_depth;
+            }
+            func(curr_path, node);
+            stack.extend(node.children.values().map(|child| (node_depth + 1, child)));
         }
+        for _ in 0..depth {
+            curr_path.pop();
+        }
+    }
+
+    fn node_count(&self) -> usize {
+        let mut count = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            count += 1;
+            stack.extend(node.children.values());
+        }
+        count
+    }
+
+    fn remove_node(&mut self, comps: &[Component<'_>]) -> Option<Self> {
+        let (last, parents) = comps.split_last().unwrap();
+        self.descendant_mut(parents)?
+            .children
+            .remove(last.as_os_str())
     }
 
+    /// The node `comps` lead to from this one
+    fn descendant_mut(&mut self, comps: &[Component<'_>]) -> Option<&mut Self> {
+        let mut node = self;
+        for comp in comps {
+            node = node.children.get_mut(comp.as_os_str())?;
+        }
+        Some(node)
+    }
+
+    /// Iterative rather than recursive, so that deep trees can't overflow
+    /// the stack: the nodes being visited are taken out of their parent and
+    /// kept on a heap-allocated stack, to be put back once visited.
     fn dfs_mut<F>(
         &mut self,
         curr_path: &mut PathBuf,
         mut func: F,
+        names: &NamePool,
     ) -> TuxDriveResult<RecursiveBehaviour>
     where
         F: FnMut(&Path, DfsMutInfo<T>) -> TuxDriveResult<DfsFuncBehaviour> + Copy,
-        T: Default,
+        T: NodeInfo,
     {
-        fn recurse_downwards<T, F>(
-            node: &mut PathNode<T>,
-            curr_path: &mut PathBuf,
-            func: F,
-        ) -> TuxDriveResult<RecursiveBehaviour>
-        where
-            F: FnMut(&Path, DfsMutInfo<T>) -> TuxDriveResult<DfsFuncBehaviour> + Copy,
-            T: Default,
-        {
-            let mut keys_to_delete = Vec::new();
-            for (key, node) in node.children.iter_mut() {
-                // Name can be empty only at the root
-                assert!(node.name.is_some());
-                curr_path.push(node.name.as_ref().unwrap());
-                match node.dfs_mut(curr_path, func)? {
-                    RecursiveBehaviour::Nothing => {}
-                    RecursiveBehaviour::Delete => keys_to_delete.push(key.clone()),
-                }
-                curr_path.pop();
+        match func(curr_path, self.get_dfs_mut_info(curr_path))? {
+            DfsFuncBehaviour::Continue => {}
+            DfsFuncBehaviour::Stop => return Ok(RecursiveBehaviour::Nothing),
+            DfsFuncBehaviour::Delete => return Ok(RecursiveBehaviour::Delete),
+            DfsFuncBehaviour::AddAndContinue(paths) => self.add_new_paths(paths, names),
+            DfsFuncBehaviour::RenameAndContinue { renamed, added } => {
+                self.rename_children(renamed, names);
+                self.add_new_paths(added, names);
             }
-            for key in keys_to_delete {
-                node.children.remove(&key);
+            DfsFuncBehaviour::AddAndStop(paths) => {
+                self.add_new_paths(paths, names);
+                return Ok(RecursiveBehaviour::Nothing);
             }
-            Ok(RecursiveBehaviour::Nothing)
         }
 
-        fn add_new_paths<T: Default>(node: &mut PathNode<T>, new_paths: Vec<PathBuf>) {
-            for path in new_paths {
-                let name = path.file_name().map(OsString::from);
-                let new_node = PathNode::new(name.clone(), T::default(), path.is_dir());
-                node.children.insert(name.unwrap(), new_node);
+        let mut stack = vec![DfsFrame::new(None, mem::take(&mut self.children))];
+        let res = dfs_frames(&mut stack, curr_path, func, names);
+        // Only the frame of this node is left, unless there was an error:
+        // then everything taken out on the way down is put back too
+        while let Some(frame) = stack.pop() {
+            match stack.last_mut() {
+                Some(parent) => {
+                    curr_path.pop();
+                    parent.keep(frame.into_node());
+                }
+                None => self.children = frame.into_children(),
             }
         }
+        res.map(|()| RecursiveBehaviour::Nothing)
+    }
 
-        match func(curr_path, self.get_dfs_mut_info(curr_path))? {
-            DfsFuncBehaviour::Continue => recurse_downwards(self, curr_path, func),
-            DfsFuncBehaviour::Stop => Ok(RecursiveBehaviour::Nothing),
-            DfsFuncBehaviour::Delete => Ok(RecursiveBehaviour::Delete),
-            DfsFuncBehaviour::AddAndContinue(paths) => {
-                add_new_paths(self, paths);
-                recurse_downwards(self, curr_path, func)
-            }
-            DfsFuncBehaviour::AddAndStop(paths) => {
-                add_new_paths(self, paths);
-                Ok(RecursiveBehaviour::Nothing)
-            }
+    fn add_new_paths(&mut self, new_paths: Vec<PathBuf>, names: &NamePool)
+    where
+        T: NodeInfo,
+    {
+        for path in new_paths {
+            let name = names.intern(path.file_name().unwrap());
+            let is_dir = path.is_dir();
+            let new_node = PathNode::new(Some(Arc::clone(&name)), new_info(&path, is_dir), is_dir);
+            self.children.insert(name, new_node);
         }
     }
 
-    fn get_dfs_mut_info(&mut self, path: &Path) -> DfsMutInfo<T> {
+    fn get_dfs_mut_info(&mut self, path: &Path) -> DfsMutInfo<'_, T> {
+        // Children are keyed by their name, so no unnamed fallback is needed
         let children_paths = self
             .children
-            .values()
-            .map(|node| path![path, node.name.as_ref().unwrap_or(&OsString::new())])
+            .keys()
+            .map(|name| path.join(&**name))
             .collect();
         DfsMutInfo {
             children_paths,
             info: &mut self.info,
             is_dir: self.is_dir,
+            children: &self.children,
+        }
+    }
+
+    /// Moves each child from the first path's name to the second's,
+    /// along with everything below it
+    fn rename_children(&mut self, renamed: Vec<(PathBuf, PathBuf)>, names: &NamePool) {
+        for (from, to) in renamed {
+            let mut child = match self.children.remove(from.file_name().unwrap()) {
+                Some(child) => child,
+                None => continue,
+            };
+            let name = names.intern(to.file_name().unwrap());
+            child.name = Some(Arc::clone(&name));
+            self.children.insert(name, child);
+        }
+    }
+}
+
+impl<T> Drop for PathNode<T> {
+    fn drop(&mut self) {
+        // Dropping the children recursively would overflow the stack on deep trees
+        let mut nodes = mem::take(&mut self.children)
+            .into_values()
+            .collect::<Vec<_>>();
+        while let Some(mut node) = nodes.pop() {
+            nodes.extend(mem::take(&mut node.children).into_values());
+        }
+    }
+}
+
+/// A node being visited by `PathNode::dfs_mut`, with its children taken out
+struct DfsFrame<T> {
+    /// `None` for the node the traversal started from, which stays in place
+    node: Option<PathNode<T>>,
+    /// Children left to visit
+    pending: Vec<PathNode<T>>,
+    /// Children visited and not deleted
+    kept: HashMap<Arc<OsStr>, PathNode<T>>,
+}
+
+impl<T> DfsFrame<T> {
+    fn new(node: Option<PathNode<T>>, children: HashMap<Arc<OsStr>, PathNode<T>>) -> Self {
+        Self {
+            node,
+            pending: children.into_values().collect(),
+            kept: HashMap::new(),
+        }
+    }
+
+    fn keep(&mut self, child: PathNode<T>) {
+        // Name can be empty only at the root
+        let name = Arc::clone(child.name.as_ref().unwrap());
+        self.kept.insert(name, child);
+    }
+
+    /// All children, including those not visited yet
+    fn into_children(mut self) -> HashMap<Arc<OsStr>, PathNode<T>> {
+        for child in mem::take(&mut self.pending) {
+            self.keep(child);
+        }
+        self.kept
+    }
+
+    fn into_node(mut self) -> PathNode<T> {
+        let mut node = self.node.take().unwrap();
+        node.children = self.into_children();
+        node
+    }
+}
+
+/// Visits the nodes below the bottom frame of `stack`, depth first.
+/// On success, only that frame is left on `stack`.
+fn dfs_frames<T, F>(
+    stack: &mut Vec<DfsFrame<T>>,
+    curr_path: &mut PathBuf,
+    mut func: F,
+    names: &NamePool,
+) -> TuxDriveResult<()>
+where
+    F: FnMut(&Path, DfsMutInfo<T>) -> TuxDriveResult<DfsFuncBehaviour> + Copy,
+    T: NodeInfo,
+{
+    loop {
+        let frame = stack.last_mut().unwrap();
+        let mut child = match frame.pending.pop() {
+            Some(child) => child,
+            None if stack.len() == 1 => return Ok(()),
+            None => {
+                // All children visited, go back up
+                let node = stack.pop().unwrap().into_node();
+                curr_path.pop();
+                stack.last_mut().unwrap().keep(node);
+                continue;
+            }
+        };
+        curr_path.push(child.name.as_deref().unwrap());
+        let behaviour = match func(curr_path, child.get_dfs_mut_info(curr_path)) {
+            Ok(behaviour) => behaviour,
+            Err(err) => {
+                frame.keep(child);
+                curr_path.pop();
+                return Err(err);
+            }
+        };
+        match behaviour {
+            DfsFuncBehaviour::Continue => {
+                let children = mem::take(&mut child.children);
+                stack.push(DfsFrame::new(Some(child), children));
+            }
+            DfsFuncBehaviour::AddAndContinue(paths) => {
+                child.add_new_paths(paths, names);
+                let children = mem::take(&mut child.children);
+                stack.push(DfsFrame::new(Some(child), children));
+            }
+            DfsFuncBehaviour::RenameAndContinue { renamed, added } => {
+                child.rename_children(renamed, names);
+                child.add_new_paths(added, names);
+                let children = mem::take(&mut child.children);
+                stack.push(DfsFrame::new(Some(child), children));
+            }
+            DfsFuncBehaviour::Stop => {
+                frame.keep(child);
+                curr_path.pop();
+            }
+            DfsFuncBehaviour::AddAndStop(paths) => {
+                child.add_new_paths(paths, names);
+                frame.keep(child);
+                curr_path.pop();
+            }
+            DfsFuncBehaviour::Delete => {
+                curr_path.pop();
+            }
         }
     }
 }
 
+impl<T: Serialize> PathNode<T> {
+    /// Iterative, like `visit`: each node is converted once all its children are
+    fn to_json(&self) -> Value {
+        // Each node being converted, its children left to convert, and those converted
+        let mut stack = vec![(self, self.sorted_children(), Vec::new())];
+        loop {
+            let (_, children, _) = stack.last_mut().unwrap();
+            if let Some(child) = children.next() {
+                stack.push((child, child.sorted_children(), Vec::new()));
+                continue;
+            }
+            let (node, _, children) = stack.pop().unwrap();
+            let mut value = json!({
+                "name": node.name.as_deref().map(|name| name.to_string_lossy()),
+                "is_dir": node.is_dir,
+                "info": serde_json::to_value(&node.info).unwrap_or(Value::Null),
+            });
+            // Not through `json!`, which would serialize them all over again, recursively
+            value["children"] = Value::Array(children);
+            match stack.last_mut() {
+                Some((_, _, siblings)) => siblings.push(value),
+                None => return value,
+            }
+        }
+    }
+
+    fn sorted_children(&self) -> std::vec::IntoIter<&Self> {
+        let mut children = self.children.iter().collect::<Vec<_>>();
+        children.sort_by_key(|(name, _)| *name);
+        children
+            .into_iter()
+            .map(|(_, child)| child)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
 #[derive(Debug)]
 pub enum DfsFuncBehaviour {
     Continue,
     Stop,
     Delete,
+    /// The new paths are visited too, before moving on
     AddAndContinue(Vec<PathBuf>),
+    /// Like `AddAndContinue`, and the children at the first path of
+    /// `renamed` move to the second one, keeping everything below them
+    RenameAndContinue {
+        renamed: Vec<(PathBuf, PathBuf)>,
+        added: Vec<PathBuf>,
+    },
     AddAndStop(Vec<PathBuf>),
 }
 
@@ -429,4 +1478,650 @@ pub struct DfsMutInfo<'info, T> {
     pub children_paths: HashSet<PathBuf>,
     pub info: &'info mut T,
     pub is_dir: bool,
+    children: &'info HashMap<Arc<OsStr>, PathNode<T>>,
+}
+
+impl<T> DfsMutInfo<'_, T> {
+    /// Name, info and whether it is a directory, of each child
+    pub fn children(&self) -> impl Iterator<Item = (&OsStr, &T, bool)> {
+        self.children
+            .iter()
+            .map(|(name, child)| (&**name, &child.info, child.is_dir))
+    }
+}
+
+/// Whether nothing is at `path` any more
+fn is_gone(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_err_and(|err| err.kind() == ErrorKind::NotFound)
+}
+
+/// How many levels `path` is below `root_path`
+pub(crate) fn depth_below(root_path: &Path, path: &Path) -> usize {
+    path.strip_prefix(root_path)
+        .map_or(0, |relative| relative.components().count())
+}
+
+/// Device and inode, which tell a directory apart whatever the path to it
+fn file_id(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.dev(), metadata.ino())
+}
+
+/// Canonical path of what a symlink points to. Relative targets are
+/// resolved against the directory containing the link, not the CWD.
+fn resolve_symlink(link: &Path) -> io::Result<PathBuf> {
+    let target = std::fs::read_link(link)?;
+    let target = match link.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    };
+    target.canonicalize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, os::unix::fs::symlink};
+
+    impl NodeInfo for u32 {}
+
+    #[test]
+    pub fn json_tree_shape() {
+        let root = std::env::temp_dir();
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path(&root, &root, 0, true);
+        forest.add_path(&root, &path![root, "dir"], 1, true);
+        forest.add_path(&root, &path![root, "dir", "b.txt"], 2, false);
+        forest.add_path(&root, &path![root, "a.txt"], 3, false);
+
+        let root_name = root.file_name().unwrap().to_string_lossy();
+        let expected = json!({
+            root.display().to_string(): {
+                "name": root_name,
+                "is_dir": true,
+                "info": 0,
+                "children": [
+                    { "name": "a.txt", "is_dir": false, "info": 3, "children": [] },
+                    {
+                        "name": "dir",
+                        "is_dir": true,
+                        "info": 1,
+                        "children": [
+                            { "name": "b.txt", "is_dir": false, "info": 2, "children": [] },
+                        ],
+                    },
+                ],
+            }
+        });
+        assert_eq!(forest.to_json_tree(), expected);
+    }
+
+    #[test]
+    pub fn forest_sizes() {
+        let root = std::env::temp_dir();
+        let mut forest = PathForest::<u32>::new();
+        assert!(forest.is_empty());
+        forest.add_path(&root, &root, 0, true);
+        forest.add_path(&root, &path![root, "dir", "b.txt"], 0, false);
+        forest.add_path(&root, &path![root, "a.txt"], 0, false);
+        assert_eq!(forest.len(), 1);
+        assert!(!forest.is_empty());
+        assert_eq!(forest.node_count(), 4);
+    }
+
+    #[test]
+    pub fn explicit_virtual_fs_option_kept() {
+        let proc = Path::new("/proc");
+        if !proc.join("self").exists() {
+            return;
+        }
+        let options = DirectoryAddOptions::new().skip_virtual_fs_unless_set(true);
+        assert!(options.skips_dir(proc));
+        let options = DirectoryAddOptions::new()
+            .skip_virtual_fs(false)
+            .skip_virtual_fs_unless_set(true);
+        assert!(!options.skips_dir(proc));
+        let options = DirectoryAddOptions::new().skip_virtual_fs_unless_set(false);
+        assert!(!options.skips_dir(proc));
+    }
+
+    #[test]
+    pub fn follow_relative_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = path![dir.path(), "root"];
+        let real = path![dir.path(), "real"];
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&real).unwrap();
+        fs::write(path![real, "file.txt"], b"data").unwrap();
+        symlink(path!["..", "real"], path![root, "link"]).unwrap();
+
+        let target = resolve_symlink(&path![root, "link"]).unwrap();
+        assert_eq!(target, real.canonicalize().unwrap());
+
+        let mut forest = PathForest::<u32>::new();
+        forest
+            .add_dir_recursively(&root, DirectoryAddOptions::new())
+            .unwrap();
+        assert_eq!(forest.node_count(), 1);
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().follow_symlinks(true);
+        forest.add_dir_recursively(&root, options).unwrap();
+        assert_eq!(forest.node_count(), 3);
+    }
+
+    #[test]
+    pub fn symlink_cycle_is_broken() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(path![root, "sub"]).unwrap();
+        symlink("..", path![root, "sub", "up"]).unwrap();
+        symlink("loop", path![root, "loop"]).unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().follow_symlinks(true);
+        forest.add_dir_recursively(root, options).unwrap();
+        assert_eq!(forest.node_count(), 2);
+    }
+
+    #[test]
+    pub fn symlink_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = path![dir.path(), "root"];
+        fs::create_dir(&root).unwrap();
+        fs::write(path![dir.path(), "file.txt"], b"data").unwrap();
+        symlink(path!["..", "file.txt"], path![root, "link.txt"]).unwrap();
+
+        for (follow, expected) in [(false, 1), (true, 2)] {
+            let options = DirectoryAddOptions::new().follow_symlinks(follow);
+            let mut forest = PathForest::<u32>::new();
+            forest.add_dir_recursively(&root, options.clone()).unwrap();
+            assert_eq!(
+                forest.node_count(),
+                expected,
+                "recursive, follow: {}",
+                follow
+            );
+
+            let mut forest = PathForest::<u32>::new();
+            forest.add_dir_non_recursively(&root, options).unwrap();
+            assert_eq!(
+                forest.node_count(),
+                expected,
+                "non-recursive, follow: {}",
+                follow
+            );
+        }
+    }
+
+    #[test]
+    pub fn max_depth_stops_at_the_boundary() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(path![root, "a", "b", "c"]).unwrap();
+        fs::write(path![root, "a", "b", "c", "deep.txt"], b"").unwrap();
+        fs::write(path![root, "a", "shallow.txt"], b"").unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().max_depth(2);
+        forest.add_dir_recursively(root, options).unwrap();
+        let mut paths = forest
+            .trees_mut()
+            .flat_map(|tree| tree.paths())
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                root.to_path_buf(),
+                path![root, "a"],
+                path![root, "a", "b"],
+                path![root, "a", "shallow.txt"],
+            ]
+        );
+    }
+
+    #[test]
+    pub fn find_all_by_info() {
+        let root = std::env::temp_dir();
+        let other_root = std::env::temp_dir().parent().unwrap().to_path_buf();
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path(&root, &root, 0, true);
+        forest.add_path(&root, &path![root, "dir"], 7, true);
+        forest.add_path(&root, &path![root, "dir", "b.txt"], 8, false);
+        forest.add_path(&root, &path![root, "a.txt"], 3, false);
+        forest.add_path(&other_root, &other_root, 9, true);
+
+        let mut found = forest.find_all(|_, info| *info > 5);
+        found.sort();
+        let mut expected = vec![path![root, "dir"], path![root, "dir", "b.txt"], other_root];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let found = forest.find_all(|path, _| path.extension().is_some_and(|ext| ext == "txt"));
+        assert_eq!(found.len(), 2);
+        assert!(forest.find_all(|_, _| false).is_empty());
+    }
+
+    #[test]
+    pub fn bfs_goes_level_by_level() {
+        let root = std::env::temp_dir();
+        let other_root = std::env::temp_dir().parent().unwrap().to_path_buf();
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path(&root, &root, 0, true);
+        forest.add_path(&root, &path![root, "dir"], 1, true);
+        forest.add_path(&root, &path![root, "dir", "sub"], 2, true);
+        forest.add_path(&root, &path![root, "dir", "sub", "c.txt"], 3, false);
+        forest.add_path(&root, &path![root, "dir", "b.txt"], 4, false);
+        forest.add_path(&root, &path![root, "a.txt"], 5, false);
+        forest.add_path(&other_root, &other_root, 6, true);
+
+        let mut visited = Vec::new();
+        forest.bfs(|path, info| visited.push((path.to_path_buf(), *info)));
+        assert_eq!(
+            visited,
+            vec![
+                (other_root, 6),
+                (root.clone(), 0),
+                (path![root, "a.txt"], 5),
+                (path![root, "dir"], 1),
+                (path![root, "dir", "b.txt"], 4),
+                (path![root, "dir", "sub"], 2),
+                (path![root, "dir", "sub", "c.txt"], 3),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn deep_tree_traversal() {
+        // About as deep as a path gets under PATH_MAX
+        const DEPTH: usize = 1_500;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let mut deepest = root.clone();
+        for _ in 0..DEPTH {
+            deepest.push("d");
+        }
+        fs::create_dir_all(&deepest).unwrap();
+
+        // On a stack one frame per level would overflow
+        let traversal = std::thread::Builder::new().stack_size(128 * 1024);
+        let traversal = traversal.spawn(move || {
+            let mut forest = PathForest::<u32>::new();
+            forest
+                .add_dir_recursively(&root, DirectoryAddOptions::new())
+                .unwrap();
+            assert_eq!(forest.node_count(), DEPTH + 1);
+            // Taken apart a level at a time, dropping it whole is recursive too
+            let mut json = forest.to_json_tree()[display_path(&root).as_ref()].take();
+            let mut json_depth = 0;
+            while let Some(child) = json["children"].as_array_mut().and_then(Vec::pop) {
+                json = child;
+                json_depth += 1;
+            }
+            assert_eq!(json_depth, DEPTH);
+
+            // The closure has to be Copy
+            let visited = std::cell::Cell::new(0);
+            let seen_deepest = std::cell::Cell::new(false);
+            forest
+                .dfs_mut(|path, _| {
+                    visited.set(visited.get() + 1);
+                    seen_deepest.set(seen_deepest.get() || path == deepest);
+                    Ok(DfsFuncBehaviour::Continue)
+                })
+                .unwrap();
+            assert_eq!(visited.get(), DEPTH + 1);
+            assert!(seen_deepest.get());
+
+            // Cut the tree in half, and add a sibling on the way down
+            let root_depth = root.components().count();
+            forest
+                .dfs_mut(|path, _| match path.components().count() - root_depth {
+                    1 => Ok(DfsFuncBehaviour::AddAndContinue(vec![path![root, "e"]])),
+                    depth if depth == DEPTH / 2 => Ok(DfsFuncBehaviour::Delete),
+                    _ => Ok(DfsFuncBehaviour::Continue),
+                })
+                .unwrap();
+            // root, levels 1 to DEPTH / 2 - 1, and e
+            assert_eq!(forest.node_count(), DEPTH / 2 + 1);
+            assert!(
+                forest
+                    .find_all(|path, _| path == path![root, "d", "e"])
+                    .len()
+                    == 1
+            );
+            drop(forest);
+        });
+        traversal.unwrap().join().unwrap();
+    }
+
+    #[test]
+    pub fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let state_path = path![root, "state", "forest.json"];
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path(&root, &root, 1, true);
+        forest.add_path(&root, &path![root, "dir", "a.txt"], 2, false);
+        forest.add_path(&root, &path![root, "b.txt"], 3, false);
+        forest.save(&state_path).unwrap();
+
+        let loaded = PathForest::<u32>::load(&state_path).unwrap();
+        let mut found = loaded.find_all(|_, _| true);
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                root.clone(),
+                path![root, "b.txt"],
+                path![root, "dir"],
+                path![root, "dir", "a.txt"]
+            ]
+        );
+        assert_eq!(
+            loaded.find_all(|_, info| *info == 2),
+            vec![path![root, "dir", "a.txt"]]
+        );
+
+        fs::write(&state_path, b"{").unwrap();
+        assert!(matches!(
+            PathForest::<u32>::load(&state_path),
+            Err(TuxDriveError::CorruptState(..))
+        ));
+    }
+
+    #[test]
+    pub fn lookup_paths() {
+        let root = std::env::temp_dir();
+        let mut forest = PathForest::<u32>::new();
+        assert_eq!(forest.get(&root), None);
+        forest.add_path(&root, &root, 1, true);
+        forest.add_path(&root, &path![root, "dir", "a.txt"], 2, false);
+
+        assert_eq!(forest.get(&root), Some(&1));
+        assert_eq!(forest.get(path![root, "dir", "a.txt"]), Some(&2));
+        assert_eq!(forest.get(path![root, "dir"]), Some(&0));
+        assert!(forest.contains(path![root, "dir"]));
+        assert!(!forest.contains(path![root, "dir", "b.txt"]));
+        assert!(!forest.contains(path![root, "a.txt", "below a file"]));
+        // Outside of any tree
+        assert!(!forest.contains(root.parent().unwrap()));
+        assert!(!forest.contains("/does/not/exist"));
+    }
+
+    #[test]
+    pub fn paths_of_all_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (first, second) = (path![root, "first"], path![root, "second"]);
+        fs::create_dir_all(path![first, "sub"]).unwrap();
+        fs::create_dir(&second).unwrap();
+        fs::write(path![first, "a"], b"").unwrap();
+        fs::write(path![first, "sub", "b"], b"").unwrap();
+        fs::write(path![second, "c"], b"").unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        forest
+            .add_dir_recursively(&first, DirectoryAddOptions::new())
+            .unwrap();
+        forest
+            .add_dir_recursively(&second, DirectoryAddOptions::new())
+            .unwrap();
+        let mut paths = forest.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                first.clone(),
+                path![first, "a"],
+                path![first, "sub"],
+                path![first, "sub", "b"],
+                second.clone(),
+                path![second, "c"],
+            ]
+        );
+        assert_eq!(forest.node_count(), 6);
+    }
+
+    #[test]
+    pub fn gitignore_files_are_honored() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(path![root, "target", "debug"]).unwrap();
+        fs::create_dir_all(path![root, "src", "gen"]).unwrap();
+        fs::write(path![root, ".gitignore"], "target/\n*.o\n").unwrap();
+        fs::write(path![root, "src", ".gitignore"], "!keep.o\ngen\n").unwrap();
+        for file in [
+            path![root, "target", "debug", "app"],
+            path![root, "main.o"],
+            path![root, "src", "lib.rs"],
+            path![root, "src", "lib.o"],
+            path![root, "src", "keep.o"],
+            path![root, "src", "gen", "out.rs"],
+        ] {
+            fs::write(file, b"").unwrap();
+        }
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().gitignore(true);
+        forest.add_dir_recursively(&root, options).unwrap();
+        let mut paths = forest.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                root.clone(),
+                path![root, ".gitignore"],
+                path![root, "src"],
+                path![root, "src", ".gitignore"],
+                path![root, "src", "keep.o"],
+                path![root, "src", "lib.rs"],
+            ]
+        );
+    }
+
+    #[test]
+    pub fn extension_allowlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(path![root, "sub"]).unwrap();
+        fs::write(path![root, "a.log"], b"").unwrap();
+        fs::write(path![root, "b.LOG"], b"").unwrap();
+        fs::write(path![root, "c.txt"], b"").unwrap();
+        fs::write(path![root, "sub", "d.log"], b"").unwrap();
+        fs::write(path![root, "sub", "log"], b"").unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().extensions(vec![String::from("log")]);
+        forest.add_dir_recursively(root, options).unwrap();
+        // root, a.log, sub, sub/d.log
+        assert_eq!(forest.node_count(), 4);
+    }
+
+    #[test]
+    pub fn root_children_paths() {
+        use std::sync::Mutex;
+
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path("/", "/", 0, true);
+        forest.add_path("/", "/tmp", 0, true);
+        forest.add_path("/", "/tmp/a.txt", 0, false);
+        let seen = Mutex::new(Vec::new());
+        let seen = &seen;
+        forest
+            .dfs_mut(|path, dfs_info| {
+                let mut children = dfs_info.children_paths.into_iter().collect::<Vec<_>>();
+                children.sort();
+                seen.lock().unwrap().push((path.to_path_buf(), children));
+                Ok(DfsFuncBehaviour::Continue)
+            })
+            .unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                (PathBuf::from("/"), vec![PathBuf::from("/tmp")]),
+                (PathBuf::from("/tmp"), vec![PathBuf::from("/tmp/a.txt")]),
+                (PathBuf::from("/tmp/a.txt"), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn ignored_paths_not_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(path![root, "node_modules", "pkg"]).unwrap();
+        fs::create_dir_all(path![root, "build"]).unwrap();
+        fs::write(path![root, "node_modules", "pkg", "index.js"], b"").unwrap();
+        fs::write(path![root, "build", "main.o"], b"").unwrap();
+        fs::write(path![root, "build", "main"], b"").unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new()
+            .ignore(&["node_modules", "build/*.o"])
+            .unwrap();
+        forest.add_dir_recursively(root, options).unwrap();
+        let json = forest.to_json_tree().to_string();
+        assert!(!json.contains("node_modules"));
+        assert!(!json.contains("main.o"));
+        // root, build, build/main
+        assert_eq!(forest.node_count(), 3);
+
+        assert!(DirectoryAddOptions::new().ignore(&["a[b"]).is_err());
+    }
+
+    #[test]
+    pub fn emptied_directories_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(path![root, "a", "b", "c", "d"]).unwrap();
+        fs::create_dir(path![root, "empty"]).unwrap();
+        fs::write(path![root, "a", "keep"], b"").unwrap();
+        fs::write(path![root, "a", "b", "c", "d", "file"], b"").unwrap();
+        let mut forest = PathForest::<u32>::new();
+        forest
+            .add_dir_recursively(&root, DirectoryAddOptions::new())
+            .unwrap();
+
+        fs::remove_dir_all(path![root, "a", "b"]).unwrap();
+        forest.remove_path(&root, &path![root, "a", "b", "c", "d", "file"]);
+        assert_eq!(forest.prune_empty(), 3);
+
+        let mut paths = forest.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                root.clone(),
+                path![root, "a"],
+                path![root, "a", "keep"],
+                path![root, "empty"],
+            ]
+        );
+        assert_eq!(forest.prune_empty(), 0);
+    }
+
+    #[test]
+    pub fn rendered_like_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (first, second) = (path![root, "a"], path![root, "b"]);
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        let mut forest = PathForest::<u32>::new();
+        forest.add_path(&second, &second, 0, true);
+        forest.add_path(&first, &first, 0, true);
+        forest.add_path(&first, &path![first, "src", "main.rs"], 0, false);
+        forest.add_path(&first, &path![first, "src", "lib.rs"], 0, false);
+        forest.add_path(&first, &path![first, "Cargo.toml"], 0, false);
+        forest.add_path(&first, &path![first, "target"], 0, true);
+
+        let expected = format!(
+            "\
+{}/
+├── Cargo.toml
+├── src/
+│   ├── lib.rs
+│   └── main.rs
+└── target/
+{}/
+",
+            first.display(),
+            second.display()
+        );
+        assert_eq!(forest.render_tree(), expected);
+    }
+
+    #[test]
+    pub fn custom_node_info_filled_in() {
+        #[derive(Debug, Default)]
+        struct ScanInfo {
+            is_dir: Option<bool>,
+            size: u64,
+        }
+
+        impl NodeInfo for ScanInfo {
+            const NEEDS_METADATA: bool = true;
+
+            fn with_is_dir(self, is_dir: bool) -> Self {
+                Self {
+                    is_dir: Some(is_dir),
+                    ..self
+                }
+            }
+
+            fn with_metadata(self, metadata: &fs::Metadata) -> Self {
+                Self {
+                    size: metadata.len(),
+                    ..self
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(path![root, "a", "b"]).unwrap();
+        fs::write(path![root, "a", "b", "file"], b"12345").unwrap();
+
+        let mut forest = PathForest::<ScanInfo>::new();
+        forest
+            .add_dir_recursively(&root, DirectoryAddOptions::new())
+            .unwrap();
+        for path in [root.clone(), path![root, "a"], path![root, "a", "b"]] {
+            assert_eq!(forest.get(&path).unwrap().is_dir, Some(true));
+        }
+        let file = forest.get(path![root, "a", "b", "file"]).unwrap();
+        assert_eq!(file.is_dir, Some(false));
+        assert_eq!(file.size, 5);
+    }
+
+    #[test]
+    pub fn hidden_paths_not_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = path![dir.path(), ".dotted"];
+        fs::create_dir_all(path![root, ".git", "objects"]).unwrap();
+        fs::create_dir_all(path![root, "src"]).unwrap();
+        fs::write(path![root, ".env"], b"").unwrap();
+        fs::write(path![root, "src", ".hidden.rs"], b"").unwrap();
+        fs::write(path![root, "src", "main.rs"], b"").unwrap();
+
+        let mut forest = PathForest::<u32>::new();
+        let options = DirectoryAddOptions::new().skip_hidden(true);
+        forest.add_dir_recursively(&root, options).unwrap();
+        let mut paths = forest.paths().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                root.clone(),
+                path![root, "src"],
+                path![root, "src", "main.rs"],
+            ]
+        );
+    }
 }
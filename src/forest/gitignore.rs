@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::display_path;
+
+const GITIGNORE: &str = ".gitignore";
+
+/// The `.gitignore` files read so far, by the directory they are in
+#[derive(Debug, Default)]
+pub(crate) struct GitIgnores {
+    by_dir: Mutex<HashMap<PathBuf, GitIgnore>>,
+}
+
+impl GitIgnores {
+    /// Reads the `.gitignore` of `dir`, unless it is unchanged since last time
+    pub(crate) fn load(&self, dir: &Path) {
+        let path = dir.join(GITIGNORE);
+        let mut by_dir = self.by_dir.lock().unwrap();
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                by_dir.remove(dir);
+                return;
+            }
+        };
+        let stamp = (metadata.ino(), metadata.mtime(), metadata.mtime_nsec());
+        if by_dir.get(dir).is_some_and(|ignore| ignore.stamp == stamp) {
+            return;
+        }
+        match fs::read(&path) {
+            Ok(contents) => {
+                let contents = String::from_utf8_lossy(&contents);
+                by_dir.insert(dir.to_path_buf(), GitIgnore::parse(&contents, stamp));
+            }
+            Err(err) => {
+                log::debug!("Could not read {}: {}", display_path(&path), err);
+                by_dir.remove(dir);
+            }
+        }
+    }
+
+    /// Whether the `.gitignore` files from `root_path` down to the parent
+    /// of `path` exclude it. The deepest file with a matching pattern decides.
+    pub(crate) fn is_ignored(&self, root_path: &Path, path: &Path, is_dir: bool) -> bool {
+        let by_dir = self.by_dir.lock().unwrap();
+        for dir in path.ancestors().skip(1) {
+            let matched = by_dir
+                .get(dir)
+                .and_then(|ignore| ignore.matches(path.strip_prefix(dir).unwrap(), is_dir));
+            if let Some(ignored) = matched {
+                return ignored;
+            }
+            if dir == root_path {
+                break;
+            }
+        }
+        false
+    }
+}
+
+/// The patterns of one `.gitignore`
+#[derive(Debug)]
+struct GitIgnore {
+    /// Inode and mtime of the file, to tell whether it changed
+    stamp: (u64, i64, i64),
+    globs: GlobSet,
+    /// Per glob, in the same order
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// `!pattern`: re-includes what an earlier pattern excluded
+    negated: bool,
+    /// `pattern/`: only matches directories
+    dir_only: bool,
+}
+
+impl GitIgnore {
+    /// Invalid patterns are skipped
+    fn parse(contents: &str, stamp: (u64, i64, i64)) -> Self {
+        let mut builder = GlobSetBuilder::new();
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let mut pattern = line.trim_end();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            let negated = pattern.starts_with('!');
+            // `\#` and `\!` stand for a leading `#` and `!`
+            if negated || pattern.starts_with("\\#") || pattern.starts_with("\\!") {
+                pattern = &pattern[1..];
+            }
+            let dir_only = pattern.ends_with('/');
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            // A slash anywhere but at the end ties the pattern to this directory,
+            // otherwise it matches at any depth
+            let glob = match pattern.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            match GlobBuilder::new(&glob).literal_separator(true).build() {
+                Ok(glob) => {
+                    builder.add(glob);
+                    rules.push(Rule { negated, dir_only });
+                }
+                Err(err) => log::warn!("Skipping .gitignore pattern {:?}: {}", line, err),
+            }
+        }
+        let globs = builder.build().unwrap_or_else(|err| {
+            log::warn!("Skipping .gitignore: {}", err);
+            rules.clear();
+            GlobSet::empty()
+        });
+        Self {
+            stamp,
+            globs,
+            rules,
+        }
+    }
+
+    /// Whether the last pattern matching `relative` ignores it,
+    /// `None` if no pattern matches
+    fn matches(&self, relative: &Path, is_dir: bool) -> Option<bool> {
+        self.globs
+            .matches(relative)
+            .into_iter()
+            .rev()
+            .map(|idx| &self.rules[idx])
+            .find(|rule| is_dir || !rule.dir_only)
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn pattern_rules() {
+        let ignore = GitIgnore::parse(
+            "# comment\n*.o\n!keep.o\nbuild/\n/top.txt\ndocs/*.html\n\\#hash\n",
+            (0, 0, 0),
+        );
+        let matches = |path: &str, is_dir| ignore.matches(Path::new(path), is_dir);
+        assert_eq!(matches("a.o", false), Some(true));
+        assert_eq!(matches("sub/a.o", false), Some(true));
+        assert_eq!(matches("sub/keep.o", false), Some(false));
+        assert_eq!(matches("sub/build", true), Some(true));
+        assert_eq!(matches("sub/build", false), None);
+        assert_eq!(matches("top.txt", false), Some(true));
+        assert_eq!(matches("sub/top.txt", false), None);
+        assert_eq!(matches("docs/index.html", false), Some(true));
+        assert_eq!(matches("docs/api/index.html", false), None);
+        assert_eq!(matches("#hash", false), Some(true));
+        assert_eq!(matches("main.c", false), None);
+    }
+}
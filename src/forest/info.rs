@@ -1,2 +1,46 @@
-#[derive(Debug, Default)]
-pub struct BasicNodeInfo {}
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// What the forest keeps for each path. The methods fill in a
+/// `Self::default()` when the path is added, all of them doing nothing
+/// unless overridden.
+pub trait NodeInfo: Default {
+    /// Whether `with_metadata` is wanted: it costs a stat per added path
+    const NEEDS_METADATA: bool = false;
+
+    fn with_is_dir(self, _is_dir: bool) -> Self {
+        self
+    }
+
+    /// Only called with `NEEDS_METADATA`, with that of the symlink target
+    /// if the path is one
+    fn with_metadata(self, _metadata: &fs::Metadata) -> Self {
+        self
+    }
+}
+
+/// The info of a path being added. Left at what `with_is_dir` gives
+/// if its metadata can't be read.
+pub(crate) fn new_info<T: NodeInfo>(path: &Path, is_dir: bool) -> T {
+    let info = T::default().with_is_dir(is_dir);
+    if !T::NEEDS_METADATA {
+        return info;
+    }
+    match fs::metadata(path) {
+        Ok(metadata) => info.with_metadata(&metadata),
+        Err(_) => info,
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BasicNodeInfo {
+    pub is_dir: bool,
+}
+
+impl NodeInfo for BasicNodeInfo {
+    fn with_is_dir(self, is_dir: bool) -> Self {
+        Self { is_dir }
+    }
+}
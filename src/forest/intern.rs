@@ -0,0 +1,119 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    sync::{Arc, Mutex},
+};
+
+/// Pool size from which names no longer used get dropped
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Hands out shared storage for path component names.
+/// When interning is on, every occurrence of the same name (`src`, `index.js`, ...)
+/// across the whole forest points to one allocation.
+/// Names only the pool holds on to are dropped once it doubled in size
+/// since the last time, or on `prune`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NamePool {
+    names: Option<Arc<Mutex<Names>>>,
+}
+
+#[derive(Debug, Default)]
+struct Names {
+    set: HashSet<Arc<OsStr>>,
+    /// Size right after the last pruning
+    pruned_len: usize,
+}
+
+impl Names {
+    fn prune(&mut self) {
+        self.set.retain(|name| Arc::strong_count(name) > 1);
+        self.pruned_len = self.set.len();
+    }
+}
+
+impl NamePool {
+    /// A pool which does not intern, every name gets its own allocation.
+    pub(crate) fn new() -> Self {
+        Self { names: None }
+    }
+
+    pub(crate) fn interning() -> Self {
+        Self {
+            names: Some(Arc::new(Mutex::new(Names::default()))),
+        }
+    }
+
+    pub(crate) fn intern(&self, name: &OsStr) -> Arc<OsStr> {
+        match &self.names {
+            Some(names) => {
+                let mut names = names.lock().unwrap();
+                if let Some(name) = names.set.get(name) {
+                    return Arc::clone(name);
+                }
+                if names.set.len() >= MIN_PRUNE_LEN.max(2 * names.pruned_len) {
+                    names.prune();
+                }
+                let name: Arc<OsStr> = Arc::from(name);
+                names.set.insert(Arc::clone(&name));
+                name
+            }
+            None => Arc::from(name),
+        }
+    }
+
+    /// Drops the names no longer used anywhere in the forest
+    pub(crate) fn prune(&self) {
+        if let Some(names) = &self.names {
+            names.lock().unwrap().prune();
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.names
+            .as_ref()
+            .map_or(0, |names| names.lock().unwrap().set.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn interned_names_share_storage() {
+        let pool = NamePool::interning();
+        let first = pool.intern(OsStr::new("src"));
+        let second = pool.intern(OsStr::new("src"));
+        let other = pool.intern(OsStr::new("index.js"));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    pub fn non_interned_names_are_separate() {
+        let pool = NamePool::new();
+        let first = pool.intern(OsStr::new("src"));
+        let second = pool.intern(OsStr::new("src"));
+        assert_eq!(first, second);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    pub fn unused_names_are_dropped() {
+        let pool = NamePool::interning();
+        let kept = pool.intern(OsStr::new("kept"));
+        drop(pool.intern(OsStr::new("dropped")));
+        pool.prune();
+        assert_eq!(pool.len(), 1);
+        assert!(Arc::ptr_eq(&kept, &pool.intern(OsStr::new("kept"))));
+
+        // Without asking, once the pool doubled
+        for idx in 0..MIN_PRUNE_LEN {
+            pool.intern(OsStr::new(&idx.to_string()));
+        }
+        assert!(pool.len() < MIN_PRUNE_LEN);
+    }
+}
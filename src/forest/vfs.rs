@@ -0,0 +1,66 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use nix::sys::statfs;
+
+/// `statfs` magic numbers of kernel filesystems that don't hold user data.
+/// Scanning them is pointless at best, and some entries block when read.
+const VIRTUAL_FS_MAGICS: &[i64] = &[
+    0x9fa0,      // proc
+    0x6265_6572, // sysfs
+    0x1cd1,      // devpts
+    0x6462_6720, // debugfs
+    0x7472_6163, // tracefs
+    0x7363_6673, // securityfs
+    0x0027_e0eb, // cgroup
+    0x6367_7270, // cgroup2
+    0xcafe_4a11, // bpf
+    0x6165_676c, // pstore
+    0x6265_6570, // configfs
+    0xf97c_ff8c, // selinuxfs
+    0x1980_0202, // mqueue
+    0x4249_4e4d, // binfmt_misc
+    0x6573_5543, // fusectl
+];
+
+/// Shared by tmpfs and devtmpfs
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+
+/// Whether `path` is on a virtual (kernel) filesystem like /proc or /sys,
+/// or on the device filesystem of /dev.
+/// If the filesystem can't be determined, it is assumed to be a real one.
+pub(crate) fn is_virtual_fs(path: &Path) -> bool {
+    // The type of the magic number differs between targets
+    #[allow(clippy::unnecessary_cast)]
+    let magic = match statfs::statfs(path) {
+        Ok(stat) => stat.filesystem_type().0 as i64,
+        Err(_) => return false,
+    };
+    VIRTUAL_FS_MAGICS.contains(&magic) || magic == TMPFS_MAGIC && is_dev_fs(path)
+}
+
+/// devtmpfs can't be told from tmpfs by its magic number, but it is the one
+/// mounted on /dev. A tmpfs mounted below, like /dev/shm, holds user data.
+fn is_dev_fs(path: &Path) -> bool {
+    match (fs::metadata(path), fs::metadata("/dev")) {
+        (Ok(metadata), Ok(dev)) => metadata.dev() == dev.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn proc_is_virtual() {
+        assert!(!is_virtual_fs(&std::env::temp_dir()));
+        if Path::new("/proc/self").exists() {
+            assert!(is_virtual_fs(Path::new("/proc")));
+        }
+        if Path::new("/dev/null").exists() {
+            assert!(is_virtual_fs(Path::new("/dev")));
+        }
+    }
+}
@@ -9,12 +9,36 @@ macro_rules! path {
     };
 }
 
+/// How paths are shown in logs and error messages.
+/// Bytes which aren't valid UTF-8 are replaced with U+FFFD.
+pub(crate) fn display_path(path: &std::path::Path) -> std::borrow::Cow<'_, str> {
+    path.to_string_lossy()
+}
+
+/// Threads for a pool: one per CPU, but no more than `max_threads`
+pub(crate) fn pool_size(max_threads: usize) -> usize {
+    usize::min(num_cpus::get(), max_threads)
+}
+
 pub mod atomic;
 pub mod config;
 pub mod error;
 pub mod forest;
 pub mod reader;
 pub mod watcher;
+pub mod writer;
 
 #[cfg(not(unix))]
 compile_error!("Cannot compile TuxDrive on Non-Unix environments!");
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn pool_size_is_capped() {
+        assert!(pool_size(4) <= 4);
+        assert!(pool_size(4) >= 1);
+        assert_eq!(pool_size(1), 1);
+    }
+}
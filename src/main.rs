@@ -1,13 +1,24 @@
-use std::{io::Write, thread};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
 use colored::*;
+use crossbeam::channel::Sender;
+use nix::sys::signal::{SigSet, Signal};
 
 use tuxdrive::{
-    config::Config,
-    error::TuxDriveResult,
-    forest::{info::BasicNodeInfo, DirectoryAddOptions, PathForest},
+    config::{Config, TrackKind},
+    error::{TuxDriveError, TuxDriveResult},
+    forest::{info::BasicNodeInfo, PathForest},
     reader::{FileReader, ReadCommand, ReadCommandKind},
-    watcher::{WatchEventKind, Watcher},
+    watcher::{WatchEvent, WatchEventKind, Watcher, WatcherBackend},
 };
 
 fn main() {
@@ -27,47 +38,515 @@ fn main() {
         .write_style(env_logger::WriteStyle::Always)
         .init();
 
-    if let Err(e) = setup_and_run() {
+    let args = match Args::parse(env::args_os().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(e) = setup_and_run(args) {
         eprintln!("Error: {}", e);
         exit(1);
     }
 }
 
-const POLL_INTERVAL_SECS: u64 = 5;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Failed poll cycles retried before the watcher thread gives up
+const POLL_RETRIES: u32 = 3;
+/// How often the state is saved while running, so that a crash loses little
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const USAGE: &str = "Usage: tuxdrive [OPTIONS]
+
+Options:
+    --config <PATH>  Read the config from PATH instead of the usual locations
+    -h, --help       Print this help
+
+Sending SIGHUP reloads the watched paths from the config.";
 
-fn setup_and_run() -> TuxDriveResult<()> {
-    let config = Config::read()?;
-    let (mut watcher, event_recv) = Watcher::<{ POLL_INTERVAL_SECS }>::new()?;
+/// Command line arguments
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    config: Option<PathBuf>,
+}
+
+impl Args {
+    /// `None` if help was asked for
+    fn parse<I: Iterator<Item = OsString>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-h" | "--help") => return Ok(None),
+                Some("--config") => match args.next() {
+                    Some(path) => parsed.config = Some(PathBuf::from(path)),
+                    None => return Err("--config needs a path".to_string()),
+                },
+                _ => match arg.as_bytes().strip_prefix(b"--config=") {
+                    Some(path) => parsed.config = Some(PathBuf::from(OsStr::from_bytes(path))),
+                    None => return Err(format!("Unknown argument {:?}", arg)),
+                },
+            }
+        }
+        Ok(Some(parsed))
+    }
+}
+
+fn setup_and_run(args: Args) -> TuxDriveResult<()> {
+    // Blocked before any thread starts, so that all of them inherit the mask
+    // and the signals only reach the thread waiting for them
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals.thread_block()?;
+
+    let config = read_config(args.config.as_deref())?;
+    let poll_interval = config.poll_interval().unwrap_or(DEFAULT_POLL_INTERVAL);
+    let mut builder = Watcher::builder()
+        .poll_interval(poll_interval)
+        .poll_retries(POLL_RETRIES, Duration::from_secs(1));
+    if let Some(window) = config.debounce() {
+        builder = builder.debounce(window);
+    }
+    if let Some(jitter) = config.poll_jitter() {
+        builder = builder.poll_jitter(jitter);
+    }
+    if let Some(cap) = config.channel_capacity() {
+        builder = builder.channel_bounded(cap);
+    }
+    let read_on_close = config.read_on_close();
+    if read_on_close {
+        builder = builder.backend(WatcherBackend::Inotify);
+    }
+    let (mut watcher, event_recv) = builder.build()?;
     let mut path_forest = PathForest::<BasicNodeInfo>::new();
+    let mut tracks = Vec::new();
     for path_conf in config.paths() {
-        watcher.add_directory(path_conf.path().canonicalize()?, path_conf.recursive())?;
-        path_forest.add_dir_recursively(path_conf.path(), DirectoryAddOptions::new())?;
+        let options = path_conf.add_options()?;
+        let path = path_conf.path().canonicalize()?;
+        watcher.add_path_config(path_conf)?;
+        if path.is_file() {
+            path_forest.add_file(&path, options)?;
+        } else {
+            path_forest.add_dir_recursively(&path, options)?;
+        }
+        tracks.push((path, path_conf.track()));
+    }
+    let tracks = Arc::new(Mutex::new(Tracks {
+        current: tracks,
+        reloaded: None,
+    }));
+
+    // Only what changed while we were down gets reported
+    let state_path = state_path();
+    if let Some(state_path) = state_path.as_ref().filter(|path| path.exists()) {
+        if let Err(err) = watcher.restore_state(state_path) {
+            log::warn!("Could not restore the saved state: {}", err);
+        }
     }
 
     // Start the watcher
-    thread::spawn(move || watcher.start_polling());
+    let watcher_handle = watcher.handle();
+    let stop_handle = watcher.handle();
+    if let Some(state_path) = state_path {
+        watcher.save_state_every(state_path, STATE_SAVE_INTERVAL);
+    }
+    let watcher_thread = thread::spawn(move || watcher.start_polling());
 
-    let (file_reader, read_comm_sender, read_data_recv) = FileReader::new()?;
+    // Stop the watcher on Ctrl-C. Once it is done, the event channel closes,
+    // and the reads already asked for finish before exiting.
+    // On SIGHUP, watch the paths of the config as it is now instead.
+    let reload_tracks = Arc::clone(&tracks);
+    let config_path = args.config;
+    thread::spawn(move || {
+        while let Ok(signal) = signals.wait() {
+            if signal != Signal::SIGHUP {
+                log::info!("Got {}, shutting down", signal);
+                watcher_handle.stop();
+                return;
+            }
+            match read_config(config_path.as_deref()) {
+                Ok(config) => {
+                    log::info!("Got SIGHUP, reloading the watched paths");
+                    reload_tracks.lock().unwrap().reloaded = Some(tracks_of(&config));
+                    let tracks = Arc::clone(&reload_tracks);
+                    watcher_handle.set_directories_then(config.paths().to_vec(), move |res| {
+                        let mut tracks = tracks.lock().unwrap();
+                        let reloaded = tracks.reloaded.take();
+                        // Otherwise the watcher logged why, and kept the current paths
+                        if res.is_ok() {
+                            tracks.current = reloaded.unwrap_or_default();
+                        }
+                    });
+                }
+                Err(err) => log::error!("Keeping the current config, reloading failed: {}", err),
+            }
+        }
+    });
+
+    let (mut file_reader, read_comm_sender, _read_data_recv) = match config.channel_capacity() {
+        Some(cap) => FileReader::bounded(cap)?,
+        None => FileReader::new()?,
+    };
+    if let Some((threshold, chunk_size)) = config.streaming() {
+        file_reader = file_reader.stream_reads_above(threshold, chunk_size);
+    }
+    if let Some(max_size) = config.max_file_size() {
+        file_reader = file_reader.max_file_size(max_size);
+    }
+    if config.dry_run() {
+        log::info!("Dry run: changes are only logged, no file gets read");
+    }
+    let reads = ReadRequests {
+        sender: read_comm_sender,
+        dry_run: config.dry_run(),
+    };
 
     // Start the file reader
-    thread::spawn(move || file_reader.start_reader());
+    let reader_thread = thread::spawn(move || file_reader.start_reader());
 
     while let Ok(event) = event_recv.recv() {
         println!("{:?}", event);
-        match event.kind {
-            WatchEventKind::Create => todo!(),
-            WatchEventKind::Delete => todo!(),
-            WatchEventKind::Written => {
+        let res = match event.kind {
+            WatchEventKind::Overflow { dropped } => {
+                log::warn!(
+                    "{} events were dropped, reading all watched files again",
+                    dropped
+                );
+                let roots = tracks.lock().unwrap().current.clone();
+                request_all_reads(&roots, event.id, &reads)
+            }
+            _ => {
+                let track = tracks.lock().unwrap().track_for(&event.path);
+                request_reads(event, track, read_on_close, &reads)
+            }
+        };
+        if res.is_err() {
+            // The reader is gone, which only happens on an error
+            break;
+        }
+    }
+
+    // Whatever ended the loop, both threads finish before returning
+    drop(event_recv);
+    drop(reads);
+    stop_handle.stop();
+    let read_res = reader_thread.join().unwrap();
+    let watch_res = watcher_thread.join().unwrap();
+    read_res?;
+    watch_res
+}
+
+/// Asks the reader for what `event` calls for
+fn request_reads(
+    event: WatchEvent,
+    track: TrackKind,
+    read_on_close: bool,
+    reads: &ReadRequests,
+) -> TuxDriveResult<()> {
+    match event.kind {
+        WatchEventKind::Create => {
+            // Directories have no content of their own, their entries come as events.
+            // Reading a pipe would block, and take the data from its reader
+            if event.is_dir || is_fifo(&event.path) {
+                return Ok(());
+            }
+            // A file being written gets read once closed
+            if track.reads_content() && !(read_on_close && is_being_written(&event)) {
                 let read_comm = ReadCommand::new(&event.path, ReadCommandKind::Data, event.id);
-                read_comm_sender.send(read_comm).unwrap();
+                reads.send(read_comm)?;
             }
-            WatchEventKind::Chmod => {
+            if track.reads_permissions() {
                 let read_comm =
                     ReadCommand::new(&event.path, ReadCommandKind::Permission, event.id);
-                read_comm_sender.send(read_comm).unwrap();
+                reads.send(read_comm)?;
             }
         }
+        WatchEventKind::Delete => {
+            let read_comm = ReadCommand::new(&event.path, ReadCommandKind::Delete, event.id);
+            reads.send(read_comm)?;
+        }
+        WatchEventKind::Written | WatchEventKind::Truncated if !track.reads_content() => {}
+        // Partial content, the read comes once the writer closes the file
+        WatchEventKind::Written | WatchEventKind::Truncated
+            if read_on_close && !event.is_close_write() => {}
+        // Reading a pipe would block, and take the data from its reader
+        WatchEventKind::Written if is_fifo(&event.path) => {}
+        WatchEventKind::Chmod | WatchEventKind::Chown if !track.reads_permissions() => {}
+        WatchEventKind::Written | WatchEventKind::Truncated => {
+            let read_comm = ReadCommand::new(&event.path, ReadCommandKind::Data, event.id);
+            reads.send(read_comm)?;
+        }
+        WatchEventKind::Chmod => {
+            let read_comm = ReadCommand::new(&event.path, ReadCommandKind::Permission, event.id);
+            reads.send(read_comm)?;
+        }
+        WatchEventKind::Chown => {
+            let read_comm = ReadCommand::new(&event.path, ReadCommandKind::Ownership, event.id);
+            reads.send(read_comm)?;
+        }
+        // Nothing to read, changes made meanwhile come as separate events
+        WatchEventKind::AccessLost | WatchEventKind::AccessRegained => {}
+        WatchEventKind::Moved { from, to } => {
+            let read_comm = ReadCommand::new(&from, ReadCommandKind::Delete, event.id);
+            reads.send(read_comm)?;
+            // A moved directory brings no events for its entries
+            request_reads_under(vec![to], |_| track, event.id, reads)?;
+        }
+        // Nothing in particular to read
+        WatchEventKind::RootChanged => {}
+        // Reading it would only make for another one
+        WatchEventKind::Accessed => {}
+        // Only reported when asked for
+        WatchEventKind::NameCollision { .. } => {}
+        // All files get read again, see `request_all_reads`
+        WatchEventKind::Overflow { .. } => {}
     }
+    Ok(())
+}
+
+/// Asks the reader for every file under `roots`, for when events were lost
+fn request_all_reads(
+    roots: &[(PathBuf, TrackKind)],
+    event_id: u64,
+    reads: &ReadRequests,
+) -> TuxDriveResult<()> {
+    // Nested roots are walked as part of the outer one
+    let outer_roots = roots
+        .iter()
+        .filter(|(root, _)| {
+            root.parent()
+                .is_none_or(|parent| track_of_root(roots, parent).is_none())
+        })
+        .map(|(root, _)| root.clone())
+        .collect::<Vec<_>>();
+    let track_of = |path: &Path| track_of_root(roots, path).unwrap_or_default();
+    request_reads_under(outer_roots, track_of, event_id, reads)
+}
 
+/// Asks the reader for every file at or below `paths`, as `track_of` each file says
+fn request_reads_under<F>(
+    mut stack: Vec<PathBuf>,
+    track_of: F,
+    event_id: u64,
+    reads: &ReadRequests,
+) -> TuxDriveResult<()>
+where
+    F: Fn(&Path) -> TrackKind,
+{
+    while let Some(path) = stack.pop() {
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            // Gone meanwhile, its deletion comes as an event if still watched
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = path.read_dir() {
+                stack.extend(entries.flatten().map(|entry| entry.path()));
+            }
+            continue;
+        }
+        if !metadata.is_file() {
+            continue;
+        }
+        let track = track_of(&path);
+        if track.reads_content() {
+            let read_comm = ReadCommand::new(&path, ReadCommandKind::Data, event_id);
+            reads.send(read_comm)?;
+        }
+        if track.reads_permissions() {
+            let read_comm = ReadCommand::new(&path, ReadCommandKind::Permission, event_id);
+            reads.send(read_comm)?;
+        }
+    }
     Ok(())
 }
+
+/// Reads the config given on the command line, or else from the usual locations
+fn read_config(path: Option<&Path>) -> TuxDriveResult<Config> {
+    match path {
+        Some(path) => Config::read_from(path),
+        None => Config::read(),
+    }
+}
+
+/// The track kind of each watched path of `config`.
+/// Paths that can't be resolved are left out, the watcher reports them.
+fn tracks_of(config: &Config) -> Vec<(PathBuf, TrackKind)> {
+    config
+        .paths()
+        .iter()
+        .filter_map(|path_conf| {
+            let path = path_conf.path().canonicalize().ok()?;
+            Some((path, path_conf.track()))
+        })
+        .collect()
+}
+
+/// Hands the read commands to the reader, or only logs them on a dry run
+struct ReadRequests {
+    sender: Sender<ReadCommand>,
+    dry_run: bool,
+}
+
+impl ReadRequests {
+    /// Fails once the reader is gone, which only happens if it hit an error
+    fn send(&self, read_comm: ReadCommand) -> TuxDriveResult<()> {
+        if self.dry_run {
+            log::info!("Dry run, not sending {:?}", read_comm);
+            return Ok(());
+        }
+        self.sender
+            .send(read_comm)
+            .map_err(|_| TuxDriveError::ChannelClosed)
+    }
+}
+
+/// Where the state of the watched trees is kept between runs
+fn state_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("tuxdrive").join("state.json"))
+}
+
+/// Whether the event is for a file just created by a writer, which is
+/// followed by a close once the writer is done
+fn is_being_written(event: &WatchEvent) -> bool {
+    use nix::sys::inotify::AddWatchFlags;
+
+    event.raw_flags.is_some_and(|flags| {
+        AddWatchFlags::from_bits_truncate(flags).contains(AddWatchFlags::IN_CREATE)
+    })
+}
+
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    path.metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+/// The track kind of each watched path
+struct Tracks {
+    current: Vec<(PathBuf, TrackKind)>,
+    /// Those of a reload the watcher hasn't applied yet. Only used for paths
+    /// under none of the current ones: the new roots are reported before
+    /// the reload is known to succeed.
+    reloaded: Option<Vec<(PathBuf, TrackKind)>>,
+}
+
+impl Tracks {
+    fn track_for(&self, path: &Path) -> TrackKind {
+        match &self.reloaded {
+            Some(reloaded) if track_of_root(&self.current, path).is_none() => {
+                track_of_root(reloaded, path).unwrap_or_default()
+            }
+            _ => track_of_root(&self.current, path).unwrap_or_default(),
+        }
+    }
+}
+
+/// The track kind of the watched path `path` is under.
+/// With nested watched paths the innermost one wins.
+fn track_of_root(tracks: &[(PathBuf, TrackKind)], path: &Path) -> Option<TrackKind> {
+    tracks
+        .iter()
+        .filter(|(root, _)| path.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+        .map(|(_, track)| *track)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    pub fn dry_run_sends_no_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join("file"), b"content").unwrap();
+        let (mut watcher, events) = Watcher::builder()
+            .emit_initial_events(true)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let (reader, sender, data_recv) = FileReader::new().unwrap();
+        let reads = ReadRequests {
+            sender,
+            dry_run: true,
+        };
+
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert!(!events.is_empty());
+        for event in events {
+            request_reads(event, TrackKind::default(), false, &reads).unwrap();
+        }
+        drop(reads);
+        reader.start_reader().unwrap();
+        assert_eq!(data_recv.try_iter().count(), 0);
+    }
+
+    #[test]
+    pub fn moved_directory_files_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (from, to) = (root.join("old"), root.join("new"));
+        std::fs::create_dir(&to).unwrap();
+        std::fs::write(to.join("file"), b"content").unwrap();
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let reads = ReadRequests {
+            sender,
+            dry_run: false,
+        };
+        let event = WatchEvent {
+            path: to.clone(),
+            kind: WatchEventKind::Moved {
+                from: from.clone(),
+                to: to.clone(),
+            },
+            is_dir: true,
+            id: 1,
+            source_id: None,
+            raw_flags: None,
+            content_hash: None,
+        };
+
+        request_reads(event, TrackKind::default(), false, &reads).unwrap();
+
+        let expected = [
+            (from, ReadCommandKind::Delete),
+            (to.join("file"), ReadCommandKind::Data),
+            (to.join("file"), ReadCommandKind::Permission),
+        ]
+        .map(|(path, kind)| format!("{:?}", ReadCommand::new(path, kind, 1)));
+        let sent = receiver
+            .try_iter()
+            .map(|read_comm| format!("{:?}", read_comm))
+            .collect::<Vec<_>>();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    pub fn config_flag() {
+        let expected = Args {
+            config: Some(PathBuf::from("/tmp/dev.json")),
+        };
+        assert_eq!(parse(&[]), Ok(Some(Args::default())));
+        assert_eq!(parse(&["--config", "/tmp/dev.json"]), Ok(Some(expected)));
+        assert_eq!(
+            parse(&["--config=/tmp/dev.json"]).unwrap().unwrap().config,
+            Some(PathBuf::from("/tmp/dev.json"))
+        );
+        assert_eq!(parse(&["--config", "/tmp/dev.json", "--help"]), Ok(None));
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
@@ -1,49 +1,135 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
 
+use blake3::hazmat::{self, HasherExt};
 use crossbeam::channel::{Receiver, Sender};
+use nix::errno::Errno;
 use nix::fcntl::{self, OFlag};
+use nix::libc;
 use nix::sys::stat::{FileStat, Mode};
-use nix::unistd;
+use nix::unistd::{self, Whence};
 use rayon::{ThreadPool, ThreadPoolBuilder};
+use sha2::Digest;
 
 #[cfg(test)]
 use derive_builder::Builder;
 
-use crate::error::TuxDriveResult;
+use crate::display_path;
+use crate::error::{TuxDriveError, TuxDriveResult};
 
 #[derive(Debug)]
 pub struct ReadCommand {
     path: PathBuf,
     kind: ReadCommandKind,
-    event_id: u32,
+    event_id: u64,
+    known_digest: Option<[u8; 32]>,
 }
 
 impl ReadCommand {
-    pub fn new<P: AsRef<Path>>(path: P, kind: ReadCommandKind, event_id: u32) -> Self {
+    pub fn new<P: AsRef<Path>>(path: P, kind: ReadCommandKind, event_id: u64) -> Self {
         Self {
             path: path.as_ref().to_path_buf(),
             kind,
             event_id,
+            known_digest: None,
         }
     }
+
+    /// The BLAKE3 digest of the content, already known from the event
+    /// (`WatchEvent::content_hash`). A BLAKE3 `Hash` read then answers
+    /// with it instead of reading the file again.
+    pub fn with_digest(mut self, digest: [u8; 32]) -> Self {
+        self.known_digest = Some(digest);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum ReadCommandKind {
     Data,
     Permission,
+    /// Owning user and group
+    Ownership,
+    /// Hash of every `block_size` bytes of the content.
+    /// Comparing these against earlier ones tells which blocks changed.
+    /// A zero `block_size` fails with `InvalidBlockSize`.
+    BlockHashes {
+        block_size: usize,
+    },
+    /// Size and timestamps
+    Metadata,
+    /// Content from `offset` on, to continue an interrupted read.
+    /// The consumer keeps track of how far it got.
+    ResumeData {
+        offset: u64,
+    },
+    /// BLAKE3 tree of the content, with `block_size` bytes per leaf.
+    /// `block_size` must be a power of two, and at least 1024 bytes
+    /// (the BLAKE3 chunk size), or else it fails with `InvalidBlockSize`.
+    MerkleTree {
+        block_size: usize,
+    },
+    /// Digest of the content, computed while reading so the whole file is
+    /// never held in memory. An unchanged digest after an mtime change means
+    /// the content is the same.
+    Hash {
+        algorithm: HashAlgorithm,
+    },
+    /// Names and values of all extended attributes (`user.*`, SELinux labels, ...)
+    Xattrs,
+    /// The path was removed, nothing is read.
+    /// Answered with `ReadDataContent::Delete`, in order with the other reads.
+    Delete,
 }
 
 impl ReadCommand {
     fn process(&self) -> TuxDriveResult<ReadData> {
+        let mut possibly_inconsistent = false;
+        let mut hard_linked = false;
         let content = match self.kind {
             ReadCommandKind::Data => {
-                if let Some(data) = read_deletable_file(&self.path)? {
+                if let Some((data, stat, torn)) = read_stable_file(&self.path)? {
+                    possibly_inconsistent = torn;
+                    hard_linked = self.check_hard_links(&stat);
                     ReadDataContent::Data(data)
                 } else {
                     ReadDataContent::Delete
                 }
             }
+            ReadCommandKind::BlockHashes { block_size } => {
+                if block_size == 0 {
+                    return Err(TuxDriveError::InvalidBlockSize(block_size));
+                }
+                if let Some((data, stat, torn)) = read_stable_file(&self.path)? {
+                    possibly_inconsistent = torn;
+                    hard_linked = self.check_hard_links(&stat);
+                    ReadDataContent::BlockHashes(block_hashes(&data, block_size))
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::ResumeData { offset } => {
+                if let Some((data, stat)) = read_deletable_file_from(&self.path, offset)? {
+                    hard_linked = self.check_hard_links(&stat);
+                    ReadDataContent::DataAt { offset, data }
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::Metadata => {
+                if let Some(stat) = stat_deletable_file(&self.path)? {
+                    ReadDataContent::Metadata((&stat).into())
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
             ReadCommandKind::Permission => {
                 if let Some(stat) = stat_deletable_file(&self.path)? {
                     let perm_bits = (stat.st_mode & 0o7777) as u16;
@@ -52,15 +138,174 @@ impl ReadCommand {
                     ReadDataContent::Delete
                 }
             }
+            ReadCommandKind::Ownership => {
+                if let Some(stat) = stat_deletable_file(&self.path)? {
+                    ownership_of(&stat)
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::MerkleTree { block_size } => {
+                if !MerkleTree::valid_block_size(block_size) {
+                    return Err(TuxDriveError::InvalidBlockSize(block_size));
+                }
+                if let Some((data, stat, torn)) = read_stable_file(&self.path)? {
+                    possibly_inconsistent = torn;
+                    hard_linked = self.check_hard_links(&stat);
+                    ReadDataContent::MerkleTree(MerkleTree::new(&data, block_size))
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::Hash {
+                algorithm: HashAlgorithm::Blake3,
+            } if self.known_digest.is_some() => {
+                if let Some(stat) = stat_deletable_file(&self.path)? {
+                    hard_linked = self.check_hard_links(&stat);
+                    ReadDataContent::Hash(ContentHash {
+                        algorithm: HashAlgorithm::Blake3,
+                        digest: self.known_digest.unwrap(),
+                    })
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::Hash { algorithm } => {
+                if let Some((hash, stat, torn)) = hash_stable_file(&self.path, algorithm)? {
+                    possibly_inconsistent = torn;
+                    hard_linked = self.check_hard_links(&stat);
+                    ReadDataContent::Hash(hash)
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::Xattrs => {
+                if let Some(xattrs) = read_deletable_xattrs(&self.path)? {
+                    ReadDataContent::Xattrs(xattrs)
+                } else {
+                    ReadDataContent::Delete
+                }
+            }
+            ReadCommandKind::Delete => ReadDataContent::Delete,
         };
         Ok(ReadData {
             content,
             event_id: self.event_id,
+            possibly_inconsistent,
+            hard_linked,
         })
     }
+
+    /// Warns if the file read has other hard links, as copying it
+    /// separately breaks the link
+    fn check_hard_links(&self, stat: &FileStat) -> bool {
+        let hard_linked = stat.st_nlink > 1;
+        if hard_linked {
+            log::warn!(
+                "{} has {} hard links, a copy of it won't share its inode",
+                display_path(&self.path),
+                stat.st_nlink
+            );
+        }
+        hard_linked
+    }
+}
+
+/// Reads the file, and checks it didn't change while being read.
+/// If it did, the read is retried once. The returned flag is true if
+/// the file changed during the retry as well, so the data may be torn.
+/// Also returns the stat of the file taken right after reading.
+fn read_stable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<(Vec<u8>, FileStat, bool)>> {
+    read_stable(path.as_ref(), |path| {
+        Ok(read_deletable_file(path)?.map(|(data, stat)| {
+            let len = data.len() as u64;
+            (data, len, stat)
+        }))
+    })
+}
+
+/// Like `read_stable_file`, but only keeps the digest of the content
+fn hash_stable_file<P: AsRef<Path>>(
+    path: P,
+    algorithm: HashAlgorithm,
+) -> TuxDriveResult<Option<(ContentHash, FileStat, bool)>> {
+    read_stable(path.as_ref(), |path| {
+        let mut hasher = ContentHasher::new(algorithm);
+        let read = read_deletable_file_with(path, 0, |bytes| hasher.update(bytes))?;
+        Ok(read.map(|(len, stat)| (hasher.finalize(), len, stat)))
+    })
 }
 
-fn read_deletable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<Vec<u8>>> {
+/// BLAKE3 digest of the content, the same one a `Hash` read gives.
+/// `None` if the file is gone, kept changing while being read, or isn't a
+/// regular file: opening a FIFO would block until it has a writer.
+pub(crate) fn content_digest<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<[u8; 32]>> {
+    if !path.as_ref().is_file() {
+        return Ok(None);
+    }
+    let hashed = hash_stable_file(path, HashAlgorithm::Blake3)?;
+    Ok(hashed.and_then(|(hash, _, torn)| (!torn).then_some(hash.digest)))
+}
+
+/// Runs `read`, which returns what it read, the number of bytes read and the
+/// stat taken before reading, until the file looks unchanged by the read.
+/// Gives up after the second attempt, flagging the result as possibly torn.
+fn read_stable<T, F>(path: &Path, read: F) -> TuxDriveResult<Option<(T, FileStat, bool)>>
+where
+    F: Fn(&Path) -> TuxDriveResult<Option<(T, u64, FileStat)>>,
+{
+    fn same_file_state(before: &FileStat, after: &FileStat) -> bool {
+        before.st_ino == after.st_ino
+            && before.st_size == after.st_size
+            && before.st_mtime == after.st_mtime
+            && before.st_mtime_nsec == after.st_mtime_nsec
+    }
+
+    let mut last_read = None;
+    for _attempt in 0..2 {
+        let (data, len, before) = match read(path)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let after = match stat_deletable_file(path)? {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+        if same_file_state(&before, &after) && len as i64 == after.st_size {
+            return Ok(Some((data, after, false)));
+        }
+        last_read = Some((data, after));
+    }
+    Ok(last_read.map(|(data, stat)| (data, stat, true)))
+}
+
+/// Also returns the stat of the file taken right before reading
+fn read_deletable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<(Vec<u8>, FileStat)>> {
+    read_deletable_file_from(path, 0)
+}
+
+/// Reads the file starting at byte `offset`
+fn read_deletable_file_from<P: AsRef<Path>>(
+    path: P,
+    offset: u64,
+) -> TuxDriveResult<Option<(Vec<u8>, FileStat)>> {
+    let mut data = Vec::new();
+    let read = read_deletable_file_with(path, offset, |bytes| data.extend(bytes))?;
+    Ok(read.map(|(_, stat)| (data, stat)))
+}
+
+/// Reads the file starting at byte `offset`, passing the content to `sink`
+/// a buffer at a time. Returns the number of bytes read, and the stat of
+/// the file taken right before reading.
+fn read_deletable_file_with<P, F>(
+    path: P,
+    offset: u64,
+    mut sink: F,
+) -> TuxDriveResult<Option<(u64, FileStat)>>
+where
+    P: AsRef<Path>,
+    F: FnMut(&[u8]),
+{
     use nix::errno::Errno;
 
     let fd = match fcntl::open(path.as_ref(), OFlag::O_RDONLY, Mode::empty()) {
@@ -69,43 +314,223 @@ fn read_deletable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<Vec<u8>
             if err == Errno::ENOENT || err == Errno::EACCES || err == Errno::EISDIR {
                 return Ok(None);
             } else {
-                return Err(err.into());
+                return Err(TuxDriveError::nix_at(&path, err));
             }
         }
     };
+    // SAFETY: `open` just handed out this descriptor and nothing else owns it.
+    // Owning it closes it on every early return below.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
 
-    let size = if let Some(stat) = stat_deletable_file(&path)? {
-        stat.st_size
+    let stat = if let Some(stat) = stat_deletable_file(&path)? {
+        stat
     } else {
         return Ok(None);
     };
+    if offset > 0 {
+        unistd::lseek(fd.as_raw_fd(), offset as i64, Whence::SeekSet)
+            .map_err(|err| TuxDriveError::nix_at(&path, err))?;
+    }
 
     const BUF_SIZE: usize = 1024;
     let mut buf = [0u8; BUF_SIZE];
-    let mut data: Vec<u8> = Vec::with_capacity(size as usize);
+    let mut total_read = 0;
     loop {
-        let bytes_read = match unistd::read(fd, &mut buf) {
+        let bytes_read = match unistd::read(fd.as_raw_fd(), &mut buf) {
             Ok(bt) => bt,
+            // Some pseudo-files refuse plain reads, but may take a buffered one.
+            // Only possible before anything went to `sink`.
+            Err(Errno::EINVAL) if total_read == 0 => {
+                drop(fd);
+                return read_buffered_fallback(path.as_ref(), offset, sink)
+                    .map(|read| read.map(|len| (len, stat)));
+            }
+            Err(Errno::EINVAL) => {
+                return Err(TuxDriveError::UnreadableFile(path.as_ref().to_path_buf()));
+            }
             Err(err) => {
                 if err == Errno::ENOENT || err == Errno::EACCES || err == Errno::EISDIR {
                     return Ok(None);
                 } else {
-                    return Err(err.into());
+                    return Err(TuxDriveError::nix_at(&path, err));
                 }
             }
         };
-        data.extend(&buf[..bytes_read]);
-        if bytes_read < BUF_SIZE {
+        if bytes_read == 0 {
             break;
         }
+        sink(&buf[..bytes_read]);
+        total_read += bytes_read as u64;
+    }
+
+    unistd::close(fd.into_raw_fd()).map_err(|err| TuxDriveError::nix_at(&path, err))?;
+    Ok(Some((total_read, stat)))
+}
+
+/// Reads the file with a buffered `read_to_end`, for files plain reads fail on.
+/// Fails with `TuxDriveError::UnreadableFile` if the file still can't be read.
+fn read_buffered_fallback<F>(path: &Path, offset: u64, mut sink: F) -> TuxDriveResult<Option<u64>>
+where
+    F: FnMut(&[u8]),
+{
+    let read = File::open(path).and_then(|file| {
+        let mut file = BufReader::new(file);
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    });
+    match read {
+        Ok(data) => {
+            sink(&data);
+            Ok(Some(data.len() as u64))
+        }
+        Err(err) if err.raw_os_error() == Some(nix::errno::Errno::EINVAL as i32) => {
+            Err(TuxDriveError::UnreadableFile(path.to_path_buf()))
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(TuxDriveError::io_at(path, err)),
+    }
+}
+
+/// Algorithm of a content hash
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    /// Slower, for compatibility with tools that expect SHA-256
+    Sha256,
+}
+
+/// Digest of the content of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentHash {
+    pub algorithm: HashAlgorithm,
+    pub digest: [u8; 32],
+}
+
+enum ContentHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl ContentHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Self::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> ContentHash {
+        match self {
+            Self::Blake3(hasher) => ContentHash {
+                algorithm: HashAlgorithm::Blake3,
+                digest: *hasher.finalize().as_bytes(),
+            },
+            Self::Sha256(hasher) => ContentHash {
+                algorithm: HashAlgorithm::Sha256,
+                digest: hasher.finalize().into(),
+            },
+        }
+    }
+}
+
+/// First 16 bytes of the BLAKE3 hash of a block
+pub type BlockHash = [u8; 16];
+
+/// `block_size` must not be zero
+fn block_hashes(data: &[u8], block_size: usize) -> Vec<BlockHash> {
+    data.chunks(block_size)
+        .map(|block| {
+            let mut block_hash = [0u8; 16];
+            block_hash.copy_from_slice(&blake3::hash(block).as_bytes()[..16]);
+            block_hash
+        })
+        .collect()
+}
+
+/// Hashes of the BLAKE3 tree of some content, level by level.
+/// The leaves are the chaining values of the blocks, and the root is the
+/// BLAKE3 hash of the whole content.
+/// Comparing two trees from the root down finds the changed blocks
+/// without comparing every leaf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    pub block_size: usize,
+    /// `levels[0]` are the leaves, the last level only holds the root.
+    /// When a level has an odd count, its last node moves up unchanged.
+    pub levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    fn valid_block_size(block_size: usize) -> bool {
+        block_size.is_power_of_two() && block_size >= blake3::CHUNK_LEN
+    }
+
+    /// `block_size` must be valid, see `valid_block_size`
+    fn new(data: &[u8], block_size: usize) -> Self {
+        if data.len() <= block_size {
+            // A single block is the root itself
+            return Self {
+                block_size,
+                levels: vec![vec![*blake3::hash(data).as_bytes()]],
+            };
+        }
+        let leaves = data
+            .chunks(block_size)
+            .enumerate()
+            .map(|(idx, block)| {
+                blake3::Hasher::new()
+                    .set_input_offset((idx * block_size) as u64)
+                    .update(block)
+                    .finalize_non_root()
+            })
+            .collect::<Vec<_>>();
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let is_root = level.len() == 2;
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] if is_root => {
+                        *hazmat::merge_subtrees_root(left, right, hazmat::Mode::Hash).as_bytes()
+                    }
+                    [left, right] => {
+                        hazmat::merge_subtrees_non_root(left, right, hazmat::Mode::Hash)
+                    }
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(parents);
+        }
+        Self { block_size, levels }
     }
 
-    unistd::close(fd)?;
-    Ok(Some(data))
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().unwrap()[0]
+    }
 }
 
 fn stat_deletable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<FileStat>> {
-    use nix::errno::Errno;
     use nix::sys::stat;
 
     match stat::stat(path.as_ref()) {
@@ -114,23 +539,243 @@ fn stat_deletable_file<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<FileSta
             if err == Errno::ENOENT || err == Errno::EACCES {
                 Ok(None)
             } else {
-                Err(err.into())
+                Err(TuxDriveError::nix_at(path, err))
+            }
+        }
+    }
+}
+
+/// All extended attributes of the file, `None` if it is gone.
+/// A filesystem without xattr support gives none, and attributes we may not
+/// read (e.g. `trusted.*`) are left out. Fails with
+/// `TuxDriveError::UnreadableFile` if they can't be listed at all.
+fn read_deletable_xattrs<P: AsRef<Path>>(path: P) -> TuxDriveResult<Option<Vec<Xattr>>> {
+    let c_path = CString::new(path.as_ref().as_os_str().as_bytes()).map_err(|err| {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, err);
+        TuxDriveError::io_at(&path, err)
+    })?;
+    // SAFETY: the path is NUL-terminated, and the buffer is valid for its length
+    let names = xattr_buffer(|buf| unsafe {
+        libc::listxattr(c_path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    });
+    let names = match names {
+        Ok(names) => names,
+        Err(Errno::ENOENT) => return Ok(None),
+        Err(Errno::EACCES) => {
+            return Err(TuxDriveError::UnreadableFile(path.as_ref().to_path_buf()))
+        }
+        Err(Errno::ENOTSUP) => return Ok(Some(Vec::new())),
+        Err(err) => return Err(TuxDriveError::nix_at(&path, err)),
+    };
+    let mut xattrs = Vec::new();
+    // The names come one after the other, each NUL-terminated
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name).unwrap();
+        // SAFETY: as above, the name is NUL-terminated too
+        let value = xattr_buffer(|buf| unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        });
+        match value {
+            Ok(value) => xattrs.push((OsString::from_vec(name.to_vec()), value)),
+            // Removed since it was listed, or not for us to read
+            Err(Errno::ENODATA | Errno::EACCES) => {}
+            Err(Errno::ENOENT) => return Ok(None),
+            Err(err) => return Err(TuxDriveError::nix_at(&path, err)),
+        }
+    }
+    Ok(Some(xattrs))
+}
+
+/// Calls `get` (`listxattr` or `getxattr`) with a buffer just large enough,
+/// after asking for the size. Asks again if the result grew meanwhile.
+fn xattr_buffer<F>(get: F) -> nix::Result<Vec<u8>>
+where
+    F: Fn(&mut [u8]) -> libc::ssize_t,
+{
+    loop {
+        let size = Errno::result(get(&mut []))? as usize;
+        let mut buf = vec![0; size];
+        match Errno::result(get(&mut buf)) {
+            Ok(len) => {
+                buf.truncate(len as usize);
+                return Ok(buf);
             }
+            Err(Errno::ERANGE) => continue,
+            Err(err) => return Err(err),
         }
     }
 }
 
+/// `ReadDataContent::Skipped` if the file is larger than `max_size`
+fn skip_too_large(comm: &ReadCommand, max_size: u64) -> TuxDriveResult<Option<ReadData>> {
+    let size = match stat_deletable_file(&comm.path)? {
+        Some(stat) if stat.st_size as u64 > max_size => stat.st_size as u64,
+        _ => return Ok(None),
+    };
+    log::warn!(
+        "Skipping {}, its {} bytes are over the limit",
+        display_path(&comm.path),
+        size
+    );
+    Ok(Some(ReadData {
+        content: ReadDataContent::Skipped { size },
+        event_id: comm.event_id,
+        possibly_inconsistent: false,
+        hard_linked: false,
+    }))
+}
+
+fn ownership_of(stat: &FileStat) -> ReadDataContent {
+    ReadDataContent::Ownership {
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+    }
+}
+
+/// Name and value of an extended attribute
+pub type Xattr = (OsString, Vec<u8>);
+
 #[derive(Debug)]
 pub enum ReadDataContent {
     Data(Vec<u8>),
+    /// Content from `offset` to the end of the file: what was appended since
+    /// the last read (tail reads), or the rest of a resumed read
+    DataAt {
+        offset: u64,
+        data: Vec<u8>,
+    },
     Permission(FilePermission),
+    Ownership {
+        uid: u32,
+        gid: u32,
+    },
+    BlockHashes(Vec<BlockHash>),
+    MerkleTree(MerkleTree),
+    Hash(ContentHash),
+    /// Content of a large file, see `FileReader::stream_reads_above`
+    Stream(DataStream),
+    /// The file exists but its content can't be read (e.g. some pseudo-files).
+    /// It is skipped.
+    Unreadable,
+    Metadata(FileMeta),
+    Xattrs(Vec<Xattr>),
+    /// The file is larger than `FileReader::max_file_size` allows,
+    /// its content wasn't read
+    Skipped {
+        size: u64,
+    },
     Delete,
 }
 
+/// Content of a file, read a chunk at a time while being iterated over,
+/// so that only one chunk is in memory at once.
+/// Iteration stops early if the file can no longer be read or shrinks,
+/// in which case `is_complete` is false once iteration is over.
+#[derive(Debug)]
+pub struct DataStream {
+    file: File,
+    chunk_size: usize,
+    size: u64,
+    read: u64,
+    failed: bool,
+    done: bool,
+}
+
+impl DataStream {
+    /// Size of the file when the stream was opened
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the whole file was read, as it was when the stream was opened.
+    /// Only meaningful after iterating until the end.
+    pub fn is_complete(&self) -> bool {
+        self.done && !self.failed && self.read == self.size
+    }
+}
+
+impl Iterator for DataStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = vec![0u8; self.chunk_size];
+        let mut filled = 0;
+        while filled < self.chunk_size {
+            match self.file.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => filled += bytes_read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    log::debug!("Stopped streaming after {} bytes: {}", self.read, err);
+                    self.failed = true;
+                    break;
+                }
+            }
+        }
+        if filled < self.chunk_size {
+            self.done = true;
+        }
+        if filled == 0 {
+            return None;
+        }
+        chunk.truncate(filled);
+        self.read += filled as u64;
+        Some(chunk)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nsecs: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    pub size: u64,
+    /// Number of hard links
+    pub nlink: u64,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+}
+
+impl From<&FileStat> for FileMeta {
+    fn from(stat: &FileStat) -> Self {
+        Self {
+            size: stat.st_size as u64,
+            nlink: stat.st_nlink,
+            atime: Timestamp {
+                secs: stat.st_atime,
+                nsecs: stat.st_atime_nsec,
+            },
+            mtime: Timestamp {
+                secs: stat.st_mtime,
+                nsecs: stat.st_mtime_nsec,
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ReadData {
     pub content: ReadDataContent,
-    pub event_id: u32,
+    pub event_id: u64,
+    /// The file kept changing while it was read, so the content may be torn.
+    /// Re-request it once the file has settled.
+    pub possibly_inconsistent: bool,
+    /// The file has other hard links, which a plain copy doesn't preserve.
+    pub hard_linked: bool,
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -156,6 +801,13 @@ impl From<u8> for NormalPermission {
     }
 }
 
+impl NormalPermission {
+    /// The inverse of `From<u8>`
+    pub fn to_bits(&self) -> u8 {
+        (self.read as u8) << 2 | (self.write as u8) << 1 | self.execute as u8
+    }
+}
+
 #[derive(Debug, Default, PartialEq, Eq)]
 #[cfg_attr(test, derive(Builder))]
 #[cfg_attr(test, builder(setter(into), default))]
@@ -179,6 +831,13 @@ impl From<u8> for SpecialPermission {
     }
 }
 
+impl SpecialPermission {
+    /// The inverse of `From<u8>`
+    pub fn to_bits(&self) -> u8 {
+        (self.suid as u8) << 2 | (self.sgid as u8) << 1 | self.sticky as u8
+    }
+}
+
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct FilePermission {
     pub user: NormalPermission,
@@ -206,41 +865,344 @@ impl From<u16> for FilePermission {
     }
 }
 
+impl FilePermission {
+    /// The inverse of `From<u16>`, e.g. `0o4755`
+    pub fn to_mode_bits(&self) -> u16 {
+        (self.spec.to_bits() as u16) << 9
+            | (self.user.to_bits() as u16) << 6
+            | (self.group.to_bits() as u16) << 3
+            | self.other.to_bits() as u16
+    }
+}
+
+/// Symbolic form, as shown by `ls -l`, e.g. `rwsr-xr-x`
+impl fmt::Display for FilePermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The special bit takes the place of execute: lowercase if execute is set too
+        let triplet = |perm: &NormalPermission, special: bool, special_char: char| {
+            let execute = match (perm.execute, special) {
+                (false, false) => '-',
+                (true, false) => 'x',
+                (false, true) => special_char.to_ascii_uppercase(),
+                (true, true) => special_char,
+            };
+            [
+                if perm.read { 'r' } else { '-' },
+                if perm.write { 'w' } else { '-' },
+                execute,
+            ]
+        };
+        let chars = [
+            triplet(&self.user, self.spec.suid, 's'),
+            triplet(&self.group, self.spec.sgid, 's'),
+            triplet(&self.other, self.spec.sticky, 't'),
+        ];
+        for c in chars.iter().flatten() {
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct FileReader {
     command_recv: Receiver<ReadCommand>,
     data_send: Sender<ReadData>,
     pool: ThreadPool,
+    read_permits: Semaphore,
+    tail_threshold: Option<u64>,
+    /// Size from which files are streamed, and the chunk size
+    stream: Option<(u64, usize)>,
+    max_file_size: Option<u64>,
+    read_offsets: Mutex<HashMap<PathBuf, ReadOffset>>,
+}
+
+/// How far a file was read, for tail reads
+#[derive(Debug, Clone, Copy)]
+struct ReadOffset {
+    ino: u64,
+    offset: u64,
 }
 
 const MAX_NUM_THREADS: usize = 4;
 
 impl FileReader {
     pub fn new() -> TuxDriveResult<(Self, Sender<ReadCommand>, Receiver<ReadData>)> {
-        let (command_send, command_recv) = crossbeam::channel::unbounded();
+        Self::with_concurrency(usize::MAX)
+    }
+
+    /// Processes at most `max_concurrent_reads` commands at once, whatever
+    /// the size of the thread pool. Keeps the disk from thrashing when many
+    /// files change at the same time.
+    pub fn with_concurrency(
+        max_concurrent_reads: usize,
+    ) -> TuxDriveResult<(Self, Sender<ReadCommand>, Receiver<ReadData>)> {
+        Self::create(max_concurrent_reads, None)
+    }
+
+    /// Queues at most `command_capacity` commands not yet processed:
+    /// sending another one blocks until the reader catches up,
+    /// rather than letting a burst of commands grow the queue without bound.
+    pub fn bounded(
+        command_capacity: usize,
+    ) -> TuxDriveResult<(Self, Sender<ReadCommand>, Receiver<ReadData>)> {
+        Self::create(usize::MAX, Some(command_capacity))
+    }
+
+    fn create(
+        max_concurrent_reads: usize,
+        command_capacity: Option<usize>,
+    ) -> TuxDriveResult<(Self, Sender<ReadCommand>, Receiver<ReadData>)> {
+        assert!(
+            max_concurrent_reads > 0,
+            "Expected at least one concurrent read"
+        );
+        let (command_send, command_recv) = match command_capacity {
+            Some(cap) => crossbeam::channel::bounded(cap),
+            None => crossbeam::channel::unbounded(),
+        };
         let (data_send, data_recv) = crossbeam::channel::unbounded();
-        let num_threads = usize::max(num_cpus::get(), MAX_NUM_THREADS);
+        let num_threads = crate::pool_size(MAX_NUM_THREADS);
         let pool = ThreadPoolBuilder::new().num_threads(num_threads).build()?;
         let ob = Self {
             command_recv,
             data_send,
             pool,
+            read_permits: Semaphore::new(max_concurrent_reads),
+            tail_threshold: None,
+            stream: None,
+            max_file_size: None,
+            read_offsets: Mutex::new(HashMap::new()),
         };
         Ok((ob, command_send, data_recv))
     }
 
+    /// For `Data` reads of files of at least `threshold` bytes, only read
+    /// what was appended since the previous read of the same path,
+    /// answering with `ReadDataContent::DataAt`.
+    /// The whole file is read again if it shrank or was replaced.
+    /// Meant for files that keep growing, like logs.
+    pub fn tail_reads_above(mut self, threshold: u64) -> Self {
+        self.tail_threshold = Some(threshold);
+        self
+    }
+
+    /// For `Data` reads of files of at least `threshold` bytes, answer with
+    /// a `ReadDataContent::Stream` reading `chunk_size` bytes at a time,
+    /// instead of reading the whole file into memory.
+    /// Larger chunks mean fewer reads and higher throughput, at the cost of
+    /// memory: each stream holds one chunk at a time.
+    /// Tail reads, if enabled, take precedence.
+    pub fn stream_reads_above(mut self, threshold: u64, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Expected a non-zero chunk size");
+        self.stream = Some((threshold, chunk_size));
+        self
+    }
+
+    /// For `Data` reads of files larger than `max_size` bytes, answer with
+    /// `ReadDataContent::Skipped` instead of reading them.
+    /// Takes precedence over tail and streamed reads.
+    /// Default: no limit
+    pub fn max_file_size(mut self, max_size: u64) -> Self {
+        self.max_file_size = Some(max_size);
+        self
+    }
+
+    /// Processes commands on all threads of the pool.
+    /// Returns once all command senders (or the data receiver) are dropped,
+    /// with the first error any thread ran into.
     pub fn start_reader(&self) -> TuxDriveResult<()> {
-        for _i in 0..self.pool.current_num_threads() {
-            self.pool.install(|| -> TuxDriveResult<()> {
-                loop {
-                    let comm = self.command_recv.recv().unwrap();
-                    let data = comm.process()?;
-                    self.data_send.send(data).unwrap();
+        let first_err = Mutex::new(None);
+        self.pool.scope(|scope| {
+            for _i in 0..self.pool.current_num_threads() {
+                scope.spawn(|_| {
+                    if let Err(err) = self.run_worker() {
+                        first_err.lock().unwrap().get_or_insert(err);
+                    }
+                });
+            }
+        });
+        match first_err.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn run_worker(&self) -> TuxDriveResult<()> {
+        while let Ok(comm) = self.command_recv.recv() {
+            let data = {
+                let _permit = self.read_permits.acquire();
+                match self.process(&comm) {
+                    Err(TuxDriveError::UnreadableFile(path)) => {
+                        log::warn!("Skipping {}, it cannot be read", display_path(&path));
+                        ReadData {
+                            content: ReadDataContent::Unreadable,
+                            event_id: comm.event_id,
+                            possibly_inconsistent: false,
+                            hard_linked: false,
+                        }
+                    }
+                    res => res?,
                 }
-            })?;
+            };
+            if self.data_send.send(data).is_err() {
+                break;
+            }
         }
         Ok(())
     }
+
+    fn process(&self, comm: &ReadCommand) -> TuxDriveResult<ReadData> {
+        if let (Some(max_size), ReadCommandKind::Data) = (self.max_file_size, comm.kind) {
+            if let Some(skipped) = skip_too_large(comm, max_size)? {
+                return Ok(skipped);
+            }
+        }
+        match (self.tail_threshold, comm.kind) {
+            (Some(threshold), ReadCommandKind::Data) => self.read_tail(comm, threshold),
+            (_, ReadCommandKind::Data) if self.stream.is_some() => {
+                let (threshold, chunk_size) = self.stream.unwrap();
+                self.read_stream(comm, threshold, chunk_size)
+            }
+            (_, ReadCommandKind::Delete) => {
+                // A file created at the same path later starts from scratch
+                self.read_offsets.lock().unwrap().remove(&comm.path);
+                comm.process()
+            }
+            _ => comm.process(),
+        }
+    }
+
+    fn read_stream(
+        &self,
+        comm: &ReadCommand,
+        threshold: u64,
+        chunk_size: usize,
+    ) -> TuxDriveResult<ReadData> {
+        let stat = match stat_deletable_file(&comm.path)? {
+            Some(stat) if stat.st_size as u64 >= threshold => stat,
+            _ => return comm.process(),
+        };
+        let file = match File::open(&comm.path) {
+            Ok(file) => file,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                return comm.process();
+            }
+            Err(err) => return Err(TuxDriveError::io_at(&comm.path, err)),
+        };
+        let stream = DataStream {
+            file,
+            chunk_size,
+            size: stat.st_size as u64,
+            read: 0,
+            failed: false,
+            done: false,
+        };
+        Ok(ReadData {
+            content: ReadDataContent::Stream(stream),
+            event_id: comm.event_id,
+            possibly_inconsistent: false,
+            hard_linked: comm.check_hard_links(&stat),
+        })
+    }
+
+    fn read_tail(&self, comm: &ReadCommand, threshold: u64) -> TuxDriveResult<ReadData> {
+        let stat = match stat_deletable_file(&comm.path)? {
+            Some(stat) => stat,
+            None => {
+                self.read_offsets.lock().unwrap().remove(&comm.path);
+                return comm.process();
+            }
+        };
+        let size = stat.st_size as u64;
+        if size < threshold {
+            self.read_offsets.lock().unwrap().remove(&comm.path);
+            return comm.process();
+        }
+
+        let last_read = self.read_offsets.lock().unwrap().get(&comm.path).copied();
+        let appended_at = last_read
+            .filter(|last_read| last_read.ino == stat.st_ino && last_read.offset <= size)
+            .map(|last_read| last_read.offset);
+        let data = match appended_at {
+            Some(offset) => match read_deletable_file_from(&comm.path, offset)? {
+                Some((data, stat)) => ReadData {
+                    content: ReadDataContent::DataAt { offset, data },
+                    event_id: comm.event_id,
+                    possibly_inconsistent: false,
+                    hard_linked: comm.check_hard_links(&stat),
+                },
+                None => comm.process()?,
+            },
+            None => comm.process()?,
+        };
+
+        let mut read_offsets = self.read_offsets.lock().unwrap();
+        match &data.content {
+            ReadDataContent::Data(content) if !data.possibly_inconsistent => {
+                let offset = content.len() as u64;
+                read_offsets.insert(
+                    comm.path.clone(),
+                    ReadOffset {
+                        ino: stat.st_ino,
+                        offset,
+                    },
+                );
+            }
+            ReadDataContent::DataAt { offset, data } => {
+                let offset = offset + data.len() as u64;
+                read_offsets.insert(
+                    comm.path.clone(),
+                    ReadOffset {
+                        ino: stat.st_ino,
+                        offset,
+                    },
+                );
+            }
+            _ => {
+                read_offsets.remove(&comm.path);
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+struct Semaphore {
+    permits: Mutex<usize>,
+    cond: Condvar,
+}
+
+struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.cond.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit(self)
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.cond.notify_one();
+    }
 }
 
 #[cfg(test)]
@@ -344,4 +1306,519 @@ mod test {
         let perm: FilePermission = perm_bits.into();
         assert_eq!(perm, expected_perm);
     }
+
+    #[test]
+    pub fn regular_file_permission_bits() {
+        let perm = FilePermission {
+            user: NormalPermissionBuilder::default()
+                .read(true)
+                .write(true)
+                .build()
+                .unwrap(),
+            group: NormalPermissionBuilder::default()
+                .read(true)
+                .build()
+                .unwrap(),
+            other: NormalPermissionBuilder::default()
+                .read(true)
+                .build()
+                .unwrap(),
+            spec: SpecialPermissionBuilder::default().build().unwrap(),
+        };
+        assert_eq!(perm.to_mode_bits(), 0o0644);
+        assert_eq!(perm.to_string(), "rw-r--r--");
+    }
+
+    #[test]
+    pub fn suid_executable_file_permission_bits() {
+        let perm = FilePermission {
+            user: NormalPermissionBuilder::default()
+                .read(true)
+                .write(true)
+                .execute(true)
+                .build()
+                .unwrap(),
+            group: NormalPermissionBuilder::default()
+                .read(true)
+                .execute(true)
+                .build()
+                .unwrap(),
+            other: NormalPermissionBuilder::default()
+                .read(true)
+                .execute(true)
+                .build()
+                .unwrap(),
+            spec: SpecialPermissionBuilder::default()
+                .suid(true)
+                .build()
+                .unwrap(),
+        };
+        assert_eq!(perm.to_mode_bits(), 0o4755);
+        assert_eq!(perm.to_string(), "rwsr-xr-x");
+    }
+
+    #[test]
+    pub fn permission_bits_round_trip() {
+        for bits in 0..=0o7777u16 {
+            assert_eq!(FilePermission::from(bits).to_mode_bits(), bits);
+        }
+        assert_eq!(FilePermission::from(0o1777).to_string(), "rwxrwxrwt");
+        assert_eq!(FilePermission::from(0o3640).to_string(), "rw-r-S--T");
+    }
+
+    #[test]
+    pub fn block_hashes_per_block() {
+        let mut data = vec![b'a'; 8];
+        data.extend([b'b'; 4]);
+        data.extend([b'a'; 6]);
+        let hashes = block_hashes(&data, 4);
+        assert_eq!(hashes.len(), 5);
+        assert_eq!(hashes[0], hashes[1]);
+        assert_ne!(hashes[1], hashes[2]);
+        assert_eq!(hashes[3], hashes[0]);
+        assert_eq!(&hashes[4], &blake3::hash(b"aa").as_bytes()[..16]);
+        assert!(block_hashes(&[], 4).is_empty());
+    }
+
+    #[test]
+    pub fn zero_block_size_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let kind = ReadCommandKind::BlockHashes { block_size: 0 };
+        assert!(matches!(
+            ReadCommand::new(&path, kind, 0).process(),
+            Err(TuxDriveError::InvalidBlockSize(0))
+        ));
+    }
+
+    #[test]
+    pub fn merkle_block_size_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        for block_size in [0, 512, 3000] {
+            let kind = ReadCommandKind::MerkleTree { block_size };
+            assert!(matches!(
+                ReadCommand::new(&path, kind, 0).process(),
+                Err(TuxDriveError::InvalidBlockSize(size)) if size == block_size
+            ));
+        }
+        let kind = ReadCommandKind::MerkleTree { block_size: 4096 };
+        assert!(ReadCommand::new(&path, kind, 0).process().is_ok());
+    }
+
+    #[test]
+    pub fn merkle_root_is_the_content_hash() {
+        for len in [
+            0usize,
+            1000,
+            4096,
+            5000,
+            3 * 4096 + 7,
+            5 * 4096,
+            13 * 4096 - 1,
+        ] {
+            let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let tree = MerkleTree::new(&data, 4096);
+            assert_eq!(
+                tree.root(),
+                *blake3::hash(&data).as_bytes(),
+                "length {}",
+                len
+            );
+            assert_eq!(tree.levels[0].len(), usize::max(1, len.div_ceil(4096)));
+        }
+
+        let mut data = vec![b'a'; 4 * 4096];
+        let old = MerkleTree::new(&data, 4096);
+        data[3 * 4096] = b'b';
+        let new = MerkleTree::new(&data, 4096);
+        let changed = (0..4)
+            .filter(|&idx| old.levels[0][idx] != new.levels[0][idx])
+            .collect::<Vec<_>>();
+        assert_eq!(changed, vec![3]);
+    }
+
+    #[test]
+    pub fn stable_file_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let content = vec![b'x'; 3000];
+        std::fs::write(&path, &content).unwrap();
+        let (data, _, torn) = read_stable_file(&path).unwrap().unwrap();
+        assert_eq!(data, content);
+        assert!(!torn);
+        assert!(read_stable_file(dir.path().join("missing"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    pub fn semaphore_bounds_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let semaphore = Semaphore::new(2);
+        let active = AtomicUsize::new(0);
+        let max_active = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _i in 0..8 {
+                scope.spawn(|| {
+                    let _permit = semaphore.acquire();
+                    let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now_active, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    pub fn reader_processes_all_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let (reader, comm_send, data_recv) = FileReader::with_concurrency(1).unwrap();
+        for id in 1..=5 {
+            let path = dir.path().join(id.to_string());
+            std::fs::write(&path, id.to_string()).unwrap();
+            comm_send
+                .send(ReadCommand::new(&path, ReadCommandKind::Data, id))
+                .unwrap();
+        }
+        drop(comm_send);
+        reader.start_reader().unwrap();
+        let mut ids = Vec::new();
+        for data in data_recv.try_iter() {
+            let expected = data.event_id.to_string().into_bytes();
+            assert!(matches!(data.content, ReadDataContent::Data(content) if content == expected));
+            ids.push(data.event_id);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    pub fn tail_reads_of_growing_file() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (reader, _comm_send, _data_recv) = FileReader::new().unwrap();
+        let reader = reader.tail_reads_above(4);
+        let read = |id| reader.process(&ReadCommand::new(&path, ReadCommandKind::Data, id));
+
+        std::fs::write(&path, b"line 1\n").unwrap();
+        let data = read(1).unwrap();
+        assert!(matches!(data.content, ReadDataContent::Data(content) if content == b"line 1\n"));
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"line 2\n").unwrap();
+        let data = read(2).unwrap();
+        assert!(matches!(
+            data.content,
+            ReadDataContent::DataAt { offset: 7, data } if data == b"line 2\n"
+        ));
+
+        // Truncated: start over
+        std::fs::write(&path, b"line 3\n").unwrap();
+        let data = read(3).unwrap();
+        assert!(matches!(data.content, ReadDataContent::Data(content) if content == b"line 3\n"));
+
+        // Below the threshold files are always read whole
+        std::fs::write(&path, b"abc").unwrap();
+        let data = read(4).unwrap();
+        assert!(matches!(data.content, ReadDataContent::Data(content) if content == b"abc"));
+    }
+
+    #[test]
+    pub fn hard_links_are_flagged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let read = |kind| ReadCommand::new(&path, kind, 1).process().unwrap();
+        assert!(!read(ReadCommandKind::Data).hard_linked);
+
+        std::fs::hard_link(&path, dir.path().join("link")).unwrap();
+        assert!(read(ReadCommandKind::Data).hard_linked);
+        let data = read(ReadCommandKind::Metadata);
+        assert!(matches!(data.content, ReadDataContent::Metadata(meta) if meta.nlink == 2));
+    }
+
+    #[test]
+    pub fn resume_from_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let content = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+        let read = |offset| {
+            ReadCommand::new(&path, ReadCommandKind::ResumeData { offset }, 1)
+                .process()
+                .unwrap()
+        };
+        let data = read(1234);
+        assert!(matches!(
+            data.content,
+            ReadDataContent::DataAt { offset: 1234, data } if data == content[1234..]
+        ));
+        let data = read(5000);
+        assert!(matches!(data.content, ReadDataContent::DataAt { data, .. } if data.is_empty()));
+    }
+
+    #[test]
+    pub fn delete_command_reads_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gone");
+        let data = ReadCommand::new(&path, ReadCommandKind::Delete, 7)
+            .process()
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Delete));
+        assert_eq!(data.event_id, 7);
+    }
+
+    #[test]
+    pub fn known_digest_not_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"abc").unwrap();
+        let known = [7; 32];
+        let hash_read = |algorithm| {
+            let data = ReadCommand::new(&path, ReadCommandKind::Hash { algorithm }, 0)
+                .with_digest(known)
+                .process()
+                .unwrap();
+            match data.content {
+                ReadDataContent::Hash(hash) => hash.digest,
+                content => panic!("Expected a hash, got {:?}", content),
+            }
+        };
+        assert_eq!(hash_read(HashAlgorithm::Blake3), known);
+        // Only a BLAKE3 digest is known
+        assert_ne!(hash_read(HashAlgorithm::Sha256), known);
+
+        std::fs::remove_file(&path).unwrap();
+        let data = ReadCommand::new(
+            &path,
+            ReadCommandKind::Hash {
+                algorithm: HashAlgorithm::Blake3,
+            },
+            0,
+        )
+        .with_digest(known)
+        .process()
+        .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Delete));
+    }
+
+    #[test]
+    pub fn hash_of_known_content() {
+        fn hex(digest: &[u8]) -> String {
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+        fn hash(path: &Path, algorithm: HashAlgorithm) -> ContentHash {
+            let data = ReadCommand::new(path, ReadCommandKind::Hash { algorithm }, 0)
+                .process()
+                .unwrap();
+            match data.content {
+                ReadDataContent::Hash(hash) => hash,
+                content => panic!("Expected a hash, got {:?}", content),
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            hex(&hash(&path, HashAlgorithm::Blake3).digest),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            hex(&hash(&path, HashAlgorithm::Sha256).digest),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // Read in several buffers
+        let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let hash = hash(&path, HashAlgorithm::default());
+        assert_eq!(hash.algorithm, HashAlgorithm::Blake3);
+        assert_eq!(&hash.digest, blake3::hash(&data).as_bytes());
+
+        std::fs::remove_file(&path).unwrap();
+        let data = ReadCommand::new(
+            &path,
+            ReadCommandKind::Hash {
+                algorithm: HashAlgorithm::Sha256,
+            },
+            0,
+        )
+        .process()
+        .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Delete));
+    }
+
+    #[test]
+    pub fn large_files_are_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (small, large) = (dir.path().join("small"), dir.path().join("large"));
+        std::fs::write(&small, b"abc").unwrap();
+        std::fs::write(&large, b"0123456789").unwrap();
+        let (reader, _, _) = FileReader::new().unwrap();
+        let reader = reader.stream_reads_above(8, 4);
+
+        let data = reader
+            .process(&ReadCommand::new(&small, ReadCommandKind::Data, 0))
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Data(content) if content == b"abc"));
+
+        let data = reader
+            .process(&ReadCommand::new(&large, ReadCommandKind::Data, 0))
+            .unwrap();
+        let mut stream = match data.content {
+            ReadDataContent::Stream(stream) => stream,
+            content => panic!("Expected a stream, got {:?}", content),
+        };
+        assert_eq!(stream.size(), 10);
+        let chunks = stream.by_ref().collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]
+        );
+        assert!(stream.is_complete());
+    }
+
+    #[test]
+    pub fn files_over_the_limit_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (small, large) = (dir.path().join("small"), dir.path().join("large"));
+        std::fs::write(&small, b"0123456789").unwrap();
+        std::fs::write(&large, vec![b'x'; 4096]).unwrap();
+        let (reader, _, _) = FileReader::new().unwrap();
+        let reader = reader.max_file_size(10);
+
+        let data = reader
+            .process(&ReadCommand::new(&small, ReadCommandKind::Data, 0))
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Data(content) if content == b"0123456789"));
+        let data = reader
+            .process(&ReadCommand::new(&large, ReadCommandKind::Data, 1))
+            .unwrap();
+        assert!(matches!(
+            data.content,
+            ReadDataContent::Skipped { size: 4096 }
+        ));
+        assert_eq!(data.event_id, 1);
+        // Only the content is limited
+        let data = reader
+            .process(&ReadCommand::new(&large, ReadCommandKind::Metadata, 2))
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Metadata(_)));
+    }
+
+    #[test]
+    pub fn shrinking_file_ends_stream_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        std::fs::write(&path, vec![b'a'; 100]).unwrap();
+        let (reader, _, _) = FileReader::new().unwrap();
+        let reader = reader.stream_reads_above(8, 16);
+        let data = reader
+            .process(&ReadCommand::new(&path, ReadCommandKind::Data, 0))
+            .unwrap();
+        let mut stream = match data.content {
+            ReadDataContent::Stream(stream) => stream,
+            content => panic!("Expected a stream, got {:?}", content),
+        };
+        assert_eq!(stream.next().unwrap().len(), 16);
+        std::fs::write(&path, b"short").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(stream.next().is_none());
+        assert!(!stream.is_complete());
+    }
+
+    #[test]
+    pub fn ownership_from_stat() {
+        use nix::unistd::{Gid, Uid};
+
+        // Only root may give files away
+        if !unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        unistd::chown(&path, Some(Uid::from_raw(1234)), Some(Gid::from_raw(5678))).unwrap();
+        let stat = stat_deletable_file(&path).unwrap().unwrap();
+        assert!(matches!(
+            ownership_of(&stat),
+            ReadDataContent::Ownership {
+                uid: 1234,
+                gid: 5678
+            }
+        ));
+
+        let data = ReadCommand::new(&path, ReadCommandKind::Ownership, 3)
+            .process()
+            .unwrap();
+        assert!(matches!(
+            data.content,
+            ReadDataContent::Ownership {
+                uid: 1234,
+                gid: 5678
+            }
+        ));
+        std::fs::remove_file(&path).unwrap();
+        let data = ReadCommand::new(&path, ReadCommandKind::Ownership, 3)
+            .process()
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Delete));
+    }
+
+    #[test]
+    pub fn xattrs_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let value = b"backed up";
+        let res = unsafe {
+            libc::setxattr(
+                c_path.as_ptr(),
+                c"user.tuxdrive".as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        let expected = if res == 0 {
+            vec![(OsString::from("user.tuxdrive"), value.to_vec())]
+        } else {
+            // Only reading them back is possible then
+            assert_eq!(Errno::last(), Errno::ENOTSUP);
+            Vec::new()
+        };
+
+        let data = ReadCommand::new(&path, ReadCommandKind::Xattrs, 3)
+            .process()
+            .unwrap();
+        match data.content {
+            // Other attributes like a SELinux label may be there too
+            ReadDataContent::Xattrs(xattrs) => assert_eq!(
+                xattrs
+                    .into_iter()
+                    .filter(|(name, _)| name.as_bytes().starts_with(b"user."))
+                    .collect::<Vec<_>>(),
+                expected
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+        let data = ReadCommand::new(&path, ReadCommandKind::Xattrs, 3)
+            .process()
+            .unwrap();
+        assert!(matches!(data.content, ReadDataContent::Delete));
+    }
 }
@@ -1,157 +1,1017 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::ErrorKind;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossbeam::channel::{Receiver, Sender};
-use crossbeam::sync::WaitGroup;
-use rayon::{ThreadPool, ThreadPoolBuilder};
+use crossbeam::channel::Receiver;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
 
 use crate::atomic::AtomicIdGenerator;
+use crate::config::PathConfig;
+use crate::display_path;
 use crate::error::{TuxDriveError, TuxDriveResult};
-use crate::forest::{DfsFuncBehaviour, DfsMutInfo, DirectoryAddOptions, PathForest, PathTree};
+use crate::forest::info::NodeInfo;
+use crate::forest::{
+    depth_below, DfsFuncBehaviour, DfsMutInfo, DirectoryAddOptions, PathForest, PathTree,
+    RecursiveBehaviour,
+};
+use crate::reader;
 
-pub struct Watcher<const POLL_INTERVAL_SECS: u64> {
+use self::debounce::Debouncer;
+use self::inotify::InotifyWatches;
+use self::overflow::EventSender;
+use self::state::StateFile;
+use self::summary::RootSummary;
+
+pub use self::builder::WatcherBuilder;
+pub use self::handle::{ScanState, WatcherHandle};
+pub use self::handler::EventHandler;
+pub use self::inotify::WatcherBackend;
+pub use self::metrics::WatcherMetrics;
+pub use self::overflow::OverflowPolicy;
+#[cfg(feature = "async")]
+pub use self::stream::EventStream;
+
+mod builder;
+mod debounce;
+mod handle;
+mod handler;
+mod inotify;
+mod metrics;
+mod overflow;
+mod reload;
+mod socket;
+mod state;
+#[cfg(feature = "async")]
+mod stream;
+mod summary;
+
+pub struct Watcher {
+    poll_interval: Duration,
     forest: PathForest<ModTimeInfo>,
-    sender: Sender<WatchEvent>,
+    sender: EventSender,
     pool: ThreadPool,
     id_gen: AtomicIdGenerator,
+    metadata_interval: Duration,
+    content_interval: Duration,
+    last_metadata_check: Option<Instant>,
+    last_content_check: Option<Instant>,
+    /// When each tree with its own poll interval is due next
+    next_polls: HashMap<PathBuf, Instant>,
+    probe_timeout: Option<Duration>,
+    skip_virtual_fs: bool,
+    handle: WatcherHandle,
+    summaries: HashMap<PathBuf, RootSummary>,
+    /// Set when using the inotify backend
+    inotify: Option<InotifyWatches>,
+    emit_initial_events: bool,
+    /// Batch size and pause between batches for the initial events
+    initial_batch: Option<(usize, Duration)>,
+    debouncer: Option<Debouncer>,
+    /// Retries of a failed poll cycle and the backoff before the first one
+    poll_retry: Option<(u32, Duration)>,
+    strict_tree_ordering: bool,
+    /// Config of the roots added from one, to tell which changed on `set_directories`
+    root_configs: HashMap<PathBuf, PathConfig>,
+    /// Fraction of the sleep between polls it is randomly made longer or shorter by
+    poll_jitter: f64,
+    /// When the upcoming poll is due without the jitter, for it to count
+    /// as due still if the jitter makes it come early
+    scheduled_poll: Option<Instant>,
+    rng: fastrand::Rng,
+    /// Set with `save_state_every`
+    state_file: Option<StateFile>,
 }
 
 const MAX_NUM_THREADS: usize = 4;
 
-impl<const POLL_INTERVAL_SECS: u64> Watcher<{ POLL_INTERVAL_SECS }> {
-    pub fn new() -> TuxDriveResult<(Self, Receiver<WatchEvent>)> {
-        let (tx, rx) = crossbeam::channel::unbounded();
-        let num_threads = usize::max(num_cpus::get(), MAX_NUM_THREADS);
-        let pool = ThreadPoolBuilder::new().num_threads(num_threads).build()?;
-        let watcher = Self {
-            forest: PathForest::new(),
-            sender: tx,
-            pool,
-            id_gen: AtomicIdGenerator::new(),
-        };
-        Ok((watcher, rx))
+/// Longest the inotify backend takes to notice a stop request
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+impl Watcher {
+    /// A watcher polling every `poll_interval`, otherwise with the defaults
+    /// of `WatcherBuilder`.
+    pub fn new(poll_interval: Duration) -> TuxDriveResult<(Self, Receiver<WatchEvent>)> {
+        WatcherBuilder::new().poll_interval(poll_interval).build()
+    }
+
+    pub fn builder() -> WatcherBuilder {
+        WatcherBuilder::new()
     }
 
     pub fn add_directory<P: AsRef<Path>>(
         &mut self,
         path: P,
         recursive: bool,
+    ) -> TuxDriveResult<()> {
+        self.add_directory_with_options(path, recursive, DirectoryAddOptions::new())
+    }
+
+    /// Same as `add_directory`, but with control over what gets tracked.
+    /// The options also apply to paths discovered later while polling.
+    pub fn add_directory_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        recursive: bool,
+        options: DirectoryAddOptions,
     ) -> TuxDriveResult<()> {
         let path = path.as_ref();
+        let options = options.skip_virtual_fs_unless_set(self.skip_virtual_fs);
+        if let Some(timeout) = self.probe_timeout {
+            probe_path(path, timeout)?;
+        }
         if !path.is_dir() {
-            return Err(TuxDriveError::NotDirectory(path.display().to_string()));
+            return Err(TuxDriveError::NotDirectory(path.to_path_buf()));
+        }
+        if options.is_summary_only() {
+            let summary = RootSummary::new(path, recursive, options)?;
+            self.summaries.insert(path.to_path_buf(), summary);
+            return Ok(());
         }
         if recursive {
-            self.forest
-                .add_dir_recursively(path, DirectoryAddOptions::new())?;
+            self.forest.add_dir_recursively(path, options)?;
         } else {
-            self.forest.add_dir_non_recursively(path)?;
+            self.forest.add_dir_non_recursively(path, options)?;
         }
         // Update the times
-        self.update_times()
+        update_times(&mut self.forest)?;
+        hash_contents(&mut self.forest)?;
+        if self.emit_initial_events {
+            self.send_initial_events(path)?;
+        }
+        self.watch_forest()
     }
 
-    fn update_times(&mut self) -> TuxDriveResult<()> {
-        self.forest.dfs_mut(|path, dfs_info| {
-            if !path.exists() {
-                return Ok(DfsFuncBehaviour::Delete);
+    /// Watches a single file, which gets the same events as a file found in
+    /// a watched directory. Once it is deleted, it is no longer watched.
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> TuxDriveResult<()> {
+        self.add_file_with_options(path, DirectoryAddOptions::new())
+    }
+
+    /// Same as `add_file`, but with options, e.g. a group name.
+    /// Those about directory contents have no effect.
+    pub fn add_file_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: DirectoryAddOptions,
+    ) -> TuxDriveResult<()> {
+        let path = path.as_ref();
+        if let Some(timeout) = self.probe_timeout {
+            probe_path(path, timeout)?;
+        }
+        if !path.is_file() {
+            return Err(TuxDriveError::NotFile(path.to_path_buf()));
+        }
+        self.forest.add_file(path, options)?;
+        update_times(&mut self.forest)?;
+        hash_contents(&mut self.forest)?;
+        if self.emit_initial_events {
+            self.send_initial_events(path)?;
+        }
+        self.watch_forest()
+    }
+
+    /// Stops watching a directory, or a file, added before. No events are
+    /// sent for it. `path` is the one it was added with.
+    /// Returns whether it was watched.
+    pub fn remove_directory<P: AsRef<Path>>(&mut self, path: P) -> TuxDriveResult<bool> {
+        let path = path.as_ref();
+        let removed = self.forest.remove_path(path, path) || self.summaries.remove(path).is_some();
+        if removed {
+            if let Some(watches) = self.inotify.as_mut() {
+                watches.unwatch_below(path);
             }
-            let old_time_info = *dfs_info.info;
-            match dfs_info.info.update_times(path)? {
-                PathAction::Delete => return Ok(DfsFuncBehaviour::Delete),
-                PathAction::Nothing => {}
+            // Trees nested in the removed one keep being watched
+            self.watch_forest()?;
+        }
+        Ok(removed)
+    }
+
+    /// A `Create` for every path of the tree just added at `root_path`,
+    /// parents before their children.
+    fn send_initial_events(&mut self, root_path: &Path) -> TuxDriveResult<()> {
+        let tree = match self.forest.tree_containing_mut(root_path) {
+            Some(tree) => tree,
+            None => return Ok(()),
+        };
+        let mut paths = tree.paths();
+        paths.sort();
+        let group = tree.options().group_name().cloned();
+        let send_info = SendInfo {
+            sender: &self.sender,
+            id_gen: &self.id_gen,
+            debouncer: self.debouncer.as_ref(),
+            group: group.as_ref(),
+            changes: None,
+        };
+        for (count, (path, is_dir)) in paths.iter().enumerate() {
+            if let Some((batch_size, pause)) = self.initial_batch {
+                if count > 0 && count % batch_size == 0 {
+                    // Give the consumer a chance to catch up
+                    self.sender.send_held_back();
+                    thread::sleep(pause);
+                }
             }
-            let action = if dfs_info.info.updated_since(&old_time_info) && dfs_info.is_dir {
-                DfsFuncBehaviour::Continue
-            } else {
-                DfsFuncBehaviour::Stop
+            send_info.send_event(path, WatchEventKind::Create, *is_dir)?;
+        }
+        self.sender.send_held_back();
+        Ok(())
+    }
+
+    /// Which checks are due at `now`, according to their intervals
+    fn due_phases(&mut self, now: Instant) -> CheckPhases {
+        let is_due = |last_check: Option<Instant>, interval| {
+            last_check.is_none_or(|last_check| now.duration_since(last_check) >= interval)
+        };
+        let phases = CheckPhases {
+            metadata: is_due(self.last_metadata_check, self.metadata_interval),
+            content: is_due(self.last_content_check, self.content_interval),
+        };
+        if phases.metadata {
+            self.last_metadata_check = Some(now);
+        }
+        if phases.content {
+            self.last_content_check = Some(now);
+        }
+        phases
+    }
+
+    /// Checks the trees that are due: those with their own poll interval
+    /// once it is over, the others according to the due phases
+    fn poll(&mut self) -> TuxDriveResult<()> {
+        let now = self.poll_time();
+        let phases = self.due_phases(now);
+        let mut own_due = HashSet::new();
+        // Rebuilt, so that trees removed meanwhile are forgotten
+        let mut next_polls = HashMap::new();
+        for tree in self.forest.trees() {
+            let interval = match tree.options().own_poll_interval() {
+                Some(interval) => interval,
+                None => continue,
+            };
+            let root_path = tree.root_path();
+            match self.next_polls.get(&root_path).filter(|due| **due > now) {
+                Some(due) => next_polls.insert(root_path, *due),
+                None => {
+                    own_due.insert(root_path.clone());
+                    next_polls.insert(root_path, now + interval)
+                }
             };
-            Ok(action)
+        }
+        self.next_polls = next_polls;
+        if !phases.any() && own_due.is_empty() {
+            self.send_debounced(false)?;
+            self.sender.send_held_back();
+            self.poll_completed();
+            return Ok(());
+        }
+        self.poll_trees_with(phases, &|tree| match tree.options().own_poll_interval() {
+            Some(_) if own_due.contains(&tree.root_path()) => CheckPhases::ALL,
+            Some(_) => CheckPhases::NONE,
+            None => phases,
         })
     }
 
-    fn poll(&mut self) -> TuxDriveResult<()> {
-        let wg = WaitGroup::new();
-        for tree in self.forest.trees_mut() {
-            let wg = wg.clone();
-            self.pool.install(|| {
-                let res = poll_tree(
-                    tree,
-                    SendInfo {
-                        sender: &self.sender,
-                        id_gen: &self.id_gen,
-                    },
-                );
-                drop(wg);
-                res
-            })?;
+    /// Checks all trees, whatever their poll interval
+    fn poll_phases(&mut self, phases: CheckPhases) -> TuxDriveResult<()> {
+        self.poll_trees_with(phases, &|_| phases)
+    }
+
+    /// `phases` applies to the summaries, `tree_phases` tells those of each tree
+    fn poll_trees_with(
+        &mut self,
+        phases: CheckPhases,
+        tree_phases: &TreePhases<'_>,
+    ) -> TuxDriveResult<()> {
+        if self.strict_tree_ordering {
+            self.poll_trees_in_order(tree_phases)?;
+        } else {
+            self.poll_trees(tree_phases)?;
+        }
+        if phases.content {
+            self.poll_summaries()?;
+        }
+        self.send_debounced(false)?;
+        self.sender.send_held_back();
+        self.poll_completed();
+        Ok(())
+    }
+
+    /// Lets the handle know, along with the paths now watched
+    fn poll_completed(&self) {
+        self.handle.set_watched_paths(self.forest.node_count());
+        self.handle.poll_completed();
+    }
+
+    /// Polls the trees side by side on the pool, then removes those whose
+    /// root is gone. The first error is returned once all are done.
+    fn poll_trees(&mut self, tree_phases: &TreePhases<'_>) -> TuxDriveResult<()> {
+        let trees = self
+            .forest
+            .trees_mut()
+            .map(|tree| (tree_phases(tree), tree))
+            .filter(|(phases, _)| phases.any())
+            .collect::<Vec<_>>();
+        let (sender, id_gen, debouncer) = (&self.sender, &self.id_gen, self.debouncer.as_ref());
+        let results = self.pool.install(|| {
+            trees
+                .into_par_iter()
+                .map(|(phases, tree)| {
+                    let group = tree.options().group_name().cloned();
+                    let send_info = SendInfo {
+                        sender,
+                        id_gen,
+                        debouncer,
+                        group: group.as_ref(),
+                        changes: None,
+                    };
+                    let res = poll_tree(tree, send_info, phases, false);
+                    (tree.root_path(), res)
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut removed = Vec::new();
+        let mut first_err = None;
+        for (root_path, res) in results {
+            match res {
+                Ok(RecursiveBehaviour::Delete) => removed.push(root_path),
+                Ok(RecursiveBehaviour::Nothing) => {}
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        self.remove_trees(removed);
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// One tree after the other, in the order of their roots, so that a root
+    /// nested in another one is polled after it.
+    fn poll_trees_in_order(&mut self, tree_phases: &TreePhases<'_>) -> TuxDriveResult<()> {
+        let mut trees = self.forest.trees_mut().collect::<Vec<_>>();
+        trees.sort_by_key(|tree| tree.root_path());
+        let mut removed = Vec::new();
+        for tree in trees {
+            let phases = tree_phases(tree);
+            if !phases.any() {
+                continue;
+            }
+            let group = tree.options().group_name().cloned();
+            let send_info = SendInfo {
+                sender: &self.sender,
+                id_gen: &self.id_gen,
+                debouncer: self.debouncer.as_ref(),
+                group: group.as_ref(),
+                changes: None,
+            };
+            if let RecursiveBehaviour::Delete = poll_tree(tree, send_info, phases, true)? {
+                removed.push(tree.root_path());
+            }
+        }
+        self.remove_trees(removed);
+        Ok(())
+    }
+
+    /// Drops the trees whose root was deleted, which was reported already
+    fn remove_trees(&mut self, root_paths: Vec<PathBuf>) {
+        for root_path in root_paths {
+            self.forest.remove_path(&root_path, &root_path);
+            if let Some(watches) = self.inotify.as_mut() {
+                watches.unwatch_below(&root_path);
+            }
+        }
+    }
+
+    /// Sends the modifications held back by the debouncer once their window
+    /// is over, or all of them with `all`.
+    fn send_debounced(&mut self, all: bool) -> TuxDriveResult<()> {
+        let debouncer = match &self.debouncer {
+            Some(debouncer) => debouncer,
+            None => return Ok(()),
+        };
+        for (change, group) in debouncer.take_due(Instant::now(), all) {
+            let event = change.into_event(self.id_gen.next_id(), self.sender.source_id());
+            self.sender.send(event, group.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// At most one `RootChanged` per summary-only root per cycle
+    fn poll_summaries(&mut self) -> TuxDriveResult<()> {
+        let send_info = SendInfo {
+            sender: &self.sender,
+            id_gen: &self.id_gen,
+            debouncer: self.debouncer.as_ref(),
+            group: None,
+            changes: None,
+        };
+        let mut removed = Vec::new();
+        for (root_path, summary) in self.summaries.iter_mut() {
+            match summary.update(root_path) {
+                Ok(true) => send_info.send_event(root_path, WatchEventKind::RootChanged, true)?,
+                Ok(false) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    send_info.send_event(root_path, WatchEventKind::Delete, true)?;
+                    removed.push(root_path.clone());
+                }
+                // Keep the last digest, so that the change shows once it's readable again
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {}
+                Err(err) => return Err(TuxDriveError::io_at(root_path, err)),
+            }
+        }
+        for root_path in removed {
+            self.summaries.remove(&root_path);
         }
-        wg.wait();
         Ok(())
     }
 
+    /// Whether the watcher completed a poll within the last `max_staleness`,
+    /// i.e. it is still polling and not stuck.
+    pub fn is_alive(&self, max_staleness: Duration) -> bool {
+        self.handle.is_alive(max_staleness)
+    }
+
+    /// See `WatcherHandle::pause`
+    pub fn pause(&self) {
+        self.handle.pause();
+    }
+
+    /// See `WatcherHandle::resume`
+    pub fn resume(&self) {
+        self.handle.resume();
+    }
+
+    /// Whether a poll is in progress, see `WatcherHandle::scan_state`
+    pub fn scan_state(&self) -> ScanState {
+        self.handle.scan_state()
+    }
+
+    /// A handle to check on the watcher from other threads,
+    /// once it was moved to its polling thread.
+    pub fn handle(&self) -> WatcherHandle {
+        self.handle.clone()
+    }
+
+    /// Counters since the watcher was created.
+    /// Use `WatcherHandle::metrics` from other threads while polling.
+    pub fn metrics(&self) -> WatcherMetrics {
+        self.handle.metrics()
+    }
+
+    /// The metrics in the Prometheus text format, to be served to a scraper
+    pub fn metrics_prometheus(&self) -> String {
+        self.metrics().to_prometheus()
+    }
+
+    /// Streams all events to clients connecting to a Unix socket at `path`,
+    /// as newline-delimited JSON. Clients are accepted on a separate thread
+    /// and dropped once they disconnect or stop reading.
+    /// `path` must not exist yet.
+    pub fn serve_unix_socket<P: AsRef<Path>>(&self, path: P) -> TuxDriveResult<()> {
+        self.sender.socket_clients().serve(path.as_ref())
+    }
+
+    /// Sends all events held back by the overflow policy right away,
+    /// blocking until the channel has room for them.
+    /// Call this before dropping the watcher, so that the last changes
+    /// aren't lost.
+    pub fn flush(&mut self) {
+        // Nothing to deliver to once the consumer is gone
+        let _ = self.send_debounced(true);
+        self.sender.flush();
+    }
+
     /// Starts the polling of the Watcher.
-    /// Polls once every POLL_INTERVAL_SECS (approximately).
-    /// Probably never returns, execpt on errors.
+    /// Polls once every poll interval (approximately), or with the inotify
+    /// backend, waits for changes to be reported. Trees added with their own
+    /// poll interval are polled on their own schedule.
+    /// Runs until `WatcherHandle::stop` is called, or an error occurs.
+    /// The watched directories can be changed meanwhile through
+    /// `WatcherHandle::set_directories`.
+    /// Either way, events held back are sent before returning.
+    /// Dropping the event receiver stops it too, without an error.
+    /// The event handler, if any, is dropped on return, which ends an
+    /// event stream.
     /// You probably should run this function on a separate thread.
     pub fn start_polling(&mut self) -> TuxDriveResult<()> {
+        let res = if self.inotify.is_some() {
+            self.run_inotify()
+        } else {
+            self.run_polling()
+        };
+        self.sender.drop_handler();
+        self.save_state_if_due(true);
+        res
+    }
+
+    fn run_polling(&mut self) -> TuxDriveResult<()> {
+        let mut was_paused = false;
+        loop {
+            if self.handle.is_paused() {
+                was_paused = true;
+            } else {
+                log::debug!("Polling ...");
+                self.apply_new_directories();
+                let after_pause = mem::take(&mut was_paused);
+                let res = self.retry_failed(|watcher| {
+                    watcher.handle.set_scanning(true);
+                    let res = if after_pause {
+                        watcher.poll_after_pause()
+                    } else {
+                        watcher.poll()
+                    };
+                    watcher.handle.set_scanning(false);
+                    res
+                });
+                if let Err(err) = res {
+                    return self.stop_on_error(err);
+                }
+                self.save_state_if_due(false);
+            }
+            let until_next_poll = self.until_next_poll();
+            self.scheduled_poll = Some(Instant::now() + until_next_poll);
+            let sleep = self.jittered(until_next_poll);
+            if self.handle.sleep_unless_stopped(sleep) {
+                self.flush();
+                return Ok(());
+            }
+        }
+    }
+
+    /// The poll interval, or less if the phases or a tree with its own
+    /// poll interval are due sooner
+    fn until_next_poll(&self) -> Duration {
+        let now = Instant::now();
+        let phases_due = [
+            self.last_metadata_check
+                .map(|last_check| last_check + self.metadata_interval),
+            self.last_content_check
+                .map(|last_check| last_check + self.content_interval),
+        ];
+        phases_due
+            .into_iter()
+            .flatten()
+            .chain(self.next_polls.values().copied())
+            .map(|due| due.saturating_duration_since(now))
+            .fold(self.poll_interval, Duration::min)
+    }
+
+    /// The time a poll counts as taking place at: when it was scheduled
+    /// if the jitter made it come earlier
+    fn poll_time(&mut self) -> Instant {
+        let now = Instant::now();
+        self.scheduled_poll
+            .take()
+            .map_or(now, |scheduled| scheduled.max(now))
+    }
+
+    /// `sleep`, made up to `poll_jitter` of it longer or shorter at random
+    fn jittered(&mut self, sleep: Duration) -> Duration {
+        if self.poll_jitter == 0.0 {
+            return sleep;
+        }
+        let offset = (self.rng.f64() * 2.0 - 1.0) * self.poll_jitter;
+        sleep.mul_f64(1.0 + offset)
+    }
+
+    fn run_inotify(&mut self) -> TuxDriveResult<()> {
+        // Waking up regularly keeps the handle alive on a quiet tree,
+        // and notices a stop request
+        let timeout = self.poll_interval.min(STOP_CHECK_INTERVAL);
+        let mut was_paused = false;
+        while !self.handle.is_stop_requested() {
+            if self.handle.is_paused() {
+                was_paused = true;
+                self.handle.sleep_unless_stopped(timeout);
+                continue;
+            }
+            self.apply_new_directories();
+            let res = if mem::take(&mut was_paused) {
+                self.retry_failed(|watcher| {
+                    watcher.handle.set_scanning(true);
+                    let res = watcher.poll_after_pause();
+                    watcher.handle.set_scanning(false);
+                    res
+                })
+            } else {
+                self.retry_failed(|watcher| watcher.inotify_step(timeout))
+            };
+            if let Err(err) = res {
+                return self.stop_on_error(err);
+            }
+            self.save_state_if_due(false);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    /// Compares everything against what was known before the pause,
+    /// so that the changes made in the meantime are reported once
+    fn poll_after_pause(&mut self) -> TuxDriveResult<()> {
+        if let Some(watches) = self.inotify.as_mut() {
+            watches.discard_queued()?;
+        }
+        self.poll_phases(CheckPhases::ALL)?;
+        // Directories created in the meantime need watches
+        self.watch_forest()
+    }
+
+    fn stop_on_error(&mut self, err: TuxDriveError) -> TuxDriveResult<()> {
+        if let TuxDriveError::ChannelClosed = err {
+            log::info!("Event receiver dropped, stopping");
+            return Ok(());
+        }
+        // Changes found before the error are still worth delivering
+        self.flush();
+        Err(err)
+    }
+
+    /// Runs `cycle`, retrying it as configured with `WatcherBuilder::poll_retries`
+    /// while it fails. Gives up early on a stop request.
+    fn retry_failed<F>(&mut self, mut cycle: F) -> TuxDriveResult<()>
+    where
+        F: FnMut(&mut Self) -> TuxDriveResult<()>,
+    {
+        let (max_retries, mut backoff) = self.poll_retry.unwrap_or((0, Duration::ZERO));
+        let mut attempt = 0;
         loop {
-            log::debug!("Polling ...");
-            self.poll()?;
-            thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
+            let err = match cycle(self) {
+                Ok(()) => return Ok(()),
+                // No point in trying again
+                Err(TuxDriveError::ChannelClosed) => return Err(TuxDriveError::ChannelClosed),
+                Err(err) => err,
+            };
+            self.handle.counters().count_poll_failure();
+            if attempt == max_retries {
+                return Err(err);
+            }
+            attempt += 1;
+            log::warn!(
+                "Poll failed ({}), retry {} of {} in {:?}",
+                err,
+                attempt,
+                max_retries,
+                backoff
+            );
+            if self.handle.sleep_unless_stopped(backoff) {
+                return Err(err);
+            }
+            backoff *= 2;
         }
     }
 }
 
+/// Hashes the files not hashed yet, of the trees skipping unchanged writes
+fn hash_contents(forest: &mut PathForest<ModTimeInfo>) -> TuxDriveResult<()> {
+    let trees = forest
+        .trees_mut()
+        .filter(|tree| tree.options().skips_unchanged_writes());
+    for tree in trees {
+        tree.dfs_mut(|path, dfs_info| {
+            if !dfs_info.is_dir && dfs_info.info.content_hash.is_none() {
+                // Left to the first change, which then can't be found unchanged
+                dfs_info.info.content_hash = reader::content_digest(path).unwrap_or_else(|err| {
+                    log::warn!("Could not hash {}: {}", display_path(path), err);
+                    None
+                });
+            }
+            Ok(DfsFuncBehaviour::Continue)
+        })?;
+    }
+    Ok(())
+}
+
+/// Reads the times of every path, for a freshly scanned forest
+fn update_times(forest: &mut PathForest<ModTimeInfo>) -> TuxDriveResult<()> {
+    forest.dfs_mut(|path, dfs_info| {
+        let old_time_info = *dfs_info.info;
+        match dfs_info.info.update_times(path, CheckPhases::ALL)? {
+            PathAction::Delete => return Ok(DfsFuncBehaviour::Delete),
+            PathAction::AccessLost => {
+                dfs_info.info.inaccessible = true;
+                return Ok(DfsFuncBehaviour::Stop);
+            }
+            PathAction::Nothing => {}
+        }
+        let action = if dfs_info.info.updated_since(&old_time_info) && dfs_info.is_dir {
+            DfsFuncBehaviour::Continue
+        } else {
+            DfsFuncBehaviour::Stop
+        };
+        Ok(action)
+    })
+}
+
+/// Touches `path` from a separate thread, so that an automounter mounts it,
+/// giving up after `timeout`.
+/// On autofs the mount point may list as empty until it is accessed, and a
+/// dead (e.g. network) mount can block any access indefinitely. A blocked probe
+/// thread is left behind, as there is no way to interrupt it.
+fn probe_path(path: &Path, timeout: Duration) -> TuxDriveResult<()> {
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    let probe_path = path.to_path_buf();
+    thread::spawn(move || {
+        let _ = fs::metadata(&probe_path).and_then(|_| fs::read_dir(&probe_path));
+        let _ = tx.send(());
+    });
+    rx.recv_timeout(timeout)
+        .map_err(|_| TuxDriveError::ProbeTimeout(path.to_path_buf()))
+}
+
 struct SendInfo<'a> {
-    sender: &'a Sender<WatchEvent>,
+    sender: &'a EventSender,
     id_gen: &'a AtomicIdGenerator,
+    group: Option<&'a Arc<str>>,
+    debouncer: Option<&'a Debouncer>,
+    /// When set, events are collected here instead of being sent, for
+    /// deletions and creations to be paired up into moves by `send_changes`.
+    /// Every event goes through it, so that they keep their order.
+    changes: Option<&'a RefCell<Vec<PendingChange>>>,
+}
+
+/// Device, inode and mtime of a file.
+/// A deleted file's inode can be reused right away by a new file,
+/// the mtime tells them apart.
+type FileId = (u64, u64, (i64, i64));
+
+/// An event to send, along with the id of the file for a deletion or creation
+#[derive(Debug, Clone)]
+struct PendingChange {
+    path: PathBuf,
+    kind: WatchEventKind,
+    file_id: Option<FileId>,
+    is_dir: bool,
+    raw_flags: Option<u32>,
+    content_hash: Option<[u8; 32]>,
+}
+
+impl PendingChange {
+    fn into_event(self, id: u64, source_id: Option<&str>) -> WatchEvent {
+        let mut event = WatchEvent::new(self.path, self.kind, self.is_dir, id, source_id);
+        event.raw_flags = self.raw_flags;
+        event.content_hash = self.content_hash;
+        event
+    }
 }
 
 impl SendInfo<'_> {
-    fn send_event<P: AsRef<Path>>(&self, path: P, kind: WatchEventKind) {
-        self.sender
-            .send(WatchEvent::new(path, kind, self.id_gen.next_id()))
-            .unwrap();
+    /// Fails with `ChannelClosed` once the consumer is gone
+    fn send_event<P: AsRef<Path>>(
+        &self,
+        path: P,
+        kind: WatchEventKind,
+        is_dir: bool,
+    ) -> TuxDriveResult<()> {
+        self.send_event_with_flags(path, kind, is_dir, None)
+    }
+
+    /// `raw_flags` is the inotify mask the event comes from
+    fn send_event_with_flags<P: AsRef<Path>>(
+        &self,
+        path: P,
+        kind: WatchEventKind,
+        is_dir: bool,
+        raw_flags: Option<u32>,
+    ) -> TuxDriveResult<()> {
+        self.queue_or_emit(PendingChange {
+            path: path.as_ref().to_path_buf(),
+            kind,
+            file_id: None,
+            is_dir,
+            raw_flags,
+            content_hash: None,
+        })
+    }
+
+    /// A `Written` or `Truncated`, along with the digest of the new content if known
+    fn send_content_changed(
+        &self,
+        path: &Path,
+        kind: WatchEventKind,
+        content_hash: Option<[u8; 32]>,
+    ) -> TuxDriveResult<()> {
+        self.queue_or_emit(PendingChange {
+            path: path.to_path_buf(),
+            kind,
+            file_id: None,
+            is_dir: false,
+            raw_flags: None,
+            content_hash,
+        })
+    }
+
+    fn queue_or_emit(&self, change: PendingChange) -> TuxDriveResult<()> {
+        match self.changes {
+            Some(changes) => {
+                changes.borrow_mut().push(change);
+                Ok(())
+            }
+            None => self.emit(&change),
+        }
+    }
+
+    /// Fails with `ChannelClosed` once the consumer is gone
+    fn emit(&self, change: &PendingChange) -> TuxDriveResult<()> {
+        if let Some(debouncer) = self.debouncer {
+            if !debouncer.admit(change, self.group, Instant::now()) {
+                return Ok(());
+            }
+        }
+        let event = change
+            .clone()
+            .into_event(self.id_gen.next_id(), self.sender.source_id());
+        self.sender.send(event, self.group)
+    }
+
+    /// `is_dir` is what the forest remembers, the path being gone
+    fn send_deleted(&self, path: &Path, info: &ModTimeInfo, is_dir: bool) -> TuxDriveResult<()> {
+        self.send_change(path, WatchEventKind::Delete, info.file_id(), is_dir)
+    }
+
+    fn send_created(&self, path: PathBuf) -> TuxDriveResult<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = fs::metadata(&path).ok();
+        let file_id = metadata.as_ref().map(|metadata| {
+            let mtime = (metadata.mtime(), metadata.mtime_nsec());
+            (metadata.dev(), metadata.ino(), mtime)
+        });
+        let is_dir = metadata.is_some_and(|metadata| metadata.is_dir());
+        self.send_change(&path, WatchEventKind::Create, file_id, is_dir)
+    }
+
+    fn send_change(
+        &self,
+        path: &Path,
+        kind: WatchEventKind,
+        file_id: Option<FileId>,
+        is_dir: bool,
+    ) -> TuxDriveResult<()> {
+        self.queue_or_emit(PendingChange {
+            path: path.to_path_buf(),
+            kind,
+            file_id,
+            is_dir,
+            raw_flags: None,
+            content_hash: None,
+        })
+    }
+
+    /// Sends the collected changes in order. A deletion and a creation of
+    /// the same file become a single `Moved`, in place of the deletion, or
+    /// of the creation with `strict_order`, so that it comes after the
+    /// creation of the directories it was moved into.
+    fn send_changes(&self, strict_order: bool) -> TuxDriveResult<()> {
+        let changes = match self.changes {
+            Some(changes) => changes.take(),
+            None => return Ok(()),
+        };
+        let mut created = HashMap::new();
+        for (idx, change) in changes.iter().enumerate() {
+            if let (WatchEventKind::Create, Some(file_id)) = (&change.kind, change.file_id) {
+                created.entry(file_id).or_insert(idx);
+            }
+        }
+        let mut moved_to = HashMap::new();
+        for (idx, change) in changes.iter().enumerate() {
+            if let (WatchEventKind::Delete, Some(file_id)) = (&change.kind, change.file_id) {
+                if let Some(created_idx) = created.remove(&file_id) {
+                    moved_to.insert(idx, created_idx);
+                }
+            }
+        }
+        let moved_from = moved_to
+            .iter()
+            .map(|(&deleted_idx, &created_idx)| (created_idx, deleted_idx))
+            .collect::<HashMap<_, _>>();
+        for (idx, change) in changes.iter().enumerate() {
+            let (from_idx, to_idx) = match (moved_to.get(&idx), moved_from.get(&idx)) {
+                (None, None) => {
+                    self.emit(change)?;
+                    continue;
+                }
+                (Some(&created_idx), _) if !strict_order => (idx, created_idx),
+                (_, Some(&deleted_idx)) if strict_order => (deleted_idx, idx),
+                // Sent along with the other half
+                _ => continue,
+            };
+            let to = changes[to_idx].path.clone();
+            let kind = WatchEventKind::Moved {
+                from: changes[from_idx].path.clone(),
+                to: to.clone(),
+            };
+            self.emit(&PendingChange {
+                path: to,
+                kind,
+                file_id: None,
+                is_dir: changes[to_idx].is_dir,
+                raw_flags: None,
+                content_hash: None,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The checks done on each path in a poll cycle.
+/// Metadata covers ctime (permissions, ownership), content covers mtime
+/// and, for directories, listing the entries to find new ones.
+#[derive(Debug, Clone, Copy)]
+struct CheckPhases {
+    metadata: bool,
+    content: bool,
+}
+
+impl CheckPhases {
+    const ALL: Self = Self {
+        metadata: true,
+        content: true,
+    };
+    const NONE: Self = Self {
+        metadata: false,
+        content: false,
+    };
+
+    fn any(self) -> bool {
+        self.metadata || self.content
     }
 }
 
-fn poll_tree(tree: &mut PathTree<ModTimeInfo>, send_info: SendInfo<'_>) -> TuxDriveResult<()> {
-    tree.dfs_mut(|path, dfs_info| {
+/// The phases to check a tree for in a poll cycle
+type TreePhases<'a> = dyn Fn(&PathTree<ModTimeInfo>) -> CheckPhases + 'a;
+
+/// With `strict_order`, the events of a path come after those of its parent.
+/// `RecursiveBehaviour::Delete` if the root itself is gone.
+fn poll_tree(
+    tree: &mut PathTree<ModTimeInfo>,
+    send_info: SendInfo<'_>,
+    phases: CheckPhases,
+    strict_order: bool,
+) -> TuxDriveResult<RecursiveBehaviour> {
+    let options = tree.options().clone();
+    let options = &options;
+    let root_path = tree.root_path();
+    let root_path = root_path.as_path();
+    // Moves show up as a deletion and a creation anywhere in the tree
+    let changes = RefCell::new(Vec::new());
+    let send_info = SendInfo {
+        changes: Some(&changes),
+        ..send_info
+    };
+    let send_info = &send_info;
+    let result = tree.dfs_mut(|path, mut dfs_info| {
         log::debug!(
             "Path: {}, Is-Dir: {}, Existing children: {}",
-            path.display(),
+            display_path(path),
             dfs_info.is_dir,
             dfs_info.children_paths.len(),
         );
 
-        if !path.exists() {
-            send_info.send_event(path, WatchEventKind::Delete);
-            return Ok(DfsFuncBehaviour::Delete);
-        }
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                // Still there, but we can't see it right now.
+                return handle_access_lost(path, &mut dfs_info, send_info);
+            }
+            Err(_) => {
+                send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
+                return Ok(DfsFuncBehaviour::Delete);
+            }
+        };
 
-        if !path.is_dir() && !path.is_file() {
-            // It is neither a file nor a directory.
+        if !options.tracks_file_type(metadata.file_type()) {
+            // It is neither a file nor a directory (nor a tracked FIFO).
             // So get rid of it.
-            send_info.send_event(path, WatchEventKind::Delete);
+            send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
             return Ok(DfsFuncBehaviour::Delete);
         }
 
-        if path.is_dir() != dfs_info.is_dir {
-            send_info.send_event(path, WatchEventKind::Delete);
+        if metadata.is_dir() != dfs_info.is_dir {
+            send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
             // We defer the "creation" until the next poll cycle
             return Ok(DfsFuncBehaviour::Delete);
         }
 
         let old_time_info = *dfs_info.info;
-        match dfs_info.info.update_times(path)? {
+        match dfs_info.info.update_times(path, phases)? {
             PathAction::Nothing => {}
             PathAction::Delete => {
-                send_info.send_event(path, WatchEventKind::Delete);
+                send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
                 return Ok(DfsFuncBehaviour::Delete);
             }
+            PathAction::AccessLost => {
+                return handle_access_lost(path, &mut dfs_info, send_info);
+            }
         }
         log::debug!(
             "Old time: {:?}, New time: {:?}",
@@ -160,123 +1020,523 @@ fn poll_tree(tree: &mut PathTree<ModTimeInfo>, send_info: SendInfo<'_>) -> TuxDr
         );
 
         if dfs_info.is_dir {
-            handle_dir(path, &dfs_info, &send_info)
+            if !phases.content {
+                return Ok(DfsFuncBehaviour::Continue);
+            }
+            handle_dir(path, &mut dfs_info, send_info, root_path, options)
         } else {
-            handle_file(path, &dfs_info, &old_time_info, &send_info)
+            handle_file(path, &mut dfs_info, &old_time_info, send_info, options)
         }
-    })?;
-    Ok(())
+    });
+    // The forest has applied whatever was collected, even if it failed part way
+    let sent = send_info.send_changes(strict_order);
+    let behaviour = result?;
+    sent?;
+    Ok(behaviour)
 }
 
-fn handle_file(
+/// The node is kept (along with its subtree) and retried on the next poll,
+/// so that a chmod on a directory doesn't look like the whole subtree got deleted.
+fn handle_access_lost(
     path: &Path,
-    dfs_info: &DfsMutInfo<ModTimeInfo>,
-    old_time_info: &ModTimeInfo,
+    dfs_info: &mut DfsMutInfo<ModTimeInfo>,
     send_info: &SendInfo<'_>,
 ) -> TuxDriveResult<DfsFuncBehaviour> {
-    if dfs_info.info.modified_since(old_time_info) {
-        send_info.send_event(path, WatchEventKind::Written);
-    } else if dfs_info.info.changed_since(old_time_info) {
-        send_info.send_event(path, WatchEventKind::Chmod);
+    if !dfs_info.info.inaccessible {
+        dfs_info.info.inaccessible = true;
+        send_info.send_event(path, WatchEventKind::AccessLost, dfs_info.is_dir)?;
     }
     Ok(DfsFuncBehaviour::Stop)
 }
 
-fn handle_dir(
+fn handle_access_regained(
     path: &Path,
-    dfs_info: &DfsMutInfo<ModTimeInfo>,
+    dfs_info: &mut DfsMutInfo<ModTimeInfo>,
     send_info: &SendInfo<'_>,
-) -> TuxDriveResult<DfsFuncBehaviour> {
-    // Handle newly created directories/files
-    let entries = match path.read_dir() {
-        Ok(v) => v,
-        Err(err) => {
-            if err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied {
-                return Ok(DfsFuncBehaviour::Delete);
-            } else {
-                return Err(err.into());
-            }
+    options: &DirectoryAddOptions,
+) -> TuxDriveResult<()> {
+    if dfs_info.info.inaccessible {
+        dfs_info.info.inaccessible = false;
+        if options.reports_access_regained() {
+            send_info.send_event(path, WatchEventKind::AccessRegained, dfs_info.is_dir)?;
         }
-    };
-    let mut new_paths = Vec::new();
-    for entry in entries {
-        let entry = match entry {
-            Ok(v) => v,
-            Err(err) => {
+    }
+    Ok(())
+}
+
+fn handle_file(
+    path: &Path,
+    dfs_info: &mut DfsMutInfo<ModTimeInfo>,
+    old_time_info: &ModTimeInfo,
+    send_info: &SendInfo<'_>,
+    options: &DirectoryAddOptions,
+) -> TuxDriveResult<DfsFuncBehaviour> {
+    handle_access_regained(path, dfs_info, send_info, options)?;
+    if dfs_info.info.mtime_went_back(old_time_info) {
+        log::warn!(
+            "mtime of {} went backwards, the system clock may have been set back",
+            display_path(path)
+        );
+    }
+    // Added in this same pass, its creation was reported already
+    let is_new = old_time_info.file_id().is_none();
+    if options.skips_unchanged_writes() && dfs_info.info.modified_since(old_time_info) {
+        // Reported as written, not knowing better
+        dfs_info.info.content_hash = reader::content_digest(path).unwrap_or_else(|err| {
+            log::warn!("Could not hash {}: {}", display_path(path), err);
+            None
+        });
+        let unchanged = old_time_info.content_hash.is_some()
+            && dfs_info.info.content_hash == old_time_info.content_hash;
+        if unchanged {
+            log::debug!("{} was rewritten unchanged", display_path(path));
+            dfs_info
+                .info
+                .send_perms_changed(old_time_info, path, send_info)?;
+            return Ok(DfsFuncBehaviour::Stop);
+        }
+    }
+    if is_new {
+        return Ok(DfsFuncBehaviour::Stop);
+    }
+    dfs_info
+        .info
+        .send_modified(old_time_info, path, send_info, options.reports_access())?;
+    Ok(DfsFuncBehaviour::Stop)
+}
+
+/// Fails unless the directory can be looked inside, which takes both read and execute
+fn dir_access(path: &Path) -> nix::Result<()> {
+    use nix::unistd::{self, AccessFlags};
+
+    #[cfg(test)]
+    if test::DENIED_ACCESS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|denied| denied == path)
+    {
+        return Err(nix::errno::Errno::EACCES);
+    }
+    unistd::access(path, AccessFlags::R_OK | AccessFlags::X_OK)
+}
+
+fn handle_dir(
+    path: &Path,
+    dfs_info: &mut DfsMutInfo<ModTimeInfo>,
+    send_info: &SendInfo<'_>,
+    root_path: &Path,
+    options: &DirectoryAddOptions,
+) -> TuxDriveResult<DfsFuncBehaviour> {
+    use nix::errno::Errno;
+
+    match dir_access(path) {
+        Ok(()) => {}
+        Err(Errno::EACCES) => return handle_access_lost(path, dfs_info, send_info),
+        Err(Errno::ENOENT) => {
+            send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
+            return Ok(DfsFuncBehaviour::Delete);
+        }
+        Err(err) => return Err(TuxDriveError::nix_at(path, err)),
+    }
+
+    // Handle newly created directories/files
+    let entries = match path.read_dir() {
+        Ok(v) => v,
+        Err(err) => {
+            if err.kind() == ErrorKind::PermissionDenied {
+                return handle_access_lost(path, dfs_info, send_info);
+            } else if err.kind() == ErrorKind::NotFound {
+                send_info.send_deleted(path, dfs_info.info, dfs_info.is_dir)?;
+                return Ok(DfsFuncBehaviour::Delete);
+            } else {
+                return Err(TuxDriveError::io_at(path, err));
+            }
+        }
+    };
+    // Anything that changed in the meantime is picked up by the regular scan below
+    handle_access_regained(path, dfs_info, send_info, options)?;
+    // Could have been created or changed since the last poll
+    options.load_gitignore(path);
+    if !options.allows_depth(depth_below(root_path, path) + 1) {
+        return Ok(DfsFuncBehaviour::Continue);
+    }
+    let mut new_paths = Vec::new();
+    let mut renamed = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(v) => v,
+            Err(err) => {
                 if err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied {
                     continue;
                 } else {
-                    return Err(err.into());
+                    return Err(TuxDriveError::io_at(path, err));
                 }
             }
         };
         if !dfs_info.children_paths.contains(&entry.path()) {
-            // Only add files and directories
-            if !entry.path().is_dir() && !entry.path().is_file() {
+            // Only add files and directories, and FIFOs if asked for
+            let metadata = match new_entry_metadata(&entry.path(), root_path, options) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            if options.is_ignored(root_path, &entry.path(), metadata.is_dir()) {
                 continue;
             }
+            if metadata.is_dir() {
+                if options.skips_dir(&entry.path()) {
+                    continue;
+                }
+            } else if !options.tracks_file(&entry.path()) {
+                continue;
+            }
+            if metadata.is_dir() {
+                if let Some(from) = renamed_from(path, &metadata, dfs_info, &renamed) {
+                    let kind = WatchEventKind::Moved {
+                        from: from.clone(),
+                        to: entry.path(),
+                    };
+                    send_info.send_change(&entry.path(), kind, None, true)?;
+                    renamed.push((from, entry.path()));
+                    continue;
+                }
+            }
             // Newly found path
             new_paths.push(entry.path());
-            send_info.send_event(entry.path(), WatchEventKind::Create);
+            send_info.send_created(entry.path())?;
         }
     }
 
-    // Handle recursion
-    if !new_paths.is_empty() {
+    if options.detects_case_collisions() && !new_paths.is_empty() {
+        report_case_collisions(&dfs_info.children_paths, &new_paths, send_info)?;
+    }
+
+    // Handle recursion. The new paths are visited in this same pass,
+    // so a new directory's own entries are found (and created) right away.
+    if !renamed.is_empty() {
+        Ok(DfsFuncBehaviour::RenameAndContinue {
+            renamed,
+            added: new_paths,
+        })
+    } else if !new_paths.is_empty() {
         Ok(DfsFuncBehaviour::AddAndContinue(new_paths))
     } else {
         Ok(DfsFuncBehaviour::Continue)
     }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+/// The old path of a directory of `dir` that was renamed to a new entry
+/// with `metadata`: a child with the same device and inode whose path is gone.
+fn renamed_from(
+    dir: &Path,
+    metadata: &fs::Metadata,
+    dfs_info: &DfsMutInfo<ModTimeInfo>,
+    renamed: &[(PathBuf, PathBuf)],
+) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    dfs_info
+        .children()
+        .filter(|(_, info, is_dir)| {
+            *is_dir && info.dev == metadata.dev() && info.ino == metadata.ino()
+        })
+        .map(|(name, _, _)| dir.join(name))
+        .find(|from| {
+            !renamed.iter().any(|(old, _)| old == from)
+                && fs::symlink_metadata(from).is_err_and(|err| err.kind() == ErrorKind::NotFound)
+        })
+}
+
+/// Metadata of an entry that appeared below `root_path`, that of its target
+/// for a symlink if they are followed. `None` if the entry isn't to be
+/// tracked, which includes a symlink to a directory above it: following it
+/// would never end.
+fn new_entry_metadata(
+    path: &Path,
+    root_path: &Path,
+    options: &DirectoryAddOptions,
+) -> Option<fs::Metadata> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = if options.follows_symlinks() {
+        fs::metadata(path).ok()?
+    } else {
+        fs::symlink_metadata(path).ok()?
+    };
+    if !options.tracks_file_type(metadata.file_type()) {
+        return None;
+    }
+    if metadata.is_dir() && path.is_symlink() {
+        let loops = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| ancestor.starts_with(root_path))
+            .filter_map(|ancestor| fs::metadata(ancestor).ok())
+            .any(|ancestor| (ancestor.dev(), ancestor.ino()) == (metadata.dev(), metadata.ino()));
+        if loops {
+            return None;
+        }
+    }
+    Some(metadata)
+}
+
+/// Reports each of `new_paths` whose name only differs by case from
+/// an existing entry of the directory or another new one.
+fn report_case_collisions(
+    existing_paths: &HashSet<PathBuf>,
+    new_paths: &[PathBuf],
+    send_info: &SendInfo<'_>,
+) -> TuxDriveResult<()> {
+    let folded_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+    };
+    let mut seen = existing_paths
+        .iter()
+        .filter_map(|path| Some((folded_name(path)?, path)))
+        .collect::<HashMap<_, _>>();
+    for new_path in new_paths {
+        let folded = match folded_name(new_path) {
+            Some(folded) => folded,
+            None => continue,
+        };
+        match seen.get(&folded) {
+            Some(other) => {
+                log::warn!(
+                    "{} and {} only differ by case",
+                    display_path(new_path),
+                    display_path(other)
+                );
+                send_info.send_event(
+                    new_path,
+                    WatchEventKind::NameCollision {
+                        with: other.to_path_buf(),
+                    },
+                    new_path.is_dir(),
+                )?;
+            }
+            None => {
+                seen.insert(folded, new_path);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 struct ModTimeInfo {
-    mtime: i64,
-    ctime: i64,
+    /// Seconds and nanoseconds, compared in that order
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    /// Missing from state saved before it was tracked
+    #[serde(default)]
+    atime: (i64, i64),
+    size: i64,
+    /// Permission bits, and owning user and group, read along with the ctime
+    mode: u32,
+    owner: (u32, u32),
+    dev: u64,
+    ino: u64,
+    /// Set while the path exists but can't be stat-ed or listed
+    inaccessible: bool,
+    /// Of the content, only kept with `DirectoryAddOptions::skip_unchanged_writes`
+    #[serde(default, with = "hex_digest")]
+    content_hash: Option<[u8; 32]>,
 }
 
+/// The times are read once the paths are added, with `update_times`
+impl NodeInfo for ModTimeInfo {}
+
 impl ModTimeInfo {
-    fn update_times<P: AsRef<Path>>(&mut self, path: P) -> TuxDriveResult<PathAction> {
+    fn update_times<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        phases: CheckPhases,
+    ) -> TuxDriveResult<PathAction> {
         use nix::sys;
 
         let path = path.as_ref();
         let stat = match sys::stat::stat(path) {
             Ok(stat) => stat,
             Err(err) => {
-                if err == nix::Error::ENOENT || err == nix::Error::EACCES {
+                if err == nix::Error::ENOENT {
                     return Ok(PathAction::Delete);
+                } else if err == nix::Error::EACCES {
+                    return Ok(PathAction::AccessLost);
                 } else {
-                    return Err(err.into());
+                    return Err(TuxDriveError::nix_at(path, err));
                 }
             }
         };
-        self.mtime = stat.st_mtime;
-        self.ctime = stat.st_ctime;
+        self.dev = stat.st_dev;
+        self.ino = stat.st_ino;
+        if phases.content {
+            self.mtime = (stat.st_mtime, stat.st_mtime_nsec);
+            self.ctime = (stat.st_ctime, stat.st_ctime_nsec);
+            self.atime = (stat.st_atime, stat.st_atime_nsec);
+            self.size = stat.st_size;
+            self.mode = stat.st_mode & 0o7777;
+            self.owner = (stat.st_uid, stat.st_gid);
+        } else if phases.metadata && (stat.st_mtime, stat.st_mtime_nsec) == self.mtime {
+            // A content change bumps the ctime too, so those are left to the content phase
+            self.ctime = (stat.st_ctime, stat.st_ctime_nsec);
+            self.mode = stat.st_mode & 0o7777;
+            self.owner = (stat.st_uid, stat.st_gid);
+        }
 
         Ok(PathAction::Nothing)
     }
 
+    /// Identifies the file across renames, once its times were read
+    fn file_id(&self) -> Option<FileId> {
+        (self.ino != 0).then_some((self.dev, self.ino, self.mtime))
+    }
+
+    /// A size change counts even with the same mtime, as filesystems with
+    /// coarse timestamps may give two writes the same one.
     fn modified_since(&self, since: &Self) -> bool {
-        self.mtime > since.mtime
+        self.mtime > since.mtime || self.size != since.size || self.mtime_went_back(since)
+    }
+
+    fn truncated_since(&self, since: &Self) -> bool {
+        self.size < since.size
+    }
+
+    /// The mtime is older than before, yet the file did change.
+    /// Happens when the clock is stepped back between two writes.
+    fn mtime_went_back(&self, since: &Self) -> bool {
+        self.mtime < since.mtime && (self.ctime != since.ctime || self.size != since.size)
     }
 
     fn changed_since(&self, since: &Self) -> bool {
         self.ctime > since.ctime
     }
 
+    /// Sends the events for a ctime change without a content change:
+    /// `Chown` if the owner changed, `Chmod` for anything else
+    fn send_changed(
+        &self,
+        since: &Self,
+        path: &Path,
+        send_info: &SendInfo<'_>,
+    ) -> TuxDriveResult<()> {
+        let chowned = self.owner != since.owner;
+        if chowned {
+            send_info.send_event(path, WatchEventKind::Chown, false)?;
+        }
+        if !chowned || self.mode != since.mode {
+            send_info.send_event(path, WatchEventKind::Chmod, false)?;
+        }
+        Ok(())
+    }
+
+    /// Along with a content change, which bumps the ctime too: `Chown` and
+    /// `Chmod` only if the owner or permission bits themselves changed.
+    /// Nothing for a file first seen since `since`.
+    fn send_perms_changed(
+        &self,
+        since: &Self,
+        path: &Path,
+        send_info: &SendInfo<'_>,
+    ) -> TuxDriveResult<()> {
+        if since.file_id().is_none() {
+            return Ok(());
+        }
+        if self.owner != since.owner {
+            send_info.send_event(path, WatchEventKind::Chown, false)?;
+        }
+        if self.mode != since.mode {
+            send_info.send_event(path, WatchEventKind::Chmod, false)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the events for whatever changed about a file since `since`,
+    /// and with `report_access` an `Accessed` if it was only read
+    fn send_modified(
+        &self,
+        since: &Self,
+        path: &Path,
+        send_info: &SendInfo<'_>,
+        report_access: bool,
+    ) -> TuxDriveResult<()> {
+        if self.truncated_since(since) {
+            send_info.send_content_changed(path, WatchEventKind::Truncated, self.content_hash)?;
+            self.send_perms_changed(since, path, send_info)
+        } else if self.modified_since(since) {
+            send_info.send_content_changed(path, WatchEventKind::Written, self.content_hash)?;
+            self.send_perms_changed(since, path, send_info)
+        } else if self.changed_since(since) {
+            self.send_changed(since, path, send_info)
+        } else if report_access && self.atime > since.atime {
+            send_info.send_event(path, WatchEventKind::Accessed, false)
+        } else {
+            Ok(())
+        }
+    }
+
     fn updated_since(&self, since: &Self) -> bool {
         self.modified_since(since) || self.changed_since(since)
     }
 }
 
-#[derive(Debug)]
+/// Serialized with serde, e.g. to JSON for other processes to consume.
+/// Paths that are not valid UTF-8 fail to serialize.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WatchEvent {
     pub path: PathBuf,
     pub kind: WatchEventKind,
-    pub id: u32,
+    /// Whether the path is (or, once deleted, was) a directory.
+    /// Events not about a single path, like `Overflow`, have it false.
+    #[serde(default)]
+    pub is_dir: bool,
+    pub id: u64,
+    /// Identifies the machine the event comes from, the hostname by default
+    pub source_id: Option<String>,
+    /// The inotify mask (`IN_*` flags) the event was made from, e.g. to tell
+    /// a write in progress (`IN_MODIFY`) from a writer being done
+    /// (`IN_CLOSE_WRITE`). Only set by the inotify backend: `None` when polling.
+    pub raw_flags: Option<u32>,
+    /// BLAKE3 digest of the new content, for a `Written` or `Truncated` in a
+    /// tree skipping unchanged writes, which hashes the files anyway.
+    /// Given to `ReadCommand::with_digest`, a `Hash` read needs no reading.
+    /// Serialized as a hex string.
+    #[serde(default, with = "hex_digest")]
+    pub content_hash: Option<[u8; 32]>,
+}
+
+/// (De)serializes an optional BLAKE3 digest as a hex string
+mod hex_digest {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        digest: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match digest {
+            Some(digest) => {
+                serializer.serialize_some(blake3::Hash::from(*digest).to_hex().as_str())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| {
+                blake3::Hash::from_hex(hex)
+                    .map(|hash| *hash.as_bytes())
+                    .map_err(D::Error::custom)
+            })
+            .transpose()
+    }
 }
 
-#[derive(Debug)]
+/// Serialized externally tagged: `"Create"`, or `{"Moved":{"from":...,"to":...}}`
+/// for the kinds carrying data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WatchEventKind {
     // Emitted for both directories and files
     Create,
@@ -284,19 +1544,101 @@ pub enum WatchEventKind {
     // If directory is deleted, only emitted for it (not descendants)
     Delete,
 
-    // Emitted only for file
+    // Emitted only for file. With the inotify backend, also emitted once
+    // the writer closes the file, with IN_CLOSE_WRITE in the raw flags.
     Written,
 
-    // Emiited only for file
+    // Emitted only for file. Also follows a Written (or Truncated) when
+    // the permissions changed along with the content.
     Chmod,
+
+    // Emitted only for file, when its owning user or group changed.
+    // Followed by a Chmod only if the permissions changed as well.
+    Chown,
+
+    // Emitted only for file, instead of Written, when it got smaller
+    Truncated,
+
+    // Emitted only for file, when it was read but is otherwise unchanged.
+    // Only emitted with `DirectoryAddOptions::report_access`.
+    Accessed,
+
+    // A file or directory was renamed or moved within the same watched
+    // directory, between two polls. The event's path is `to`.
+    // A directory renamed within its parent keeps its entries, which
+    // aren't reported again. The entries of a directory moved elsewhere
+    // follow as Create events.
+    Moved { from: PathBuf, to: PathBuf },
+
+    // Emitted once when a path can no longer be accessed (but still exists).
+    // The path stays tracked and is retried on later polls.
+    AccessLost,
+
+    // Emitted once when a path that had lost access becomes accessible again.
+    // Changes made in the meantime follow as regular events.
+    AccessRegained,
+
+    // Something below a root watched in summary-only mode changed.
+    // Emitted for the root, at most once per poll cycle.
+    RootChanged,
+
+    // A new entry's name only differs by case from the one of `with`,
+    // in the same directory. Both stay tracked.
+    // Only emitted with `DirectoryAddOptions::detect_case_collisions`.
+    NameCollision { with: PathBuf },
+
+    // Events were dropped because the bounded event channel was full.
+    // Not tied to a path: the path is empty and the id is 0.
+    Overflow { dropped: u64 },
 }
 
 impl WatchEvent {
-    fn new<P: AsRef<Path>>(path: P, kind: WatchEventKind, id: u32) -> Self {
+    fn new<P: AsRef<Path>>(
+        path: P,
+        kind: WatchEventKind,
+        is_dir: bool,
+        id: u64,
+        source_id: Option<&str>,
+    ) -> Self {
         Self {
             path: path.as_ref().to_path_buf(),
             kind,
+            is_dir,
             id,
+            source_id: source_id.map(String::from),
+            raw_flags: None,
+            content_hash: None,
+        }
+    }
+
+    /// Whether the inotify backend reported the file being closed after
+    /// writing: the writer is done, so the file can be read in full
+    pub fn is_close_write(&self) -> bool {
+        use nix::sys::inotify::AddWatchFlags;
+
+        self.raw_flags.is_some_and(|flags| {
+            AddWatchFlags::from_bits_truncate(flags).contains(AddWatchFlags::IN_CLOSE_WRITE)
+        })
+    }
+}
+
+impl WatchEventKind {
+    /// Name of the kind in snake case, e.g. `"name_collision"`
+    pub fn name(&self) -> &'static str {
+        match self {
+            WatchEventKind::Create => "create",
+            WatchEventKind::Delete => "delete",
+            WatchEventKind::Written => "written",
+            WatchEventKind::Chmod => "chmod",
+            WatchEventKind::Chown => "chown",
+            WatchEventKind::Truncated => "truncated",
+            WatchEventKind::Accessed => "accessed",
+            WatchEventKind::Moved { .. } => "moved",
+            WatchEventKind::AccessLost => "access_lost",
+            WatchEventKind::AccessRegained => "access_regained",
+            WatchEventKind::RootChanged => "root_changed",
+            WatchEventKind::NameCollision { .. } => "name_collision",
+            WatchEventKind::Overflow { .. } => "overflow",
         }
     }
 }
@@ -304,4 +1646,1117 @@ impl WatchEvent {
 enum PathAction {
     Nothing,
     Delete,
+    AccessLost,
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+
+    use std::sync::Mutex;
+
+    use nix::sys::time::{TimeVal, TimeValLike};
+
+    use super::*;
+
+    /// Directories `dir_access` refuses, as a chmod would for anyone but root
+    pub(super) static DENIED_ACCESS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    /// Access to the directory is denied until this is dropped
+    struct DeniedAccess(PathBuf);
+
+    impl DeniedAccess {
+        fn new(path: &Path) -> Self {
+            DENIED_ACCESS.lock().unwrap().push(path.to_path_buf());
+            Self(path.to_path_buf())
+        }
+    }
+
+    impl Drop for DeniedAccess {
+        fn drop(&mut self) {
+            DENIED_ACCESS.lock().unwrap().retain(|path| *path != self.0);
+        }
+    }
+
+    #[test]
+    pub fn metadata_phase_leaves_content_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        File::create(&path).unwrap();
+        let mut info = ModTimeInfo::default();
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        let old_info = info;
+
+        // Move the mtime, as a write would
+        let past = TimeVal::seconds(old_info.mtime.0 - 100);
+        nix::sys::stat::utimes(&path, &past, &past).unwrap();
+
+        let metadata_only = CheckPhases {
+            metadata: true,
+            content: false,
+        };
+        info.update_times(&path, metadata_only).unwrap();
+        assert!(!info.changed_since(&old_info));
+        assert_eq!(info.mtime, old_info.mtime);
+
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        assert_eq!(info.mtime, (old_info.mtime.0 - 100, 0));
+    }
+
+    #[test]
+    pub fn probe_existing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(probe_path(dir.path(), Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    pub fn older_mtime_with_new_size_is_a_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"old").unwrap();
+        let mut info = ModTimeInfo::default();
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        let old_info = info;
+
+        // Written after the clock was set back
+        fs::write(&path, b"new content").unwrap();
+        let past = TimeVal::seconds(old_info.mtime.0 - 100);
+        nix::sys::stat::utimes(&path, &past, &past).unwrap();
+
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        assert!(info.mtime_went_back(&old_info));
+        assert!(info.modified_since(&old_info));
+
+        // Nothing changed since, so nothing to report
+        let old_info = info;
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        assert!(!info.modified_since(&old_info));
+    }
+
+    #[test]
+    pub fn initial_events_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("a"), b"").unwrap();
+        fs::write(root.join("b"), b"").unwrap();
+        let (mut watcher, events) = Watcher::builder()
+            .emit_initial_events(true)
+            .initial_event_batches(2, Duration::from_millis(1))
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let created = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Create))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            vec![
+                root.clone(),
+                root.join("b"),
+                root.join("sub"),
+                root.join("sub").join("a"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn same_mtime_write_is_a_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"old").unwrap();
+        let same = TimeVal::seconds(1_600_000_000);
+        nix::sys::stat::utimes(&path, &same, &same).unwrap();
+        let mut info = ModTimeInfo::default();
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        let old_info = info;
+
+        // As happens on filesystems with coarse timestamps
+        fs::write(&path, b"longer content").unwrap();
+        nix::sys::stat::utimes(&path, &same, &same).unwrap();
+
+        info.update_times(&path, CheckPhases::ALL).unwrap();
+        assert_eq!(info.mtime, old_info.mtime);
+        assert!(info.modified_since(&old_info));
+        assert!(!info.truncated_since(&old_info));
+    }
+
+    #[test]
+    pub fn nanoseconds_tell_writes_apart() {
+        let old_info = ModTimeInfo {
+            mtime: (1_600_000_000, 100),
+            ctime: (1_600_000_000, 100),
+            ..Default::default()
+        };
+        let info = ModTimeInfo {
+            mtime: (1_600_000_000, 200),
+            ctime: (1_600_000_000, 200),
+            ..Default::default()
+        };
+        assert!(info.modified_since(&old_info));
+        assert!(info.changed_since(&old_info));
+        assert!(!old_info.changed_since(&info));
+        assert!(!info.modified_since(&info));
+    }
+
+    #[test]
+    pub fn chown_is_told_apart_from_chmod() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use nix::unistd::{self, Gid, Uid};
+
+        // Only root may give files away
+        if !unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let kinds = |watcher: &mut Watcher| {
+            watcher.poll_phases(CheckPhases::ALL).unwrap();
+            events
+                .try_iter()
+                .filter(|event| event.path == path)
+                .map(|event| event.kind.name())
+                .collect::<Vec<_>>()
+        };
+
+        unistd::chown(&path, Some(Uid::from_raw(1234)), None).unwrap();
+        assert_eq!(kinds(&mut watcher), vec!["chown"]);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(kinds(&mut watcher), vec!["chmod"]);
+        unistd::chown(&path, None, Some(Gid::from_raw(5678))).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(kinds(&mut watcher), vec!["chown", "chmod"]);
+    }
+
+    #[test]
+    pub fn new_symlinks_are_followed_once() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("root");
+        let outside = dir.path().canonicalize().unwrap().join("outside");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("file"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().follow_symlinks(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        symlink("../outside", root.join("linked")).unwrap();
+        symlink("..", root.join("sub").join("up")).unwrap();
+        symlink("../outside/file", root.join("file")).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        let mut created = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Create))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        created.sort();
+        assert_eq!(
+            created,
+            vec![
+                root.join("file"),
+                root.join("linked"),
+                root.join("linked").join("file"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn events_round_trip_through_json() {
+        let events = [
+            WatchEvent::new("/a/file", WatchEventKind::Written, false, 7, Some("host")),
+            WatchEvent::new(
+                "/a/new",
+                WatchEventKind::Moved {
+                    from: PathBuf::from("/a/old"),
+                    to: PathBuf::from("/a/new"),
+                },
+                true,
+                8,
+                None,
+            ),
+        ];
+        let lines = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            r#"{"path":"/a/file","kind":"Written","is_dir":false,"id":7,"source_id":"host","raw_flags":null,"content_hash":null}"#
+        );
+        for (event, line) in events.iter().zip(&lines) {
+            let parsed: WatchEvent = serde_json::from_str(line).unwrap();
+            assert_eq!(parsed.path, event.path);
+            assert_eq!(parsed.is_dir, event.is_dir);
+            assert_eq!(parsed.id, event.id);
+            assert_eq!(parsed.source_id, event.source_id);
+            assert_eq!(format!("{:?}", parsed.kind), format!("{:?}", event.kind));
+        }
+    }
+
+    #[test]
+    pub fn max_depth_applies_to_new_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().max_depth(2);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        fs::create_dir_all(root.join("a").join("b").join("c")).unwrap();
+        fs::write(root.join("a").join("b").join("file"), b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        let created = events
+            .try_iter()
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        assert_eq!(created, vec![root.join("a"), root.join("a").join("b")]);
+    }
+
+    #[test]
+    pub fn truncation_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"some content").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let same = TimeVal::seconds(1_600_000_000);
+        nix::sys::stat::utimes(&path, &same, &same).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        events.try_iter().for_each(drop);
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        nix::sys::stat::utimes(&path, &same, &same).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .filter(|event| event.path == path)
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        assert!(matches!(kinds[..], [WatchEventKind::Truncated]));
+    }
+
+    #[test]
+    pub fn case_collisions_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("README.md"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().detect_case_collisions(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        fs::write(root.join("readme.md"), b"").unwrap();
+        fs::write(root.join("other.md"), b"").unwrap();
+        watcher.poll().unwrap();
+
+        let collisions = events
+            .try_iter()
+            .filter_map(|event| match event.kind {
+                WatchEventKind::NameCollision { with } => Some((event.path, with)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            collisions,
+            vec![(root.join("readme.md"), root.join("README.md"))]
+        );
+    }
+
+    #[test]
+    pub fn renames_and_moves_are_paired() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(root.join("c.txt"), b"c").unwrap();
+        fs::write(root.join("gone.txt"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::rename(root.join("a.txt"), root.join("b.txt")).unwrap();
+        fs::rename(root.join("c.txt"), root.join("sub").join("c.txt")).unwrap();
+        fs::remove_file(root.join("gone.txt")).unwrap();
+        fs::write(root.join("new.txt"), b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let mut moves = Vec::new();
+        let mut others = Vec::new();
+        for event in events.try_iter() {
+            match event.kind {
+                WatchEventKind::Moved { from, to } => {
+                    assert_eq!(event.path, to);
+                    moves.push((from, to));
+                }
+                WatchEventKind::Create | WatchEventKind::Delete => {
+                    others.push((format!("{:?}", event.kind), event.path))
+                }
+                _ => {}
+            }
+        }
+        moves.sort();
+        assert_eq!(
+            moves,
+            vec![
+                (root.join("a.txt"), root.join("b.txt")),
+                (root.join("c.txt"), root.join("sub").join("c.txt")),
+            ]
+        );
+        others.sort();
+        assert_eq!(
+            others,
+            vec![
+                ("Create".to_string(), root.join("new.txt")),
+                ("Delete".to_string(), root.join("gone.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn renamed_directory_keeps_its_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (old, new) = (root.join("old"), root.join("new"));
+        fs::create_dir_all(old.join("sub")).unwrap();
+        fs::write(old.join("file"), b"file").unwrap();
+        fs::write(old.join("sub").join("nested"), b"nested").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let file_info = *watcher.forest.get(old.join("file")).unwrap();
+
+        fs::rename(&old, &new).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0].path, new);
+        assert!(events[0].is_dir);
+        assert!(matches!(
+            &events[0].kind,
+            WatchEventKind::Moved { from, to } if *from == old && *to == new
+        ));
+        assert!(!watcher.forest.contains(&old));
+        assert!(watcher.forest.contains(new.join("sub").join("nested")));
+        let moved_info = watcher.forest.get(new.join("file")).unwrap();
+        assert_eq!(moved_info.mtime, file_info.mtime);
+        assert_eq!(moved_info.ino, file_info.ino);
+    }
+
+    #[test]
+    pub fn strict_ordering_puts_parents_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("x"), "x").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .strict_tree_ordering(true)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let nested = path![root, "a", "b", "c"];
+        fs::create_dir_all(&nested).unwrap();
+        fs::rename(root.join("x"), nested.join("x")).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let events = events
+            .try_iter()
+            .map(|event| match event.kind {
+                WatchEventKind::Moved { from, .. } => ("moved", from, event.path),
+                kind => (kind.name(), PathBuf::new(), event.path),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ("create", PathBuf::new(), path![root, "a"]),
+                ("create", PathBuf::new(), path![root, "a", "b"]),
+                ("create", PathBuf::new(), nested.clone()),
+                ("moved", root.join("x"), nested.join("x")),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn dropped_receiver_ends_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .poll_interval(Duration::from_millis(10))
+            .poll_retries(3, Duration::from_secs(60))
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        drop(events);
+
+        fs::write(root.join("file"), b"").unwrap();
+        assert!(matches!(
+            watcher.poll_phases(CheckPhases::ALL),
+            Err(TuxDriveError::ChannelClosed)
+        ));
+        // Neither retried nor reported as a failure
+        fs::write(root.join("other"), b"").unwrap();
+        watcher.start_polling().unwrap();
+    }
+
+    #[test]
+    pub fn new_gitignore_applies_to_new_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().gitignore(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        fs::write(root.join(".gitignore"), "*.tmp\n").unwrap();
+        fs::write(root.join("sub").join(".gitignore"), "!keep.tmp\n").unwrap();
+        for name in ["a.tmp", "keep.tmp", "b.txt"] {
+            fs::write(root.join("sub").join(name), b"").unwrap();
+        }
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        let mut created = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Create))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        created.sort();
+        assert_eq!(
+            created,
+            vec![
+                root.join(".gitignore"),
+                root.join("sub").join(".gitignore"),
+                root.join("sub").join("b.txt"),
+                root.join("sub").join("keep.tmp"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn watched_file_is_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        fs::write(root.join("sibling"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            watcher.add_file(&root),
+            Err(TuxDriveError::NotFile(_))
+        ));
+        watcher.add_file(&path).unwrap();
+
+        fs::write(&path, b"content").unwrap();
+        let later = TimeVal::seconds(1_600_000_000);
+        nix::sys::stat::utimes(&path, &later, &later).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(root.join("sibling"), b"content").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Written".to_string(), path.clone()),
+                ("Chmod".to_string(), path)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn write_and_chmod_both_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (path, written) = (root.join("script"), root.join("written"));
+        fs::write(&path, b"").unwrap();
+        fs::write(&written, b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        thread::sleep(Duration::from_millis(10));
+        fs::write(&path, b"#!/bin/sh").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(&written, b"content").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let mut kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        kinds.sort();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Chmod".to_string(), path.clone()),
+                ("Written".to_string(), path),
+                ("Written".to_string(), written),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn watched_file_is_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_file(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        // Not picked up again once it is back
+        fs::write(&path, b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![("Delete".to_string(), path)]);
+    }
+
+    #[test]
+    pub fn builder_sets_thread_count() {
+        let (watcher, _events) = Watcher::builder().num_threads(7).build().unwrap();
+        assert_eq!(watcher.pool.current_num_threads(), 7);
+    }
+
+    #[test]
+    pub fn metrics_follow_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .poll_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        assert_eq!(watcher.metrics(), WatcherMetrics::default());
+
+        let handle = watcher.handle();
+        let poller = thread::spawn(move || watcher.start_polling());
+        fs::write(root.join("sub").join("file"), b"").unwrap();
+        events.recv_timeout(Duration::from_secs(10)).unwrap();
+        while handle.metrics().watched_paths < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let metrics = handle.metrics();
+        handle.stop();
+        poller.join().unwrap().unwrap();
+        assert_eq!(metrics.watched_paths, 3);
+        assert!(metrics.poll_cycles >= 1);
+        let sent = metrics.events.values().sum::<u64>();
+        assert_eq!(sent, 1 + events.try_iter().count() as u64);
+    }
+
+    #[test]
+    pub fn events_tell_directories_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("file"), b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        fs::remove_dir_all(root.join("sub")).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .map(|event| (event.kind.name(), event.path, event.is_dir))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("create", root.join("sub"), true),
+                ("create", root.join("sub").join("file"), false),
+                // Known from the forest, the directory being gone
+                ("delete", root.join("sub"), true),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn new_subtree_created_in_one_poll() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let deepest = path![root, "a", "b", "c"];
+        fs::create_dir_all(&deepest).unwrap();
+        for dir in deepest.ancestors().take(3) {
+            fs::write(dir.join("file"), b"").unwrap();
+        }
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let mut created = events
+            .try_iter()
+            .map(|event| {
+                assert!(matches!(event.kind, WatchEventKind::Create));
+                event.path
+            })
+            .collect::<Vec<_>>();
+        created.sort();
+        assert_eq!(
+            created,
+            vec![
+                path![root, "a"],
+                path![root, "a", "b"],
+                path![root, "a", "b", "c"],
+                path![root, "a", "b", "c", "file"],
+                path![root, "a", "b", "file"],
+                path![root, "a", "file"],
+            ]
+        );
+    }
+
+    #[test]
+    pub fn removed_directory_is_no_longer_watched() {
+        for backend in [WatcherBackend::Polling, WatcherBackend::Inotify] {
+            let (kept, removed) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            let kept = kept.path().canonicalize().unwrap();
+            let removed = removed.path().canonicalize().unwrap();
+            let (mut watcher, events) = WatcherBuilder::new().backend(backend).build().unwrap();
+            watcher.add_directory(&kept, true).unwrap();
+            watcher.add_directory(&removed, true).unwrap();
+
+            assert!(watcher.remove_directory(&removed).unwrap());
+            assert!(!watcher.remove_directory(&removed).unwrap());
+            fs::write(kept.join("file"), b"").unwrap();
+            fs::write(removed.join("file"), b"").unwrap();
+            watcher.inotify_step(Duration::from_millis(50)).unwrap();
+
+            let paths = events
+                .try_iter()
+                .map(|event| event.path)
+                .collect::<HashSet<_>>();
+            assert_eq!(paths, HashSet::from([kept.join("file")]), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    pub fn changes_while_paused_surface_on_resume() {
+        for backend in [WatcherBackend::Polling, WatcherBackend::Inotify] {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            let file = root.join("file");
+            fs::write(&file, b"").unwrap();
+            let (mut watcher, events) = WatcherBuilder::new()
+                .poll_interval(Duration::from_millis(10))
+                .backend(backend)
+                .build()
+                .unwrap();
+            watcher.add_directory(&root, true).unwrap();
+            watcher.pause();
+            let handle = watcher.handle();
+            let poller = thread::spawn(move || watcher.start_polling());
+
+            fs::write(&file, b"content").unwrap();
+            let later = TimeVal::seconds(1_600_000_000);
+            nix::sys::stat::utimes(&file, &later, &later).unwrap();
+            fs::create_dir(root.join("new")).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            assert!(events.try_recv().is_err(), "{:?}", backend);
+
+            handle.resume();
+            let mut kinds = (0..2)
+                .map(|_| {
+                    let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+                    (event.kind.name(), event.path)
+                })
+                .collect::<Vec<_>>();
+            // Nothing more on the next polls
+            thread::sleep(Duration::from_millis(50));
+            handle.stop();
+            poller.join().unwrap().unwrap();
+            kinds.extend(
+                events
+                    .try_iter()
+                    .map(|event| (event.kind.name(), event.path)),
+            );
+            kinds.sort();
+            assert_eq!(
+                kinds,
+                vec![("create", root.join("new")), ("written", file)],
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    pub fn polls_jittered_within_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let file = root.join("file");
+        fs::write(&file, b"").unwrap();
+        let interval = Duration::from_millis(100);
+        let (mut watcher, events) = WatcherBuilder::new()
+            .poll_interval(interval)
+            .poll_jitter(0.5)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let handle = watcher.handle();
+        let polling = thread::spawn(move || watcher.start_polling());
+        // Each poll finds the file written, so the events tell when it polled
+        let poll_times =
+            thread::spawn(move || events.iter().map(|_| Instant::now()).collect::<Vec<_>>());
+
+        let start = Instant::now();
+        let mut len = 0;
+        while start.elapsed() < Duration::from_secs(3) {
+            len += 1;
+            fs::write(&file, vec![0; len]).unwrap();
+            thread::sleep(Duration::from_millis(2));
+        }
+        handle.stop();
+        polling.join().unwrap().unwrap();
+        let poll_times = poll_times.join().unwrap();
+
+        let gaps = poll_times
+            .windows(2)
+            .map(|times| times[1] - times[0])
+            .collect::<Vec<_>>();
+        assert!(gaps.len() > 10);
+        assert!(gaps.iter().all(|gap| *gap >= interval.mul_f64(0.4)));
+        assert!(gaps.iter().any(|gap| *gap < interval.mul_f64(0.9)));
+        assert!(gaps.iter().any(|gap| *gap > interval.mul_f64(1.1)));
+    }
+
+    #[test]
+    pub fn trees_poll_on_their_own_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (fast, slow) = (root.join("fast"), root.join("slow"));
+        for path in [&fast, &slow] {
+            fs::create_dir(path).unwrap();
+        }
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(60)).unwrap();
+        let options = DirectoryAddOptions::new().poll_interval(Duration::from_millis(10));
+        watcher
+            .add_directory_with_options(&fast, true, options)
+            .unwrap();
+        watcher.add_directory(&slow, true).unwrap();
+        let handle = watcher.handle();
+        let poller = thread::spawn(move || watcher.start_polling());
+        // Past the first cycle, which polls both
+        while handle.metrics().poll_cycles == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        fs::create_dir(slow.join("new")).unwrap();
+        fs::create_dir(fast.join("new")).unwrap();
+        let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(event.path, fast.join("new"));
+        thread::sleep(Duration::from_millis(50));
+        handle.stop();
+        poller.join().unwrap().unwrap();
+        assert!(events.try_recv().is_err(), "the slow tree isn't due yet");
+        assert!(handle.metrics().poll_cycles > 2);
+    }
+
+    #[test]
+    pub fn reads_reported_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (reported, quiet) = (root.join("reported"), root.join("quiet"));
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        for (dir, report) in [(&reported, true), (&quiet, false)] {
+            fs::create_dir(dir).unwrap();
+            let file = dir.join("file");
+            fs::write(&file, b"content").unwrap();
+            // Old enough for relatime to update the atime on the next read
+            let past = TimeVal::seconds(1_600_000_000);
+            nix::sys::stat::utimes(&file, &past, &past).unwrap();
+            let options = DirectoryAddOptions::new().report_access(report);
+            watcher
+                .add_directory_with_options(dir, true, options)
+                .unwrap();
+        }
+
+        for dir in [&reported, &quiet] {
+            assert_eq!(fs::read(dir.join("file")).unwrap(), b"content");
+        }
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        let kinds = events
+            .try_iter()
+            .map(|event| (event.kind.name(), event.path))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![("accessed", reported.join("file"))]);
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn stop_ends_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(60)).unwrap();
+        watcher.add_directory(dir.path(), true).unwrap();
+        let handle = watcher.handle();
+        let polling = thread::spawn(move || watcher.start_polling());
+        handle.stop();
+        assert!(polling.join().unwrap().is_ok());
+        // The watcher is gone, along with the sending side
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    pub fn failed_poll_is_retried() {
+        let (mut watcher, _events) = WatcherBuilder::new()
+            .poll_retries(2, Duration::from_millis(1))
+            .build()
+            .unwrap();
+        let mut attempts = 0;
+        let res = watcher.retry_failed(|_| {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(io::Error::from(io::ErrorKind::Interrupted).into()),
+                _ => Ok(()),
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(attempts, 3);
+
+        attempts = 0;
+        let res = watcher.retry_failed(|_| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted).into())
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 3, "gives up after the retries");
+    }
+
+    #[test]
+    pub fn writes_to_fifos_are_reported() {
+        use nix::fcntl::{self, OFlag};
+        use nix::sys::stat::{self, Mode};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let fifo = root.join("pipe");
+        nix::unistd::mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().track_fifos(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+        let past = TimeVal::seconds(stat::stat(&fifo).unwrap().st_mtime - 10);
+        stat::utimes(&fifo, &past, &past).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        events.try_iter().for_each(drop);
+
+        // Opening read-write doesn't wait for a reader
+        let fd = fcntl::open(&fifo, OFlag::O_RDWR | OFlag::O_NONBLOCK, Mode::empty()).unwrap();
+        nix::unistd::write(fd, b"data").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        nix::unistd::close(fd).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .filter(|event| event.path == fifo)
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        assert!(matches!(kinds[..], [WatchEventKind::Written]));
+    }
+
+    #[test]
+    pub fn ignored_paths_never_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new()
+            .ignore(&["node_modules"])
+            .unwrap();
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        fs::create_dir_all(root.join("node_modules").join("pkg")).unwrap();
+        fs::write(root.join("node_modules").join("index.js"), b"").unwrap();
+        fs::write(root.join("main.js"), b"").unwrap();
+        watcher.poll().unwrap();
+
+        let created = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Create))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        assert_eq!(created, vec![root.join("main.js")]);
+    }
+
+    #[test]
+    pub fn unchanged_rewrites_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (same, changed) = (root.join("same"), root.join("changed"));
+        fs::write(&same, b"generated").unwrap();
+        fs::write(&changed, b"generated").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().skip_unchanged_writes(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(10));
+        fs::write(&same, b"generated").unwrap();
+        fs::write(&changed, b"generatee").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let written = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Written))
+            .map(|event| (event.path, event.content_hash))
+            .collect::<Vec<_>>();
+        let digest = *blake3::hash(b"generatee").as_bytes();
+        assert_eq!(written, vec![(changed, Some(digest))]);
+    }
+
+    #[test]
+    pub fn fifos_not_hashed() {
+        use nix::fcntl::{self, OFlag};
+        use nix::sys::stat::{self, Mode};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let fifo = root.join("pipe");
+        nix::unistd::mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new()
+            .track_fifos(true)
+            .skip_unchanged_writes(true);
+        // Opening the FIFO to hash it would block here
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+        let past = TimeVal::seconds(stat::stat(&fifo).unwrap().st_mtime - 10);
+        stat::utimes(&fifo, &past, &past).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        events.try_iter().for_each(drop);
+
+        let fd = fcntl::open(&fifo, OFlag::O_RDWR | OFlag::O_NONBLOCK, Mode::empty()).unwrap();
+        nix::unistd::write(fd, b"data").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        nix::unistd::close(fd).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .map(|event| event.kind)
+            .collect::<Vec<_>>();
+        assert!(matches!(kinds[..], [WatchEventKind::Written]));
+    }
+
+    #[test]
+    pub fn hidden_paths_never_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let options = DirectoryAddOptions::new().skip_hidden(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+
+        fs::create_dir_all(root.join(".cache").join("pkg")).unwrap();
+        fs::write(root.join(".swp"), b"").unwrap();
+        fs::write(root.join("notes.txt"), b"").unwrap();
+        watcher.poll().unwrap();
+
+        let created = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Create))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        assert_eq!(created, vec![root.join("notes.txt")]);
+    }
+
+    #[test]
+    pub fn inaccessible_directory_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let locked = root.join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("file"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let denied = DeniedAccess::new(&locked);
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        drop(denied);
+
+        let kinds = events
+            .try_iter()
+            .map(|event| (event.kind.name(), event.path))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![("access_lost", locked.clone())]);
+        // Its entries were kept all along
+        assert!(watcher.forest.contains(locked.join("file")));
+    }
+
+    #[test]
+    pub fn access_regained_reported() {
+        for report in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            let locked = root.join("locked");
+            fs::create_dir(&locked).unwrap();
+            fs::write(locked.join("file"), b"").unwrap();
+            let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+            let options = DirectoryAddOptions::new().report_access_regained(report);
+            watcher
+                .add_directory_with_options(&root, true, options)
+                .unwrap();
+
+            let denied = DeniedAccess::new(&locked);
+            watcher.poll_phases(CheckPhases::ALL).unwrap();
+            fs::write(locked.join("new"), b"").unwrap();
+            watcher.poll_phases(CheckPhases::ALL).unwrap();
+            drop(denied);
+            watcher.poll_phases(CheckPhases::ALL).unwrap();
+            // Only once
+            watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+            let kinds = events
+                .try_iter()
+                .map(|event| (event.kind.name(), event.path))
+                .collect::<Vec<_>>();
+            let mut expected = vec![("access_lost", locked.clone())];
+            if report {
+                expected.push(("access_regained", locked.clone()));
+            }
+            expected.push(("create", locked.join("new")));
+            assert_eq!(kinds, expected, "reported: {}", report);
+        }
+    }
 }
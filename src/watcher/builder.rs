@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use rayon::ThreadPoolBuilder;
+
+use crate::atomic::AtomicIdGenerator;
+use crate::error::TuxDriveResult;
+use crate::forest::PathForest;
+
+use super::debounce::Debouncer;
+use super::handle::WatcherHandle;
+use super::handler::EventHandler;
+use super::inotify::{InotifyWatches, WatcherBackend};
+use super::overflow::{EventSender, OverflowPolicy};
+use super::{WatchEvent, Watcher, MAX_NUM_THREADS};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configures and creates a `Watcher`.
+#[derive(Debug)]
+pub struct WatcherBuilder {
+    poll_interval: Duration,
+    num_threads: Option<usize>,
+    channel_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    metadata_interval: Option<Duration>,
+    content_interval: Option<Duration>,
+    probe_timeout: Option<Duration>,
+    skip_virtual_fs: bool,
+    source_id: Option<String>,
+    backend: WatcherBackend,
+    emit_initial_events: bool,
+    initial_batch: Option<(usize, Duration)>,
+    debounce: Option<Duration>,
+    rate_limit: Option<Duration>,
+    poll_retry: Option<(u32, Duration)>,
+    strict_tree_ordering: bool,
+    poll_jitter: f64,
+}
+
+impl Default for WatcherBuilder {
+    fn default() -> Self {
+        Self {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            num_threads: None,
+            channel_capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+            metadata_interval: None,
+            content_interval: None,
+            probe_timeout: None,
+            skip_virtual_fs: true,
+            source_id: hostname(),
+            backend: WatcherBackend::default(),
+            emit_initial_events: false,
+            initial_batch: None,
+            debounce: None,
+            rate_limit: None,
+            poll_retry: None,
+            strict_tree_ordering: false,
+            poll_jitter: 0.0,
+        }
+    }
+}
+
+impl WatcherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time between two poll cycles.
+    /// Default: 5 seconds
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Make each wait between two polls up to `fraction` of it longer or
+    /// shorter at random, e.g. 0.1 for 4.5 to 5.5 seconds between polls
+    /// every 5 seconds, so that several instances don't all hit shared
+    /// storage at the same time. Must be less than 1.
+    /// Default: 0
+    pub fn poll_jitter(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..1.0).contains(&fraction),
+            "poll jitter must be at least 0 and less than 1"
+        );
+        self.poll_jitter = fraction;
+        self
+    }
+
+    /// Threads polling the watched trees, each tree on one of them.
+    /// Default: one per CPU, at most 4
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "number of threads must not be 0");
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Bound the event channel to `cap` events.
+    /// Default: unbounded
+    pub fn channel_bounded(mut self, cap: usize) -> Self {
+        self.channel_capacity = Some(cap);
+        self
+    }
+
+    /// What to do when the bounded event channel is full.
+    /// Has no effect on an unbounded channel.
+    /// Default: `OverflowPolicy::Block`
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// How often to check for metadata changes (permissions, ownership).
+    /// Checks happen on poll cycles, so this is rounded up to the poll interval.
+    /// Default: every poll cycle
+    pub fn metadata_poll_interval(mut self, interval: Duration) -> Self {
+        self.metadata_interval = Some(interval);
+        self
+    }
+
+    /// How often to check for content changes (writes, new directory entries).
+    /// Checks happen on poll cycles, so this is rounded up to the poll interval.
+    /// Default: every poll cycle
+    pub fn content_poll_interval(mut self, interval: Duration) -> Self {
+        self.content_interval = Some(interval);
+        self
+    }
+
+    /// Access each directory before adding it, so that autofs mounts it
+    /// before it gets scanned. Adding fails if the directory doesn't respond
+    /// within `timeout`, rather than hanging on a dead mount.
+    /// Default: no probing
+    pub fn automount_probe(mut self, timeout: Duration) -> Self {
+        self.probe_timeout = Some(timeout);
+        self
+    }
+
+    /// Skip virtual filesystems (/proc, /sys, ...) found below watched
+    /// directories. Applies to all directories added to the watcher, except
+    /// those added with `DirectoryAddOptions::skip_virtual_fs` set.
+    /// Default: true
+    pub fn skip_virtual_filesystems(mut self, skip: bool) -> Self {
+        self.skip_virtual_fs = skip;
+        self
+    }
+
+    /// Tag put on every event, to tell apart events of several machines.
+    /// `None` leaves events untagged.
+    /// Default: the hostname
+    pub fn source_id(mut self, source_id: Option<String>) -> Self {
+        self.source_id = source_id;
+        self
+    }
+
+    /// How changes are found. With `WatcherBackend::Inotify` the poll
+    /// interval only paces the summary-only roots and the liveness updates.
+    /// Default: `WatcherBackend::Polling`
+    pub fn backend(mut self, backend: WatcherBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Send a `Create` for every path already there when a directory is
+    /// added, so that the event stream alone describes the whole tree.
+    /// Default: false
+    pub fn emit_initial_events(mut self, emit: bool) -> Self {
+        self.emit_initial_events = emit;
+        self
+    }
+
+    /// Send the initial events `batch_size` at a time, pausing for `pause`
+    /// in between, rather than flooding the channel on a large tree.
+    /// Default: all at once
+    pub fn initial_event_batches(mut self, batch_size: usize, pause: Duration) -> Self {
+        assert!(batch_size > 0, "batch size must not be 0");
+        self.initial_batch = Some((batch_size, pause));
+        self
+    }
+
+    /// Collapse modifications (`Written`, `Chmod`, `Chown`, `Truncated`) of
+    /// the same path within `window` into one event of each kind: the first is sent
+    /// right away, the rest once the window is over. Deletions are never
+    /// held back.
+    /// Default: no debouncing
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(window);
+        self
+    }
+
+    /// At most one modification (`Written`, `Chmod`, `Chown`, `Truncated`)
+    /// per path per `interval`. Unlike `debounce`, of those that came in the
+    /// meantime only the latest content change (`Written`, `Truncated`) and
+    /// the latest metadata change (`Chmod`, `Chown`) are kept, and sent once
+    /// the interval is over.
+    /// Takes the place of `debounce` if both are set.
+    /// Default: no rate limit
+    pub fn rate_limit(mut self, interval: Duration) -> Self {
+        self.rate_limit = Some(interval);
+        self
+    }
+
+    /// Retry a failed poll cycle up to `max_retries` times before
+    /// `Watcher::start_polling` gives up, waiting `initial_backoff` before
+    /// the first retry and twice as long before each further one.
+    /// Default: no retries
+    pub fn poll_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.poll_retry = Some((max_retries, initial_backoff));
+        self
+    }
+
+    /// Guarantee that the events of a poll cycle about a path come after
+    /// those about its parent directory, e.g. a `Create` (or `Moved`) of a
+    /// file after the `Create` of the directory it is in, which a mirror
+    /// needs to create the directory first. Trees are polled one after the
+    /// other, ordered by their root. Deletions carry no such guarantee.
+    /// Default: false
+    pub fn strict_tree_ordering(mut self, strict: bool) -> Self {
+        self.strict_tree_ordering = strict;
+        self
+    }
+
+    pub fn build(self) -> TuxDriveResult<(Watcher, Receiver<WatchEvent>)> {
+        self.create(None)
+    }
+
+    /// Like `build`, but the events are handed to `handler` right where
+    /// they are found, instead of going through a channel.
+    /// `channel_bounded` and `overflow_policy` have no effect then.
+    pub fn build_with_handler<H: EventHandler + 'static>(
+        self,
+        handler: H,
+    ) -> TuxDriveResult<Watcher> {
+        let (watcher, _) = self.create(Some(Box::new(handler)))?;
+        Ok(watcher)
+    }
+
+    fn create(
+        self,
+        handler: Option<Box<dyn EventHandler>>,
+    ) -> TuxDriveResult<(Watcher, Receiver<WatchEvent>)> {
+        let (tx, rx) = match self.channel_capacity {
+            Some(cap) => crossbeam::channel::bounded(cap),
+            None => crossbeam::channel::unbounded(),
+        };
+        let num_threads = self
+            .num_threads
+            .unwrap_or_else(|| crate::pool_size(MAX_NUM_THREADS));
+        let pool = ThreadPoolBuilder::new().num_threads(num_threads).build()?;
+        let poll_interval = self.poll_interval;
+        let inotify = match self.backend {
+            WatcherBackend::Polling => None,
+            WatcherBackend::Inotify => Some(InotifyWatches::new()?),
+        };
+        let handle = WatcherHandle::new();
+        let mut sender = EventSender::new(tx, self.overflow_policy, self.source_id, handle.clone());
+        if let Some(handler) = handler {
+            sender = sender.with_handler(handler);
+        }
+        let watcher = Watcher {
+            poll_interval,
+            forest: PathForest::new(),
+            sender,
+            pool,
+            id_gen: AtomicIdGenerator::new(),
+            metadata_interval: self.metadata_interval.unwrap_or(poll_interval),
+            content_interval: self.content_interval.unwrap_or(poll_interval),
+            last_metadata_check: None,
+            last_content_check: None,
+            next_polls: HashMap::new(),
+            probe_timeout: self.probe_timeout,
+            skip_virtual_fs: self.skip_virtual_fs,
+            handle,
+            summaries: HashMap::new(),
+            inotify,
+            emit_initial_events: self.emit_initial_events,
+            initial_batch: self.initial_batch,
+            debouncer: match (self.rate_limit, self.debounce) {
+                (Some(interval), _) => Some(Debouncer::rate_limit(interval)),
+                (None, window) => window.map(Debouncer::new),
+            },
+            poll_retry: self.poll_retry,
+            strict_tree_ordering: self.strict_tree_ordering,
+            root_configs: HashMap::new(),
+            poll_jitter: self.poll_jitter,
+            scheduled_poll: None,
+            rng: fastrand::Rng::new(),
+            state_file: None,
+        };
+        Ok((watcher, rx))
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let hostname = nix::unistd::gethostname(&mut buf).ok()?;
+    Some(hostname.to_string_lossy().into_owned())
+}
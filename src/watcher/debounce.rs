@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{PendingChange, WatchEventKind};
+
+/// Collapses bursts of modifications to the same path.
+/// The first modification goes out right away, later ones within the window
+/// are held back and sent once the window is over, at most one of each kind,
+/// or when rate limiting only the latest content change (`Written`,
+/// `Truncated`) and the latest metadata change (`Chmod`, `Chown`).
+#[derive(Debug)]
+pub(crate) struct Debouncer {
+    window: Duration,
+    latest_only: bool,
+    paths: Mutex<HashMap<PathBuf, DebouncedPath>>,
+}
+
+#[derive(Debug)]
+struct DebouncedPath {
+    last_sent: Instant,
+    /// Along with their raw flags, e.g. to keep an `IN_CLOSE_WRITE`
+    held_back: Vec<PendingChange>,
+    group: Option<Arc<str>>,
+}
+
+/// Held back events whose window is over
+pub(crate) type DueEvents = Vec<(PendingChange, Option<Arc<str>>)>;
+
+impl Debouncer {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            latest_only: false,
+            paths: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// At most one modification per path per `interval`, the latest one
+    pub(crate) fn rate_limit(interval: Duration) -> Self {
+        Self {
+            latest_only: true,
+            ..Self::new(interval)
+        }
+    }
+
+    /// Whether the event is to be sent now. If not, it was held back.
+    pub(crate) fn admit(
+        &self,
+        change: &PendingChange,
+        group: Option<&Arc<str>>,
+        now: Instant,
+    ) -> bool {
+        let (path, kind) = (change.path.as_path(), &change.kind);
+        let mut paths = self.paths.lock().unwrap();
+        match kind {
+            WatchEventKind::Written
+            | WatchEventKind::Chmod
+            | WatchEventKind::Chown
+            | WatchEventKind::Truncated => {}
+            // The earlier modifications don't matter any more
+            WatchEventKind::Delete => {
+                paths.remove(path);
+                return true;
+            }
+            WatchEventKind::Moved { from, .. } => {
+                paths.remove(from.as_path());
+                return true;
+            }
+            _ => return true,
+        }
+        match paths.get_mut(path) {
+            Some(debounced) if now.duration_since(debounced.last_sent) < self.window => {
+                if self.latest_only {
+                    // A metadata change never hides a change of the content
+                    let changes_content = |kind: &WatchEventKind| {
+                        matches!(kind, WatchEventKind::Written | WatchEventKind::Truncated)
+                    };
+                    debounced
+                        .held_back
+                        .retain(|held| changes_content(&held.kind) != changes_content(kind));
+                    debounced.held_back.push(change.clone());
+                    return false;
+                }
+                let discriminant = mem::discriminant(kind);
+                if !debounced
+                    .held_back
+                    .iter()
+                    .any(|held| mem::discriminant(&held.kind) == discriminant)
+                {
+                    debounced.held_back.push(change.clone());
+                }
+                false
+            }
+            _ => {
+                paths.insert(
+                    path.to_path_buf(),
+                    DebouncedPath {
+                        last_sent: now,
+                        held_back: Vec::new(),
+                        group: group.cloned(),
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Takes the held back events whose window is over by `now`,
+    /// or all of them with `all`.
+    pub(crate) fn take_due(&self, now: Instant, all: bool) -> DueEvents {
+        let mut paths = self.paths.lock().unwrap();
+        let mut due = Vec::new();
+        for debounced in paths.values_mut() {
+            let window_over = now.duration_since(debounced.last_sent) >= self.window;
+            if debounced.held_back.is_empty() || !(window_over || all) {
+                continue;
+            }
+            debounced.last_sent = now;
+            for change in debounced.held_back.drain(..) {
+                due.push((change, debounced.group.clone()));
+            }
+        }
+        // Nothing held back and quiet for a window: no need to remember
+        paths.retain(|_, debounced| {
+            !debounced.held_back.is_empty() || now.duration_since(debounced.last_sent) < self.window
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn change(path: &Path, kind: WatchEventKind) -> PendingChange {
+        PendingChange {
+            path: path.to_path_buf(),
+            kind,
+            file_id: None,
+            is_dir: false,
+            raw_flags: None,
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    pub fn bursts_collapse_per_path() {
+        let debouncer = Debouncer::new(Duration::from_millis(500));
+        let now = Instant::now();
+        let (file, other) = (Path::new("/a/file"), Path::new("/a/other"));
+        assert!(debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(!debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(!debouncer.admit(&change(file, WatchEventKind::Chmod), None, now));
+        assert!(!debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(debouncer.admit(&change(other, WatchEventKind::Written), None, now));
+        assert!(debouncer.take_due(now, false).is_empty());
+
+        let later = now + Duration::from_millis(500);
+        let due = debouncer.take_due(later, false);
+        let kinds = due
+            .iter()
+            .map(|(change, _)| (change.path.as_path(), format!("{:?}", change.kind)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![(file, "Written".to_string()), (file, "Chmod".to_string())]
+        );
+    }
+
+    #[test]
+    pub fn delete_is_never_held_back() {
+        let debouncer = Debouncer::new(Duration::from_millis(500));
+        let now = Instant::now();
+        let file = Path::new("/a/file");
+        assert!(debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(!debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(debouncer.admit(&change(file, WatchEventKind::Delete), None, now));
+        // The held back write went along with the file
+        assert!(debouncer.take_due(now, true).is_empty());
+    }
+
+    #[test]
+    pub fn rate_limit_keeps_latest() {
+        let limiter = Debouncer::rate_limit(Duration::from_millis(500));
+        let now = Instant::now();
+        let file = Path::new("/a/file");
+        assert!(limiter.admit(&change(file, WatchEventKind::Written), None, now));
+        for kind in [
+            WatchEventKind::Written,
+            WatchEventKind::Chmod,
+            WatchEventKind::Written,
+        ] {
+            assert!(!limiter.admit(&change(file, kind.clone()), None, now));
+        }
+
+        let later = now + Duration::from_millis(500);
+        let due = limiter.take_due(later, false);
+        let kinds = due
+            .iter()
+            .map(|(change, _)| change.kind.name())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["chmod", "written"]);
+        // The interval starts over with the held back event
+        assert!(!limiter.admit(&change(file, WatchEventKind::Chmod), None, later));
+    }
+
+    #[test]
+    pub fn rate_limit_keeps_write_before_chmod() {
+        let limiter = Debouncer::rate_limit(Duration::from_millis(500));
+        let now = Instant::now();
+        let file = Path::new("/a/file");
+        assert!(limiter.admit(&change(file, WatchEventKind::Chmod), None, now));
+        assert!(!limiter.admit(&change(file, WatchEventKind::Written), None, now));
+        assert!(!limiter.admit(&change(file, WatchEventKind::Chmod), None, now));
+
+        let kinds = limiter
+            .take_due(now, true)
+            .iter()
+            .map(|(change, _)| change.kind.name())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["written", "chmod"]);
+    }
+
+    #[test]
+    pub fn held_back_keeps_raw_flags() {
+        let debouncer = Debouncer::new(Duration::from_millis(500));
+        let now = Instant::now();
+        let file = Path::new("/a/file");
+        assert!(debouncer.admit(&change(file, WatchEventKind::Written), None, now));
+        let closed = PendingChange {
+            raw_flags: Some(0x8),
+            ..change(file, WatchEventKind::Written)
+        };
+        assert!(!debouncer.admit(&closed, None, now));
+
+        let due = debouncer.take_due(now, true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0.raw_flags, Some(0x8));
+    }
+}
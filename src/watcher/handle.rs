@@ -0,0 +1,248 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::PathConfig;
+use crate::error::TuxDriveResult;
+
+use super::metrics::{MetricCounters, WatcherMetrics};
+
+/// State of a `Watcher` that stays reachable once the watcher itself
+/// was moved to its polling thread.
+#[derive(Debug, Clone)]
+pub struct WatcherHandle {
+    state: Arc<SharedState>,
+}
+
+#[derive(Debug)]
+struct SharedState {
+    /// Unix time in milliseconds
+    last_poll_completed: AtomicU64,
+    /// Number of polls completed
+    cycle: AtomicU64,
+    /// Paths in the forest as of the last poll
+    watched_paths: AtomicU64,
+    counters: MetricCounters,
+    scanning: AtomicBool,
+    paused: AtomicBool,
+    /// Passed to `set_directories`, not applied yet
+    new_directories: Mutex<Option<(Vec<PathConfig>, DirectoriesApplied)>>,
+    stop_requested: Mutex<bool>,
+    stop_cond: Condvar,
+}
+
+/// Whether the watcher is in the middle of a poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanState {
+    Idle,
+    Scanning,
+}
+
+impl WatcherHandle {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::new(SharedState {
+                last_poll_completed: AtomicU64::new(now_millis()),
+                cycle: AtomicU64::new(0),
+                watched_paths: AtomicU64::new(0),
+                counters: MetricCounters::default(),
+                scanning: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+                new_directories: Mutex::new(None),
+                stop_requested: Mutex::new(false),
+                stop_cond: Condvar::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn poll_completed(&self) {
+        self.state
+            .last_poll_completed
+            .store(now_millis(), Ordering::SeqCst);
+        self.state.cycle.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_watched_paths(&self, watched_paths: usize) {
+        self.state
+            .watched_paths
+            .store(watched_paths as u64, Ordering::SeqCst);
+    }
+
+    pub(crate) fn counters(&self) -> &MetricCounters {
+        &self.state.counters
+    }
+
+    pub(crate) fn set_scanning(&self, scanning: bool) {
+        self.state.scanning.store(scanning, Ordering::SeqCst);
+    }
+
+    /// Events of a poll may still be coming in while `Scanning`.
+    pub fn scan_state(&self) -> ScanState {
+        if self.state.scanning.load(Ordering::SeqCst) {
+            ScanState::Scanning
+        } else {
+            ScanState::Idle
+        }
+    }
+
+    /// Number of polls completed so far.
+    /// Seeing the same cycle before and after an `Idle` state means no
+    /// poll happened in between.
+    pub fn cycle(&self) -> u64 {
+        self.state.cycle.load(Ordering::SeqCst)
+    }
+
+    /// Counters since the watcher was created.
+    /// Safe to call while the watcher is polling on another thread
+    pub fn metrics(&self) -> WatcherMetrics {
+        let counters = &self.state.counters;
+        WatcherMetrics {
+            events: counters.sent(),
+            events_dropped: counters.dropped(),
+            sends_blocked: counters.blocked(),
+            poll_cycles: self.cycle(),
+            poll_failures: counters.poll_failures(),
+            watched_paths: self.state.watched_paths.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Makes `Watcher::start_polling` stop polling (and sending events)
+    /// from the next cycle on, until `resume` is called. The poll in progress,
+    /// if any, still completes. `is_alive` goes false over a long pause.
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Polling starts over within a poll interval, with a full poll that
+    /// reports what changed while paused
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    /// Has `Watcher::start_polling` swap the watched directories for those
+    /// of `configs` before its next poll, as `Watcher::set_directories` does.
+    /// If that fails, the error is logged and the old ones are kept.
+    pub fn set_directories(&self, configs: Vec<PathConfig>) {
+        self.set_directories_then(configs, |_| {});
+    }
+
+    /// Same as `set_directories`, then calls `applied` on the polling thread
+    /// with the outcome, e.g. to start using the new config only once the
+    /// watcher does. If directories are set again before these were
+    /// applied, `applied` is never called.
+    pub fn set_directories_then<F>(&self, configs: Vec<PathConfig>, applied: F)
+    where
+        F: FnOnce(&TuxDriveResult<()>) + Send + 'static,
+    {
+        let applied = DirectoriesApplied(Box::new(applied));
+        *self.state.new_directories.lock().unwrap() = Some((configs, applied));
+    }
+
+    pub(crate) fn take_new_directories(&self) -> Option<(Vec<PathConfig>, DirectoriesApplied)> {
+        self.state.new_directories.lock().unwrap().take()
+    }
+
+    /// Makes `Watcher::start_polling` return once the current poll is done,
+    /// after sending the events still held back.
+    pub fn stop(&self) {
+        *self.state.stop_requested.lock().unwrap() = true;
+        self.state.stop_cond.notify_all();
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        *self.state.stop_requested.lock().unwrap()
+    }
+
+    /// Sleeps for `timeout`, waking up early on `stop`.
+    /// Returns whether a stop was requested.
+    pub(crate) fn sleep_unless_stopped(&self, timeout: Duration) -> bool {
+        let stop_requested = self.state.stop_requested.lock().unwrap();
+        let (stop_requested, _) = self
+            .state
+            .stop_cond
+            .wait_timeout_while(stop_requested, timeout, |stop_requested| !*stop_requested)
+            .unwrap();
+        *stop_requested
+    }
+
+    /// Whether a poll completed within the last `max_staleness`.
+    /// Counts from the creation of the watcher until the first poll.
+    /// Pick `max_staleness` well above the poll interval.
+    pub fn is_alive(&self, max_staleness: Duration) -> bool {
+        let last = self.state.last_poll_completed.load(Ordering::SeqCst);
+        let staleness = Duration::from_millis(now_millis().saturating_sub(last));
+        staleness <= max_staleness
+    }
+}
+
+/// Called once directories passed to `WatcherHandle::set_directories_then`
+/// were applied, or failed to
+pub(crate) struct DirectoriesApplied(Box<AppliedFn>);
+
+type AppliedFn = dyn FnOnce(&TuxDriveResult<()>) + Send;
+
+impl DirectoriesApplied {
+    pub(crate) fn call(self, res: &TuxDriveResult<()>) {
+        (self.0)(res)
+    }
+}
+
+impl fmt::Debug for DirectoriesApplied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DirectoriesApplied")
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn stale_handle_is_not_alive() {
+        let handle = WatcherHandle::new();
+        assert!(handle.is_alive(Duration::from_secs(60)));
+        handle
+            .state
+            .last_poll_completed
+            .store(now_millis() - 120_000, Ordering::SeqCst);
+        assert!(!handle.is_alive(Duration::from_secs(60)));
+        handle.poll_completed();
+        assert!(handle.is_alive(Duration::from_secs(60)));
+    }
+
+    #[test]
+    pub fn stop_wakes_up_sleep() {
+        let handle = WatcherHandle::new();
+        assert!(!handle.sleep_unless_stopped(Duration::from_millis(1)));
+        let stopper = handle.clone();
+        let thread = std::thread::spawn(move || stopper.stop());
+        assert!(handle.sleep_unless_stopped(Duration::from_secs(60)));
+        assert!(handle.is_stop_requested());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    pub fn scan_state_and_cycle() {
+        let handle = WatcherHandle::new();
+        assert_eq!(handle.scan_state(), ScanState::Idle);
+        assert_eq!(handle.cycle(), 0);
+        handle.set_scanning(true);
+        assert_eq!(handle.scan_state(), ScanState::Scanning);
+        handle.poll_completed();
+        handle.set_scanning(false);
+        assert_eq!(handle.scan_state(), ScanState::Idle);
+        assert_eq!(handle.cycle(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use crossbeam::channel::Sender;
+
+use crate::error::{TuxDriveError, TuxDriveResult};
+
+use super::WatchEvent;
+
+/// Receives the events of a watcher built with
+/// `WatcherBuilder::build_with_handler`, as soon as they are found, on the
+/// polling threads. Polling waits for `on_event` to return.
+pub trait EventHandler: Send {
+    /// An error stops the watcher: `ChannelClosed` makes `start_polling`
+    /// return without an error, any other is returned by it.
+    fn on_event(&mut self, event: &WatchEvent) -> TuxDriveResult<()>;
+}
+
+/// Forwards the events to a channel. The watcher stops once the receiver
+/// is dropped.
+impl EventHandler for Sender<WatchEvent> {
+    fn on_event(&mut self, event: &WatchEvent) -> TuxDriveResult<()> {
+        self.send(event.clone())
+            .map_err(|_| TuxDriveError::ChannelClosed)
+    }
+}
+
+/// The handler of a watcher, called by one polling thread at a time
+pub(crate) struct SharedHandler(Mutex<Box<dyn EventHandler>>);
+
+impl SharedHandler {
+    pub(crate) fn new(handler: Box<dyn EventHandler>) -> Self {
+        Self(Mutex::new(handler))
+    }
+
+    pub(crate) fn on_event(&self, event: &WatchEvent) -> TuxDriveResult<()> {
+        self.0.lock().unwrap().on_event(event)
+    }
+}
+
+impl fmt::Debug for SharedHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedHandler")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::super::{CheckPhases, WatcherBuilder};
+    use super::*;
+
+    /// Keeps the path and kind of the events
+    struct Recorder(Arc<Mutex<Vec<(&'static str, PathBuf)>>>);
+
+    impl EventHandler for Recorder {
+        fn on_event(&mut self, event: &WatchEvent) -> TuxDriveResult<()> {
+            let mut events = self.0.lock().unwrap();
+            events.push((event.kind.name(), event.path.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn handler_gets_events_without_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("old"), b"").unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut watcher = WatcherBuilder::new()
+            .build_with_handler(Recorder(Arc::clone(&events)))
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::write(root.join("new"), b"").unwrap();
+        fs::remove_file(root.join("old")).unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(
+            events,
+            vec![("create", root.join("new")), ("delete", root.join("old"))]
+        );
+    }
+
+    #[test]
+    pub fn dropped_receiver_stops_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (send, events) = crossbeam::channel::unbounded();
+        let mut watcher = WatcherBuilder::new()
+            .poll_interval(Duration::from_millis(10))
+            .build_with_handler(send)
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        drop(events);
+        fs::write(root.join("new"), b"").unwrap();
+        // Returns on its own, nothing asked it to stop
+        watcher.start_polling().unwrap();
+    }
+}
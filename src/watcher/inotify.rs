@@ -0,0 +1,796 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+
+use crate::display_path;
+use crate::error::{TuxDriveError, TuxDriveResult};
+use crate::forest::{depth_below, PathTree};
+
+use super::{
+    new_entry_metadata, CheckPhases, ModTimeInfo, PathAction, SendInfo, WatchEventKind, Watcher,
+};
+
+/// How the watcher finds out about changes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WatcherBackend {
+    /// Rescan the watched trees every poll interval.
+    /// Works on any filesystem.
+    #[default]
+    Polling,
+    /// Let the kernel report changes through inotify (Linux only).
+    /// Changes made from other machines on network filesystems go unnoticed.
+    Inotify,
+}
+
+/// An inotify instance along with the directory (or single file) each watch is on
+pub(crate) struct InotifyWatches {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl InotifyWatches {
+    pub(crate) fn new() -> TuxDriveResult<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        Ok(Self {
+            inotify,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Directories that vanished in the meantime are skipped.
+    pub(crate) fn watch_dir(&mut self, dir: &Path) -> TuxDriveResult<()> {
+        let flags = AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_DELETE_SELF
+            | AddWatchFlags::IN_ONLYDIR;
+        match self.inotify.add_watch(dir, flags) {
+            Ok(wd) => {
+                self.dirs.insert(wd, dir.to_path_buf());
+                Ok(())
+            }
+            Err(Errno::ENOENT | Errno::ENOTDIR | Errno::EACCES) => Ok(()),
+            Err(err) => Err(TuxDriveError::nix_at(dir, err)),
+        }
+    }
+
+    /// Watches a single file rather than a directory's entries.
+    /// It is gone as well once moved away.
+    pub(crate) fn watch_file(&mut self, file: &Path) -> TuxDriveResult<()> {
+        let flags = AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_DELETE_SELF
+            | AddWatchFlags::IN_MOVE_SELF;
+        match self.inotify.add_watch(file, flags) {
+            Ok(wd) => {
+                self.dirs.insert(wd, file.to_path_buf());
+                Ok(())
+            }
+            Err(Errno::ENOENT | Errno::EACCES) => Ok(()),
+            Err(err) => Err(TuxDriveError::nix_at(file, err)),
+        }
+    }
+
+    /// Drops the watches on `dir` and everything below it
+    pub(crate) fn unwatch_below(&mut self, dir: &Path) {
+        let wds = self
+            .dirs
+            .iter()
+            .filter(|(_, watched)| watched.starts_with(dir))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+        for wd in wds {
+            // Fails if the kernel already dropped the watch along with the directory
+            let _ = self.inotify.rm_watch(wd);
+            self.dirs.remove(&wd);
+        }
+    }
+
+    /// Keeps track of the watches on `from` and everything below it, now at `to`.
+    /// The kernel watches the directories themselves, whatever they are called.
+    pub(crate) fn rename_below(&mut self, from: &Path, to: &Path) {
+        for watched in self.dirs.values_mut() {
+            if let Ok(rest) = watched.strip_prefix(from) {
+                *watched = to.join(rest);
+            }
+        }
+    }
+
+    /// Drops the events queued so far, for when a full poll finds the same changes
+    pub(crate) fn discard_queued(&mut self) -> TuxDriveResult<()> {
+        loop {
+            let events = self.wait(Duration::ZERO)?;
+            if events.is_empty() {
+                return Ok(());
+            }
+            for event in events {
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    self.dirs.remove(&event.wd);
+                }
+            }
+        }
+    }
+
+    /// The path `event` is about, if its watch is still known
+    fn event_path(&self, event: &InotifyEvent) -> Option<PathBuf> {
+        let dir = self.dirs.get(&event.wd)?;
+        Some(match &event.name {
+            Some(name) => dir.join(name),
+            None => dir.clone(),
+        })
+    }
+
+    /// Waits up to `timeout` for events, returning all that are queued.
+    fn wait(&mut self, timeout: Duration) -> TuxDriveResult<Vec<InotifyEvent>> {
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll::poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(Vec::new()),
+            Ok(_) => {}
+            Err(err) => return Err(err.into()),
+        }
+        match self.inotify.read_events() {
+            Ok(events) => Ok(events),
+            Err(Errno::EAGAIN) => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for InotifyWatches {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.inotify.as_raw_fd());
+    }
+}
+
+impl Watcher {
+    /// Adds a watch on every directory in the forest, and on the files
+    /// watched on their own.
+    /// Watching an already watched directory again is harmless.
+    pub(super) fn watch_forest(&mut self) -> TuxDriveResult<()> {
+        let watches = match self.inotify.as_mut() {
+            Some(watches) => watches,
+            None => return Ok(()),
+        };
+        for tree in self.forest.trees_mut() {
+            if !tree.root_is_dir() {
+                watches.watch_file(&tree.root_path())?;
+                continue;
+            }
+            for dir in tree.dir_paths() {
+                watches.watch_dir(&dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the inotify events arriving within `timeout`.
+    /// The counterpart of a poll cycle for the inotify backend.
+    pub(super) fn inotify_step(&mut self, timeout: Duration) -> TuxDriveResult<()> {
+        let events = match self.inotify.as_mut() {
+            Some(watches) => watches.wait(timeout)?,
+            None => return self.poll(),
+        };
+        self.handle.set_scanning(true);
+        let res = self.handle_inotify_events(events);
+        self.handle.set_scanning(false);
+        res
+    }
+
+    fn handle_inotify_events(&mut self, events: Vec<InotifyEvent>) -> TuxDriveResult<()> {
+        if events
+            .iter()
+            .any(|event| event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW))
+        {
+            log::warn!("inotify queue overflowed, rescanning all watched directories");
+            self.poll_phases(CheckPhases::ALL)?;
+            return self.watch_forest();
+        }
+        // A file closed in this batch is reported once, by its close
+        let closed = match self.inotify.as_ref() {
+            Some(watches) => events
+                .iter()
+                .filter(|event| event.mask.contains(AddWatchFlags::IN_CLOSE_WRITE))
+                .filter_map(|event| watches.event_path(event))
+                .collect(),
+            None => HashSet::new(),
+        };
+        // The two halves of a rename share a cookie, the new name comes second
+        let moved_to = match self.inotify.as_ref() {
+            Some(watches) => events
+                .iter()
+                .filter(|event| event.mask.contains(AddWatchFlags::IN_MOVED_TO))
+                .filter_map(|event| Some((event.cookie, (watches.event_path(event)?, event.mask))))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let mut moves_done = HashSet::new();
+        // A single write often comes as several IN_MODIFY
+        let mut written = HashSet::new();
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_MOVED_TO) && moves_done.contains(&event.cookie)
+            {
+                continue;
+            }
+            if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                let from = self
+                    .inotify
+                    .as_ref()
+                    .and_then(|watches| watches.event_path(&event));
+                if let (Some(from), Some((to, to_mask))) = (from, moved_to.get(&event.cookie)) {
+                    if self.handle_inotify_move(&from, to, *to_mask, &mut written)? {
+                        moves_done.insert(event.cookie);
+                        continue;
+                    }
+                }
+            }
+            self.handle_inotify_event(event, &closed, &mut written)?;
+        }
+        if self.due_phases(Instant::now()).content {
+            self.poll_summaries()?;
+        }
+        self.send_debounced(false)?;
+        self.sender.send_held_back();
+        self.poll_completed();
+        Ok(())
+    }
+
+    /// Moves `from` to `to` in its tree, keeping everything below it, and
+    /// reports a single `Moved`. Returns false, having done nothing, if it
+    /// can't be told as a move: `to` is in another tree or not tracked there.
+    /// The two halves are then handled as a deletion and a creation.
+    fn handle_inotify_move(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        to_mask: AddWatchFlags,
+        written: &mut HashSet<PathBuf>,
+    ) -> TuxDriveResult<bool> {
+        let from_root = self
+            .forest
+            .tree_containing_mut(from)
+            .map(|tree| tree.root_path());
+        let to_root = self
+            .forest
+            .tree_containing_mut(to)
+            .map(|tree| tree.root_path());
+        let (watches, tree) = match (self.inotify.as_mut(), from_root) {
+            (Some(watches), Some(root_path)) if to_root.as_ref() == Some(&root_path) => {
+                (watches, self.forest.tree_containing_mut(from).unwrap())
+            }
+            _ => return Ok(false),
+        };
+        let is_dir = match tree.node_info(from) {
+            Some((_, is_dir)) => is_dir,
+            None => return Ok(false),
+        };
+        if !tracks_moved(tree, from, to, is_dir) || !tree.move_path(from, to) {
+            return Ok(false);
+        }
+        watches.rename_below(from, to);
+        written.remove(from);
+        let group = tree.options().group_name().cloned();
+        let send_info = SendInfo {
+            sender: &self.sender,
+            id_gen: &self.id_gen,
+            debouncer: self.debouncer.as_ref(),
+            group: group.as_ref(),
+            changes: None,
+        };
+        let kind = WatchEventKind::Moved {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        };
+        send_info.send_event_with_flags(to, kind, is_dir, Some(to_mask.bits()))?;
+        Ok(true)
+    }
+
+    fn handle_inotify_event(
+        &mut self,
+        event: InotifyEvent,
+        closed: &HashSet<PathBuf>,
+        written: &mut HashSet<PathBuf>,
+    ) -> TuxDriveResult<()> {
+        let watches = match self.inotify.as_mut() {
+            Some(watches) => watches,
+            None => return Ok(()),
+        };
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            watches.dirs.remove(&event.wd);
+            return Ok(());
+        }
+        let path = match watches.event_path(&event) {
+            Some(path) => path,
+            // Left over from a watch that was dropped
+            None => return Ok(()),
+        };
+        let tree = match self.forest.tree_containing_mut(&path) {
+            Some(tree) => tree,
+            None => return Ok(()),
+        };
+        if path.file_name().is_some_and(|name| name == ".gitignore") {
+            if let Some(dir) = path.parent() {
+                tree.options().load_gitignore(dir);
+            }
+        }
+        let group = tree.options().group_name().cloned();
+        let send_info = SendInfo {
+            sender: &self.sender,
+            id_gen: &self.id_gen,
+            debouncer: self.debouncer.as_ref(),
+            group: group.as_ref(),
+            changes: None,
+        };
+        let mask = event.mask;
+        let raw_flags = Some(mask.bits());
+        if mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO) {
+            handle_created(tree, watches, &send_info, &path, raw_flags)?;
+        } else if mask.intersects(
+            AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_MOVED_FROM
+                | AddWatchFlags::IN_DELETE_SELF
+                | AddWatchFlags::IN_MOVE_SELF,
+        ) {
+            let is_dir = match tree.node_info(&path) {
+                Some((_, is_dir)) => is_dir,
+                None => return Ok(()),
+            };
+            let root_path = tree.root_path();
+            if root_path == path && !is_dir && path.is_file() {
+                // Replaced by a rename over it, the way editors save: the watch
+                // moves on to the new file
+                if let Some((info, _)) = tree.node_info_mut(&path) {
+                    info.update_times(&path, CheckPhases::ALL)?;
+                }
+                watches.unwatch_below(&path);
+                watches.watch_file(&path)?;
+                if written.insert(path.clone()) {
+                    send_info.send_event_with_flags(
+                        &path,
+                        WatchEventKind::Written,
+                        false,
+                        raw_flags,
+                    )?;
+                }
+                return Ok(());
+            }
+            if root_path == path {
+                // Removes the whole tree
+                self.forest.remove_path(&root_path, &path);
+            } else {
+                tree.remove_path(&path);
+            }
+            watches.unwatch_below(&path);
+            written.remove(&path);
+            send_info.send_event_with_flags(&path, WatchEventKind::Delete, is_dir, raw_flags)?;
+        } else if mask.contains(AddWatchFlags::IN_CLOSE_WRITE) {
+            // The writer is done, whether or not its writes were reported by
+            // an earlier batch
+            if let Some((info, false)) = tree.node_info_mut(&path) {
+                let action = info.update_times(&path, CheckPhases::ALL)?;
+                if matches!(action, PathAction::Nothing) && written.insert(path.clone()) {
+                    send_info.send_event_with_flags(
+                        &path,
+                        WatchEventKind::Written,
+                        false,
+                        raw_flags,
+                    )?;
+                }
+            }
+        } else if mask.intersects(AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_ATTRIB) {
+            let info = match tree.node_info_mut(&path) {
+                Some((info, false)) => info,
+                // Directories only report entries coming and going
+                _ => return Ok(()),
+            };
+            let old_info = *info;
+            match info.update_times(&path, CheckPhases::ALL)? {
+                PathAction::Nothing => {}
+                // The removal follows as an event of its own
+                PathAction::Delete | PathAction::AccessLost => return Ok(()),
+            }
+            if mask.contains(AddWatchFlags::IN_MODIFY) && info.truncated_since(&old_info) {
+                send_info.send_event_with_flags(
+                    &path,
+                    WatchEventKind::Truncated,
+                    false,
+                    raw_flags,
+                )?;
+            } else if mask.contains(AddWatchFlags::IN_MODIFY) {
+                if !closed.contains(&path) && written.insert(path.clone()) {
+                    send_info.send_event_with_flags(
+                        &path,
+                        WatchEventKind::Written,
+                        false,
+                        raw_flags,
+                    )?;
+                }
+            } else {
+                info.send_changed(&old_info, &path, &send_info)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the tree's options keep tracking what was at `from` once moved
+/// to `to`, as they would if it had been created there.
+/// A directory moved up or down with a depth limit is left to be added
+/// again, so that the limit applies to what it contains.
+fn tracks_moved(tree: &PathTree<ModTimeInfo>, from: &Path, to: &Path, is_dir: bool) -> bool {
+    let root_path = tree.root_path();
+    let options = tree.options();
+    let depth = depth_below(&root_path, to);
+    if from == root_path || !options.allows_depth(depth) {
+        return false;
+    }
+    if is_dir && depth != depth_below(&root_path, from) && !options.allows_depth(usize::MAX) {
+        return false;
+    }
+    if options.is_ignored(&root_path, to, is_dir) {
+        return false;
+    }
+    if is_dir {
+        !options.skips_dir(to)
+    } else {
+        options.tracks_file(to)
+    }
+}
+
+/// Adds a path that appeared in a watched directory, subject to the tree's options.
+/// A new directory gets watched, and whatever it already contains is added too:
+/// it may have been moved in, or filled before the watch was in place.
+/// `raw_flags` are those of the inotify event, only set for `path` itself.
+fn handle_created(
+    tree: &mut PathTree<ModTimeInfo>,
+    watches: &mut InotifyWatches,
+    send_info: &SendInfo<'_>,
+    path: &Path,
+    raw_flags: Option<u32>,
+) -> TuxDriveResult<()> {
+    if tree.node_info_mut(path).is_some() {
+        return Ok(());
+    }
+    if !tree
+        .options()
+        .allows_depth(depth_below(&tree.root_path(), path))
+    {
+        return Ok(());
+    }
+    match path.parent().map(|parent| tree.node_info_mut(parent)) {
+        Some(Some((_, true))) => {}
+        _ => return Ok(()),
+    }
+    let options = tree.options();
+    let metadata = match new_entry_metadata(path, &tree.root_path(), options) {
+        Some(metadata) => metadata,
+        // Gone again, not for us to see, or not tracked
+        None => return Ok(()),
+    };
+    let is_dir = metadata.is_dir();
+    if options.is_ignored(&tree.root_path(), path, is_dir) {
+        return Ok(());
+    }
+    if is_dir && options.skips_dir(path) || !is_dir && !options.tracks_file(path) {
+        return Ok(());
+    }
+    let mut info = ModTimeInfo::default();
+    match info.update_times(path, CheckPhases::ALL)? {
+        PathAction::Nothing => {}
+        PathAction::Delete | PathAction::AccessLost => return Ok(()),
+    }
+    tree.add_path(path, info, is_dir);
+    send_info.send_event_with_flags(path, WatchEventKind::Create, is_dir, raw_flags)?;
+    if !is_dir {
+        return Ok(());
+    }
+    watches.watch_dir(path)?;
+    tree.options().load_gitignore(path);
+    let entries = match path.read_dir() {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::debug!(
+                "Could not list new directory {}: {}",
+                display_path(path),
+                err
+            );
+            return Ok(());
+        }
+    };
+    for entry in entries {
+        match entry {
+            Ok(entry) => handle_created(tree, watches, send_info, &entry.path(), None)?,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied
+                ) => {}
+            Err(err) => return Err(TuxDriveError::io_at(path, err)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::WatcherBuilder;
+    use super::*;
+
+    #[test]
+    pub fn inotify_reports_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("old"), b"").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::write(root.join("file"), b"content").unwrap();
+        fs::set_permissions(root.join("old"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("inner"), b"").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        fs::remove_file(root.join("old")).unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+
+        let events = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        for expected in [
+            ("Create", root.join("file")),
+            ("Written", root.join("file")),
+            ("Chmod", root.join("old")),
+            ("Create", root.join("sub")),
+            ("Create", root.join("sub").join("inner")),
+            ("Delete", root.join("old")),
+        ] {
+            let expected = (expected.0.to_string(), expected.1);
+            assert!(events.contains(&expected), "missing {:?}", expected);
+        }
+        assert_eq!(watcher.forest.len(), 1);
+        assert_eq!(
+            watcher.inotify.as_ref().unwrap().dirs.len(),
+            2,
+            "the root and sub should be watched"
+        );
+    }
+
+    #[test]
+    pub fn close_after_write_is_flagged() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let mut file = fs::File::options().append(true).open(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.kind, WatchEventKind::Written));
+        let flags = AddWatchFlags::from_bits_truncate(event.raw_flags.unwrap());
+        assert!(flags.contains(AddWatchFlags::IN_MODIFY));
+
+        drop(file);
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.kind, WatchEventKind::Written));
+        let flags = AddWatchFlags::from_bits_truncate(event.raw_flags.unwrap());
+        assert!(flags.contains(AddWatchFlags::IN_CLOSE_WRITE));
+    }
+
+    #[test]
+    pub fn close_write_comes_once_per_writer() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        let mut file = fs::File::options().append(true).open(&path).unwrap();
+        for chunk in [b"first ", b"second"] {
+            file.write_all(chunk).unwrap();
+            watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        }
+        let events_while_open = events.try_iter().collect::<Vec<_>>();
+        assert!(!events_while_open.is_empty());
+        assert!(!events_while_open.iter().any(|event| event.is_close_write()));
+
+        drop(file);
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let closed = events
+            .try_iter()
+            .filter(|event| event.is_close_write())
+            .collect::<Vec<_>>();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].path, path);
+        assert_eq!(fs::read(&path).unwrap(), b"first second");
+    }
+
+    #[test]
+    pub fn write_and_close_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::write(&path, b"content").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(matches!(events[0].kind, WatchEventKind::Written));
+        assert!(events[0].is_close_write());
+    }
+
+    #[test]
+    pub fn inotify_watches_single_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_file(&path).unwrap();
+
+        fs::write(&path, b"content").unwrap();
+        fs::write(root.join("sibling"), b"").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        fs::remove_file(&path).unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+
+        let kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Written".to_string(), path.clone()),
+                ("Delete".to_string(), path)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn single_file_replaced_by_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("file");
+        fs::write(&path, b"old").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_file(&path).unwrap();
+
+        fs::write(root.join("file.tmp"), b"new").unwrap();
+        fs::rename(root.join("file.tmp"), &path).unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let kinds = events
+            .try_iter()
+            .map(|event| format!("{:?}", event.kind))
+            .collect::<Vec<_>>();
+        assert!(kinds.contains(&"Written".to_string()), "{:?}", kinds);
+        assert!(!kinds.contains(&"Delete".to_string()), "{:?}", kinds);
+        assert_eq!(watcher.forest.len(), 1);
+
+        // Still watched after the replacement
+        fs::write(&path, b"newer").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        assert!(events
+            .try_iter()
+            .any(|event| matches!(event.kind, WatchEventKind::Written)));
+    }
+
+    #[test]
+    pub fn renames_reported_as_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a"), b"a").unwrap();
+        fs::write(root.join("b"), b"b").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::rename(root.join("a"), root.join("renamed")).unwrap();
+        fs::rename(root.join("b"), root.join("sub").join("b")).unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+
+        let events = events
+            .try_iter()
+            .map(|event| match event.kind {
+                WatchEventKind::Moved { from, to } => {
+                    assert_eq!(event.path, to);
+                    ("moved", from, to)
+                }
+                kind => (kind.name(), event.path, PathBuf::new()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ("moved", root.join("a"), root.join("renamed")),
+                ("moved", root.join("b"), root.join("sub").join("b")),
+            ]
+        );
+        assert!(!watcher.forest.contains(root.join("a")));
+        assert!(watcher.forest.contains(root.join("sub").join("b")));
+    }
+
+    #[test]
+    pub fn renamed_directory_stays_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (old, new) = (root.join("old"), root.join("new"));
+        fs::create_dir_all(old.join("sub")).unwrap();
+        fs::write(old.join("sub").join("nested"), b"nested").unwrap();
+        let (mut watcher, events) = WatcherBuilder::new()
+            .backend(WatcherBackend::Inotify)
+            .build()
+            .unwrap();
+        watcher.add_directory(&root, true).unwrap();
+
+        fs::rename(&old, &new).unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let moves = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(moves.len(), 1, "{:?}", moves);
+        assert!(moves[0].is_dir);
+        assert!(matches!(
+            &moves[0].kind,
+            WatchEventKind::Moved { from, to } if *from == old && *to == new
+        ));
+        assert!(!watcher.forest.contains(&old));
+        assert!(watcher.forest.contains(new.join("sub").join("nested")));
+
+        // Events below the directory now carry its new path
+        let nested = new.join("sub").join("nested");
+        fs::write(&nested, b"changed").unwrap();
+        watcher.inotify_step(Duration::from_millis(50)).unwrap();
+        let written = events
+            .try_iter()
+            .filter(|event| matches!(event.kind, WatchEventKind::Written))
+            .map(|event| event.path)
+            .collect::<Vec<_>>();
+        assert_eq!(written, vec![nested]);
+    }
+}
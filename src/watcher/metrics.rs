@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::WatchEventKind;
+
+/// Counters of a watcher, from `Watcher::metrics` or `WatcherHandle::metrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatcherMetrics {
+    /// Events sent, by kind name (see `WatchEventKind::name`).
+    /// Includes those dropped later on.
+    pub events: BTreeMap<&'static str, u64>,
+    /// Events dropped because the bounded event channel was full
+    pub events_dropped: u64,
+    /// Sends that had to wait for room in the full event channel
+    pub sends_blocked: u64,
+    pub poll_cycles: u64,
+    /// Failed poll cycles, retried or not
+    pub poll_failures: u64,
+    /// Paths in the watched trees as of the last poll, roots included.
+    /// Summary-only roots are left out.
+    pub watched_paths: u64,
+}
+
+impl WatcherMetrics {
+    /// Formats the counters in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        write_header(
+            &mut out,
+            "tuxdrive_events_total",
+            "Events sent, by kind.",
+            "counter",
+        );
+        for (kind, count) in &self.events {
+            writeln!(out, "tuxdrive_events_total{{kind=\"{}\"}} {}", kind, count).unwrap();
+        }
+        for (name, help, value) in [
+            (
+                "tuxdrive_events_dropped_total",
+                "Events dropped because the event channel was full.",
+                self.events_dropped,
+            ),
+            (
+                "tuxdrive_event_sends_blocked_total",
+                "Sends that waited for room in the event channel.",
+                self.sends_blocked,
+            ),
+            (
+                "tuxdrive_poll_cycles_total",
+                "Completed poll cycles.",
+                self.poll_cycles,
+            ),
+            (
+                "tuxdrive_poll_failures_total",
+                "Failed poll cycles.",
+                self.poll_failures,
+            ),
+        ] {
+            write_header(&mut out, name, help, "counter");
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        let name = "tuxdrive_watched_paths";
+        write_header(
+            &mut out,
+            name,
+            "Paths watched as of the last poll.",
+            "gauge",
+        );
+        writeln!(out, "{} {}", name, self.watched_paths).unwrap();
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// The counters behind `WatcherMetrics`, shared through the handle
+#[derive(Debug, Default)]
+pub(crate) struct MetricCounters {
+    sent: Mutex<BTreeMap<&'static str, u64>>,
+    dropped: AtomicU64,
+    blocked: AtomicU64,
+    poll_failures: AtomicU64,
+}
+
+impl MetricCounters {
+    pub(crate) fn count_sent(&self, kind: &WatchEventKind) {
+        *self.sent.lock().unwrap().entry(kind.name()).or_default() += 1;
+    }
+
+    pub(crate) fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_blocked(&self) {
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_poll_failure(&self) {
+        self.poll_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self) -> BTreeMap<&'static str, u64> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn poll_failures(&self) -> u64 {
+        self.poll_failures.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::super::{CheckPhases, Watcher};
+
+    #[test]
+    pub fn prometheus_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, _events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        fs::write(root.join("a"), b"").unwrap();
+        fs::write(root.join("b"), b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+
+        let metrics = watcher.metrics();
+        assert_eq!(metrics.events.get("create"), Some(&2));
+        assert_eq!(metrics.poll_cycles, 1);
+        let text = watcher.metrics_prometheus();
+        assert!(text.contains("# TYPE tuxdrive_events_total counter\n"));
+        assert!(text.contains("\ntuxdrive_events_total{kind=\"create\"} 2\n"));
+        assert!(text.contains("\ntuxdrive_poll_cycles_total 1\n"));
+        assert!(text.contains("\ntuxdrive_events_dropped_total 0\n"));
+        assert!(text.contains("# TYPE tuxdrive_watched_paths gauge\ntuxdrive_watched_paths 3\n"));
+        assert_eq!(watcher.handle().metrics(), metrics);
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam::channel::{SendTimeoutError, Sender, TrySendError};
+
+use crate::error::{TuxDriveError, TuxDriveResult};
+
+use super::handle::WatcherHandle;
+use super::handler::{EventHandler, SharedHandler};
+use super::socket::SocketClients;
+use super::{WatchEvent, WatchEventKind};
+
+/// How long a blocked send waits before warning about it (again)
+const BLOCKED_WARN_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a blocked send checks whether the watcher is asked to stop
+const BLOCKED_STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// What to do with an event when the (bounded) event channel is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the consumer to make room. Nothing is lost, but polling stalls.
+    #[default]
+    Block,
+    /// Keep the latest events: once the channel is full, new events wait
+    /// in a queue as long as the channel, whose oldest event is dropped to
+    /// make room for a new one.
+    DropOldest,
+    /// Drop the new event.
+    DropNewest,
+    /// Hold back events per path and send them once there is room.
+    /// Only the latest event for a path is kept, except that a held back
+    /// `Create` stays a `Create`.
+    /// Directories added in the same group share one window of held back
+    /// events, which doesn't hold back events of other groups.
+    Coalesce,
+}
+
+/// Held back events of one group, by path
+type PendingEvents = HashMap<PathBuf, WatchEvent>;
+
+/// Sending half of the event channel, applying the overflow policy.
+/// Events dropped by `DropOldest`/`DropNewest` are reported to the consumer
+/// through a `WatchEventKind::Overflow` event as soon as it fits.
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    sender: Sender<WatchEvent>,
+    policy: OverflowPolicy,
+    /// Waiting for room in the channel, only used by `DropOldest`
+    queued: Mutex<VecDeque<WatchEvent>>,
+    pending: Mutex<HashMap<Option<Arc<str>>, PendingEvents>>,
+    dropped: AtomicU64,
+    socket_clients: SocketClients,
+    source_id: Option<String>,
+    handle: WatcherHandle,
+    /// Gets the events instead of the channel, if set
+    handler: Option<SharedHandler>,
+}
+
+impl EventSender {
+    pub(crate) fn new(
+        sender: Sender<WatchEvent>,
+        policy: OverflowPolicy,
+        source_id: Option<String>,
+        handle: WatcherHandle,
+    ) -> Self {
+        Self {
+            sender,
+            policy,
+            queued: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            socket_clients: SocketClients::default(),
+            source_id,
+            handle,
+            handler: None,
+        }
+    }
+
+    /// Hands the events to `handler` instead of the channel,
+    /// so the overflow policy never comes into play
+    pub(crate) fn with_handler(mut self, handler: Box<dyn EventHandler>) -> Self {
+        self.set_handler(handler);
+        self
+    }
+
+    /// Like `with_handler`, replacing the handler if there was one
+    pub(crate) fn set_handler(&mut self, handler: Box<dyn EventHandler>) {
+        self.handler = Some(SharedHandler::new(handler));
+    }
+
+    /// Drops the handler, which closes the channel it forwards to, if any.
+    /// Events go to the channel again.
+    pub(crate) fn drop_handler(&mut self) {
+        self.handler = None;
+    }
+
+    pub(crate) fn source_id(&self) -> Option<&str> {
+        self.source_id.as_deref()
+    }
+
+    pub(crate) fn socket_clients(&self) -> &SocketClients {
+        &self.socket_clients
+    }
+
+    /// `group` is the coalescing group of the directory the event is from.
+    /// Fails with `ChannelClosed` once the receiver is dropped.
+    pub(crate) fn send(&self, event: WatchEvent, group: Option<&Arc<str>>) -> TuxDriveResult<()> {
+        // Socket clients get every event, whatever happens in the channel
+        self.socket_clients.broadcast(&event);
+        self.handle.counters().count_sent(&event.kind);
+        if let Some(handler) = &self.handler {
+            return handler.on_event(&event);
+        }
+        match self.policy {
+            OverflowPolicy::Block => self.send_blocking(event)?,
+            OverflowPolicy::DropNewest => {
+                self.report_overflow();
+                match self.sender.try_send(event) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        self.dropped.fetch_add(1, Ordering::SeqCst);
+                        self.handle.counters().count_dropped();
+                    }
+                    Err(TrySendError::Disconnected(_)) => return Err(TuxDriveError::ChannelClosed),
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let mut queued = self.queued.lock().unwrap();
+                queued.push_back(event);
+                let capacity = self.sender.capacity().unwrap_or(usize::MAX);
+                if queued.len() > capacity {
+                    queued.pop_front();
+                    self.dropped.fetch_add(1, Ordering::SeqCst);
+                    self.handle.counters().count_dropped();
+                }
+                self.send_queued(&mut queued)?;
+            }
+            OverflowPolicy::Coalesce => {
+                let mut pending = self.pending.lock().unwrap();
+                let pending = pending.entry(group.cloned()).or_default();
+                self.send_pending(pending);
+                if pending.is_empty() {
+                    match self.sender.try_send(event) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(event)) => {
+                            pending.insert(event.path.clone(), event);
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            return Err(TuxDriveError::ChannelClosed)
+                        }
+                    }
+                } else {
+                    // Keep the order, the new event must not overtake held back ones
+                    coalesce_into(pending, event);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for room in the channel, warning every `BLOCKED_WARN_INTERVAL`
+    /// that polling is stalled by a consumer not keeping up.
+    /// Gives up on the event once the watcher is asked to stop.
+    fn send_blocking(&self, event: WatchEvent) -> TuxDriveResult<()> {
+        let mut event = match self.sender.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(event)) => event,
+            Err(TrySendError::Disconnected(_)) => return Err(TuxDriveError::ChannelClosed),
+        };
+        self.handle.counters().count_blocked();
+        let (mut waited, mut since_warning) = (Duration::ZERO, Duration::ZERO);
+        loop {
+            if self.handle.is_stop_requested() {
+                log::warn!("Stopping with the event channel full, dropping an event");
+                self.handle.counters().count_dropped();
+                return Ok(());
+            }
+            match self.sender.send_timeout(event, BLOCKED_STOP_CHECK) {
+                Ok(()) => return Ok(()),
+                Err(SendTimeoutError::Timeout(ev)) => {
+                    event = ev;
+                    waited += BLOCKED_STOP_CHECK;
+                    since_warning += BLOCKED_STOP_CHECK;
+                    if since_warning >= BLOCKED_WARN_INTERVAL {
+                        since_warning = Duration::ZERO;
+                        log::warn!(
+                            "Event channel full for {}s, polling is stalled until events are received",
+                            waited.as_secs()
+                        );
+                    }
+                }
+                Err(SendTimeoutError::Disconnected(_)) => return Err(TuxDriveError::ChannelClosed),
+            }
+        }
+    }
+
+    /// Sends whatever the overflow policy held back, as far as there is room.
+    pub(crate) fn send_held_back(&self) {
+        // A closed channel is reported by the next `send`
+        let _ = self.send_queued(&mut self.queued.lock().unwrap());
+        self.report_overflow();
+        let mut pending = self.pending.lock().unwrap();
+        // The group held back the longest goes first
+        let mut groups = pending.values_mut().collect::<Vec<_>>();
+        groups.sort_by_key(|group| group.values().map(|event| event.id).min());
+        for group in groups {
+            self.send_pending(group);
+        }
+        pending.retain(|_, group| !group.is_empty());
+    }
+
+    /// Sends everything still held back, waiting for room in the channel
+    /// instead of keeping events for later. Used when no later poll is coming.
+    pub(crate) fn flush(&self) {
+        let dropped = self.dropped.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            let event = self.overflow_event(dropped);
+            let _ = self.sender.send(event);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let mut events = pending
+            .drain()
+            .flat_map(|(_, group)| group.into_values())
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.id);
+        let queued = mem::take(&mut *self.queued.lock().unwrap());
+        let events = queued.into_iter().chain(events);
+        for event in events {
+            // Nothing left to deliver to once the consumer is gone
+            if self.sender.send(event).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn send_pending(&self, pending: &mut PendingEvents) {
+        if pending.is_empty() {
+            return;
+        }
+        let mut events = pending.drain().map(|(_, event)| event).collect::<Vec<_>>();
+        events.sort_by_key(|event| event.id);
+        let mut events = events.into_iter();
+        while let Some(event) = events.next() {
+            if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+                pending.insert(event.path.clone(), event);
+                pending.extend(events.map(|event| (event.path.clone(), event)));
+                break;
+            }
+        }
+    }
+
+    /// Sends the events queued by `DropOldest`, as far as there is room.
+    /// The overflow report goes first: the dropped events come right before them.
+    fn send_queued(&self, queued: &mut VecDeque<WatchEvent>) -> TuxDriveResult<()> {
+        self.report_overflow();
+        if self.dropped.load(Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        while let Some(event) = queued.pop_front() {
+            match self.sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(event)) => {
+                    queued.push_front(event);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(TuxDriveError::ChannelClosed),
+            }
+        }
+        Ok(())
+    }
+
+    fn overflow_event(&self, dropped: u64) -> WatchEvent {
+        let kind = WatchEventKind::Overflow { dropped };
+        WatchEvent::new(PathBuf::new(), kind, false, 0, self.source_id())
+    }
+
+    fn report_overflow(&self) {
+        let dropped = self.dropped.swap(0, Ordering::SeqCst);
+        if dropped == 0 {
+            return;
+        }
+        let event = self.overflow_event(dropped);
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(dropped, Ordering::SeqCst);
+        }
+    }
+}
+
+fn coalesce_into(pending: &mut PendingEvents, event: WatchEvent) {
+    match pending.get_mut(&event.path) {
+        Some(old_event) => {
+            let keep_create = matches!(old_event.kind, WatchEventKind::Create)
+                && !matches!(event.kind, WatchEventKind::Delete);
+            old_event.id = event.id;
+            if !keep_create {
+                old_event.kind = event.kind;
+            }
+        }
+        None => {
+            pending.insert(event.path.clone(), event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::channel::Receiver;
+
+    use super::*;
+
+    fn sender(cap: usize, policy: OverflowPolicy) -> (EventSender, Receiver<WatchEvent>) {
+        let (tx, rx) = crossbeam::channel::bounded(cap);
+        (EventSender::new(tx, policy, None, WatcherHandle::new()), rx)
+    }
+
+    fn event(path: &str, kind: WatchEventKind, id: u64) -> WatchEvent {
+        WatchEvent::new(path, kind, false, id, None)
+    }
+
+    fn ids(rx: &Receiver<WatchEvent>) -> Vec<u64> {
+        rx.try_iter().map(|ev| ev.id).collect()
+    }
+
+    #[test]
+    pub fn block_waits_for_room() {
+        let (tx, rx) = sender(2, OverflowPolicy::Block);
+        let tx = Arc::new(tx);
+        let producer = {
+            let tx = Arc::clone(&tx);
+            std::thread::spawn(move || {
+                for id in 1..=10 {
+                    tx.send(event("/a", WatchEventKind::Written, id), None)
+                        .unwrap();
+                }
+            })
+        };
+        while tx.handle.counters().blocked() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!producer.is_finished());
+        let received = rx.iter().take(10).map(|ev| ev.id).collect::<Vec<_>>();
+        producer.join().unwrap();
+        assert_eq!(received, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn block_gives_up_on_stop() {
+        let (tx, rx) = sender(1, OverflowPolicy::Block);
+        let tx = Arc::new(tx);
+        let producer = {
+            let tx = Arc::clone(&tx);
+            std::thread::spawn(move || {
+                for id in 1..=3 {
+                    tx.send(event("/a", WatchEventKind::Written, id), None)
+                        .unwrap();
+                }
+            })
+        };
+        while tx.handle.counters().blocked() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        tx.handle.stop();
+        producer.join().unwrap();
+        assert_eq!(ids(&rx), vec![1]);
+        assert_eq!(tx.handle.counters().dropped(), 2);
+    }
+
+    #[test]
+    pub fn drop_newest_reports_overflow() {
+        let (tx, rx) = sender(2, OverflowPolicy::DropNewest);
+        for id in 1..=4 {
+            tx.send(event("/a", WatchEventKind::Written, id), None)
+                .unwrap();
+        }
+        assert_eq!(ids(&rx), vec![1, 2]);
+        tx.send_held_back();
+        let ev = rx.try_recv().unwrap();
+        assert!(matches!(ev.kind, WatchEventKind::Overflow { dropped: 2 }));
+    }
+
+    #[test]
+    pub fn drop_oldest_keeps_latest() {
+        let (tx, rx) = sender(2, OverflowPolicy::DropOldest);
+        for id in 1..=6 {
+            tx.send(event("/a", WatchEventKind::Written, id), None)
+                .unwrap();
+        }
+        assert_eq!(ids(&rx), vec![1, 2]);
+        tx.send_held_back();
+        let ev = rx.try_recv().unwrap();
+        assert!(matches!(ev.kind, WatchEventKind::Overflow { dropped: 2 }));
+        assert_eq!(ids(&rx), vec![5]);
+        tx.send_held_back();
+        assert_eq!(ids(&rx), vec![6]);
+    }
+
+    #[test]
+    pub fn drop_oldest_notices_dropped_receiver() {
+        let (tx, rx) = sender(2, OverflowPolicy::DropOldest);
+        drop(rx);
+        let res = tx.send(event("/a", WatchEventKind::Written, 1), None);
+        assert!(matches!(res, Err(TuxDriveError::ChannelClosed)));
+    }
+
+    #[test]
+    pub fn coalesce_holds_back_per_path() {
+        let (tx, rx) = sender(1, OverflowPolicy::Coalesce);
+        tx.send(event("/a", WatchEventKind::Written, 1), None)
+            .unwrap();
+        tx.send(event("/b", WatchEventKind::Create, 2), None)
+            .unwrap();
+        tx.send(event("/c", WatchEventKind::Written, 3), None)
+            .unwrap();
+        tx.send(event("/b", WatchEventKind::Written, 4), None)
+            .unwrap();
+        tx.send(event("/c", WatchEventKind::Chmod, 5), None)
+            .unwrap();
+        assert_eq!(ids(&rx), vec![1]);
+
+        tx.send_held_back();
+        let ev = rx.try_recv().unwrap();
+        assert_eq!((ev.path.to_str().unwrap(), ev.id), ("/b", 4));
+        assert!(matches!(ev.kind, WatchEventKind::Create));
+
+        tx.send_held_back();
+        let ev = rx.try_recv().unwrap();
+        assert_eq!((ev.path.to_str().unwrap(), ev.id), ("/c", 5));
+        assert!(matches!(ev.kind, WatchEventKind::Chmod));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn block_loses_nothing() {
+        let (tx, rx) = sender(1, OverflowPolicy::Block);
+        let consumer =
+            std::thread::spawn(move || (0..10).map(|_| rx.recv().unwrap().id).collect::<Vec<_>>());
+        for id in 1..=10 {
+            tx.send(event("/a", WatchEventKind::Written, id), None)
+                .unwrap();
+        }
+        assert_eq!(consumer.join().unwrap(), (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn flush_sends_everything_held_back() {
+        let (tx, rx) = sender(1, OverflowPolicy::Coalesce);
+        for (id, path) in [(1, "/a"), (2, "/b"), (3, "/c")] {
+            tx.send(event(path, WatchEventKind::Written, id), None)
+                .unwrap();
+        }
+        let consumer =
+            std::thread::spawn(move || (0..3).map(|_| rx.recv().unwrap().id).collect::<Vec<_>>());
+        tx.flush();
+        assert_eq!(consumer.join().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    pub fn coalesce_groups_hold_back_separately() {
+        let (tx, rx) = sender(2, OverflowPolicy::Coalesce);
+        let group_g: Arc<str> = Arc::from("g");
+        let group_h: Arc<str> = Arc::from("h");
+        tx.send(event("/a", WatchEventKind::Written, 1), None)
+            .unwrap();
+        tx.send(event("/g/a", WatchEventKind::Written, 2), Some(&group_g))
+            .unwrap();
+        tx.send(event("/g/b", WatchEventKind::Written, 3), Some(&group_g))
+            .unwrap();
+        tx.send(event("/b", WatchEventKind::Written, 4), None)
+            .unwrap();
+        assert_eq!(rx.try_recv().unwrap().id, 1);
+
+        // Group h has nothing held back, so it isn't stuck behind the others
+        tx.send(event("/h/a", WatchEventKind::Written, 5), Some(&group_h))
+            .unwrap();
+        assert_eq!(ids(&rx), vec![2, 5]);
+
+        tx.send_held_back();
+        assert_eq!(ids(&rx), vec![3, 4]);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use crate::config::PathConfig;
+use crate::error::{TuxDriveError, TuxDriveResult};
+
+use super::summary::RootSummary;
+use super::{hash_contents, probe_path, update_times, SendInfo, WatchEventKind, Watcher};
+
+impl Watcher {
+    /// Watches the path of `config`, a directory or a single file, with its
+    /// options. Unlike with `add_directory_with_options`, a later
+    /// `set_directories` with the same config leaves the root alone.
+    pub fn add_path_config(&mut self, config: &PathConfig) -> TuxDriveResult<()> {
+        let path = config.path().canonicalize()?;
+        let options = config.add_options()?;
+        if path.is_file() {
+            self.add_file_with_options(&path, options)?;
+        } else {
+            self.add_directory_with_options(&path, config.recursive(), options)?;
+        }
+        self.root_configs.insert(path, config.clone());
+        Ok(())
+    }
+
+    /// Replaces the watched directories with those of `configs`, e.g. on a
+    /// config reload. Roots no longer present stop being watched, without
+    /// any event: what they hold is still there. Every path of a new root
+    /// gets a `Create`. Roots present before and after are left alone if
+    /// their config is the same. Otherwise they keep their state: only the
+    /// paths their new options add or drop are reported, and changes since
+    /// the last poll still show on the next one.
+    /// On error, nothing changes.
+    pub fn set_directories(&mut self, configs: &[PathConfig]) -> TuxDriveResult<()> {
+        let mut wanted = HashMap::new();
+        for config in configs {
+            wanted.insert(config.path().canonicalize()?, config);
+        }
+        // The new and changed roots are scanned aside, so that a failure
+        // leaves the current ones as they are
+        let mut forest = self.forest.sharing_names();
+        let mut summaries = HashMap::new();
+        for (path, config) in &wanted {
+            let watched = self.forest.has_root(path) || self.summaries.contains_key(path);
+            if watched && self.root_configs.get(path) == Some(*config) {
+                continue;
+            }
+            let options = config
+                .add_options()?
+                .skip_virtual_fs_unless_set(self.skip_virtual_fs);
+            if let Some(timeout) = self.probe_timeout {
+                probe_path(path, timeout)?;
+            }
+            if path.is_file() {
+                forest.add_file(path, options)?;
+                continue;
+            }
+            if !path.is_dir() {
+                return Err(TuxDriveError::NotDirectory(path.clone()));
+            }
+            if options.is_summary_only() {
+                let summary = match self.summaries.get(path) {
+                    // The old one is kept, it knows the last digest
+                    Some(_) => None,
+                    None => Some(RootSummary::new(path, config.recursive(), options)?),
+                };
+                summaries.insert(path.clone(), summary);
+            } else if config.recursive() {
+                forest.add_dir_recursively(path, options)?;
+            } else {
+                forest.add_dir_non_recursively(path, options)?;
+            }
+        }
+        update_times(&mut forest)?;
+        hash_contents(&mut forest)?;
+
+        // All set, now apply the differences
+        let dropped = self
+            .forest
+            .trees()
+            .map(|tree| tree.root_path())
+            .chain(self.summaries.keys().cloned())
+            .filter(|root_path| !wanted.contains_key(root_path))
+            .collect::<Vec<_>>();
+        for root_path in dropped {
+            self.forest.take_tree(&root_path);
+            self.summaries.remove(&root_path);
+            if let Some(watches) = self.inotify.as_mut() {
+                watches.unwatch_below(&root_path);
+            }
+        }
+        self.root_configs = wanted
+            .into_iter()
+            .map(|(path, config)| (path, config.clone()))
+            .collect();
+        let mut new_roots = Vec::new();
+        let mut new_summary_roots = Vec::new();
+        for (root_path, summary) in summaries {
+            // Was a tree before
+            let was_tree = self.forest.take_tree(&root_path).is_some();
+            if let Some(summary) = summary {
+                self.summaries.insert(root_path.clone(), summary);
+                new_summary_roots.push(root_path);
+            } else if was_tree {
+                new_summary_roots.push(root_path);
+            }
+        }
+        for (root_path, mut tree) in forest.into_trees() {
+            self.summaries.remove(&root_path);
+            let mut old_tree = match self.forest.take_tree(&root_path) {
+                Some(old_tree) => old_tree,
+                None => {
+                    self.forest.insert_tree(tree);
+                    new_roots.push(root_path);
+                    continue;
+                }
+            };
+            let group = tree.options().group_name().cloned();
+            let send_info = SendInfo {
+                sender: &self.sender,
+                id_gen: &self.id_gen,
+                debouncer: self.debouncer.as_ref(),
+                group: group.as_ref(),
+                changes: None,
+            };
+            for (path, is_dir) in tree.paths() {
+                match old_tree.node_info_mut(&path) {
+                    Some((old_info, _)) => *tree.node_info_mut(&path).unwrap().0 = *old_info,
+                    None => send_info.send_event(&path, WatchEventKind::Create, is_dir)?,
+                }
+            }
+            for (path, is_dir) in old_tree.paths() {
+                if tree.node_info_mut(&path).is_none() {
+                    send_info.send_event(&path, WatchEventKind::Delete, is_dir)?;
+                }
+            }
+            self.forest.insert_tree(tree);
+        }
+
+        let send_info = SendInfo {
+            sender: &self.sender,
+            id_gen: &self.id_gen,
+            debouncer: self.debouncer.as_ref(),
+            group: None,
+            changes: None,
+        };
+        for root_path in new_summary_roots {
+            send_info.send_event(&root_path, WatchEventKind::Create, true)?;
+        }
+        for root_path in new_roots {
+            self.send_initial_events(&root_path)?;
+        }
+        self.sender.send_held_back();
+        self.watch_forest()
+    }
+
+    /// Applies the directories passed to `WatcherHandle::set_directories`
+    pub(super) fn apply_new_directories(&mut self) {
+        if let Some((configs, applied)) = self.handle.take_new_directories() {
+            let res = self.set_directories(&configs);
+            if let Err(err) = &res {
+                log::error!("Could not change the watched directories: {}", err);
+            }
+            applied.call(&res);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::super::CheckPhases;
+    use super::*;
+
+    fn kinds_by_path(
+        events: &crossbeam::channel::Receiver<super::super::WatchEvent>,
+    ) -> Vec<(String, PathBuf)> {
+        let mut kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    }
+
+    #[test]
+    pub fn set_directories_swaps_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (kept, dropped, added) = (root.join("kept"), root.join("dropped"), root.join("added"));
+        for path in [&kept, &dropped, &added] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(kept.join("file"), b"old").unwrap();
+        fs::write(kept.join("skip.log"), b"").unwrap();
+        fs::write(added.join("file"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&kept, true).unwrap();
+        watcher.add_directory(&dropped, true).unwrap();
+
+        fs::write(kept.join("file"), b"written before the reload").unwrap();
+        let configs = [
+            PathConfig::new(&kept, true).with_ignore(vec!["*.log".to_string()]),
+            PathConfig::new(&added, true),
+        ];
+        watcher.set_directories(&configs).unwrap();
+        // The dropped root is still there, only no longer watched
+        assert_eq!(
+            kinds_by_path(&events),
+            vec![
+                ("Create".to_string(), added.clone()),
+                ("Create".to_string(), added.join("file")),
+                ("Delete".to_string(), kept.join("skip.log")),
+            ]
+        );
+
+        // The kept root still knows the old state
+        fs::write(dropped.join("file"), b"").unwrap();
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        assert_eq!(
+            kinds_by_path(&events),
+            vec![("Written".to_string(), kept.join("file"))]
+        );
+    }
+
+    #[test]
+    pub fn same_config_leaves_root_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        let config = PathConfig::new(&root, true);
+        watcher.add_path_config(&config).unwrap();
+
+        fs::write(root.join("file"), b"").unwrap();
+        // Not scanned again, so the new file is left to the next poll
+        watcher.set_directories(&[config]).unwrap();
+        assert!(events.try_iter().next().is_none());
+        watcher.poll_phases(CheckPhases::ALL).unwrap();
+        assert_eq!(
+            kinds_by_path(&events),
+            vec![("Create".to_string(), root.join("file"))]
+        );
+    }
+
+    #[test]
+    pub fn handle_swaps_roots_while_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (old, new) = (root.join("old"), root.join("new"));
+        for path in [&old, &new] {
+            fs::create_dir(path).unwrap();
+        }
+        let (mut watcher, events) = super::super::WatcherBuilder::new()
+            .poll_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        watcher.add_directory(&old, true).unwrap();
+        let handle = watcher.handle();
+        let poller = std::thread::spawn(move || watcher.start_polling());
+
+        let (applied_tx, applied) = crossbeam::channel::bounded(1);
+        handle.set_directories_then(vec![PathConfig::new(&new, true)], move |res| {
+            applied_tx.send(res.is_ok()).unwrap();
+        });
+        let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            (format!("{:?}", event.kind), event.path),
+            ("Create".to_string(), new.clone())
+        );
+        assert!(applied.recv_timeout(Duration::from_secs(10)).unwrap());
+        fs::write(new.join("file"), b"").unwrap();
+        fs::write(old.join("file"), b"").unwrap();
+        let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.stop();
+        poller.join().unwrap().unwrap();
+        assert_eq!(event.path, new.join("file"));
+    }
+
+    #[test]
+    pub fn set_directories_error_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let configs = [PathConfig::new(Path::new("/does/not/exist"), true)];
+        assert!(watcher.set_directories(&configs).is_err());
+        assert_eq!(watcher.forest.len(), 1);
+        assert!(events.try_iter().next().is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::TuxDriveResult;
+
+use super::WatchEvent;
+
+/// A client that doesn't take an event within this long is disconnected,
+/// so a stuck client can't stall the polling.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Clients connected through `Watcher::serve_unix_socket`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SocketClients {
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+impl SocketClients {
+    /// Binds `path` and accepts clients on a separate thread.
+    pub(crate) fn serve(&self, path: &Path) -> TuxDriveResult<()> {
+        let listener = UnixListener::bind(path)?;
+        let clients = Arc::clone(&self.clients);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+                            log::warn!("Dropping socket client: {}", err);
+                            continue;
+                        }
+                        clients.lock().unwrap().push(stream);
+                    }
+                    Err(err) => log::warn!("Failed to accept socket client: {}", err),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Writes the event as a line of JSON to every client.
+    /// Clients which fail to take it (disconnected or stuck) are dropped.
+    pub(crate) fn broadcast(&self, event: &WatchEvent) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return;
+        }
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(err) => {
+                log::warn!("Failed to serialize event {:?}: {}", event, err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        clients.retain_mut(|client| client.write_all(&line).is_ok());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+    use crate::watcher::WatchEventKind;
+
+    #[test]
+    pub fn events_reach_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("events.sock");
+        let clients = SocketClients::default();
+        clients.serve(&socket_path).unwrap();
+
+        let client = UnixStream::connect(&socket_path).unwrap();
+        while clients.len() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        clients.broadcast(&WatchEvent::new(
+            "/foo/bar",
+            WatchEventKind::Written,
+            false,
+            7,
+            Some("host"),
+        ));
+        let mut line = String::new();
+        BufReader::new(&client).read_line(&mut line).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["path"], "/foo/bar");
+        assert_eq!(value["id"], 7);
+        assert_eq!(value["kind"], "Written");
+        assert_eq!(value["is_dir"], false);
+        assert_eq!(value["source_id"], "host");
+
+        // A disconnected client is dropped on the next event
+        drop(client);
+        clients.broadcast(&WatchEvent::new(
+            "/foo/bar",
+            WatchEventKind::Written,
+            false,
+            8,
+            Some("host"),
+        ));
+        clients.broadcast(&WatchEvent::new(
+            "/foo/bar",
+            WatchEventKind::Written,
+            false,
+            9,
+            Some("host"),
+        ));
+        assert_eq!(clients.len(), 0);
+    }
+}
@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::display_path;
+use crate::error::TuxDriveResult;
+use crate::forest::PathForest;
+
+use super::{ModTimeInfo, SendInfo, Watcher};
+
+/// Where and how often `start_polling` saves the state
+pub(super) struct StateFile {
+    path: PathBuf,
+    interval: Duration,
+    last_saved: Instant,
+}
+
+impl Watcher {
+    /// Saves the state of the watched trees to `path`, for `restore_state`
+    /// to pick up from on the next start. The file is replaced as a whole,
+    /// a crash midway leaves the previous one.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> TuxDriveResult<()> {
+        self.forest.save(path)
+    }
+
+    /// Has `start_polling` save the state to `path` after a poll once
+    /// `interval` passed since the last save, and once more on returning.
+    /// A failed save is logged, and tried again after the next poll.
+    pub fn save_state_every<P: AsRef<Path>>(&mut self, path: P, interval: Duration) {
+        self.state_file = Some(StateFile {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            last_saved: Instant::now(),
+        });
+    }
+
+    /// Saves the state as set with `save_state_every`, if it is time to,
+    /// or anyway with `force`
+    pub(super) fn save_state_if_due(&mut self, force: bool) {
+        let state_file = match &self.state_file {
+            Some(state_file) => state_file,
+            None => return,
+        };
+        if !force && state_file.last_saved.elapsed() < state_file.interval {
+            return;
+        }
+        match self.forest.save(&state_file.path) {
+            Ok(()) => {
+                if let Some(state_file) = self.state_file.as_mut() {
+                    state_file.last_saved = Instant::now();
+                }
+            }
+            Err(err) => log::warn!(
+                "Could not save the state to {}: {}",
+                display_path(&state_file.path),
+                err
+            ),
+        }
+    }
+
+    /// Reports what changed since `save_state` wrote `path`, e.g. while the
+    /// daemon was down: a `Create` for each new path, a `Delete` for each
+    /// path gone, and `Written`, `Truncated`, `Chmod` or `Chown` for the
+    /// files modified, moves being paired up as when polling.
+    /// Call this after adding the directories. Roots not in the saved
+    /// state are left alone.
+    pub fn restore_state<P: AsRef<Path>>(&mut self, path: P) -> TuxDriveResult<()> {
+        let mut saved_trees = PathForest::<ModTimeInfo>::load(path)?.into_trees();
+        for tree in self.forest.trees_mut() {
+            let mut saved_tree = match saved_trees.remove(&tree.root_path()) {
+                Some(saved_tree) => saved_tree,
+                None => continue,
+            };
+            let group = tree.options().group_name().cloned();
+            let changes = RefCell::new(Vec::new());
+            let send_info = SendInfo {
+                sender: &self.sender,
+                id_gen: &self.id_gen,
+                debouncer: self.debouncer.as_ref(),
+                group: group.as_ref(),
+                changes: Some(&changes),
+            };
+            for (path, is_dir) in tree.paths() {
+                let info = *tree.node_info_mut(&path).unwrap().0;
+                match saved_tree.node_info_mut(&path) {
+                    Some((saved_info, saved_is_dir)) if saved_is_dir == is_dir => {
+                        // As when polling, directories only report their entries
+                        if !is_dir {
+                            // Reads are no change worth catching up on
+                            info.send_modified(saved_info, &path, &send_info, false)?;
+                        }
+                    }
+                    Some((saved_info, saved_is_dir)) => {
+                        send_info.send_deleted(&path, saved_info, saved_is_dir)?;
+                        send_info.send_created(path)?;
+                    }
+                    None => send_info.send_created(path)?,
+                }
+            }
+            for (path, is_dir) in saved_tree.paths() {
+                if tree.node_info_mut(&path).is_some() {
+                    continue;
+                }
+                // Only the topmost path gone, as when polling
+                let parent_kept = path
+                    .parent()
+                    .is_some_and(|parent| tree.node_info_mut(parent).is_some());
+                if parent_kept {
+                    let saved_info = *saved_tree.node_info_mut(&path).unwrap().0;
+                    send_info.send_deleted(&path, &saved_info, is_dir)?;
+                }
+            }
+            send_info.send_changes(self.strict_tree_ordering)?;
+        }
+        self.sender.send_held_back();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::super::WatchEventKind;
+    use super::*;
+
+    #[test]
+    pub fn changes_while_offline_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("root");
+        let state_path = dir.path().join("state.json");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        for name in ["modified", "removed", "kept"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        fs::write(root.join("sub").join("inner"), b"").unwrap();
+        let (mut watcher, _events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        watcher.save_state(&state_path).unwrap();
+        drop(watcher);
+
+        fs::write(root.join("modified"), b"modified while offline").unwrap();
+        fs::remove_file(root.join("removed")).unwrap();
+        fs::remove_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("added"), b"").unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        watcher.restore_state(&state_path).unwrap();
+
+        let mut kinds = events
+            .try_iter()
+            .map(|event| (format!("{:?}", event.kind), event.path))
+            .collect::<Vec<(String, PathBuf)>>();
+        kinds.sort();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Create".to_string(), root.join("added")),
+                ("Delete".to_string(), root.join("removed")),
+                ("Delete".to_string(), root.join("sub")),
+                ("Written".to_string(), root.join("modified")),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn moves_while_offline_are_paired() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("root");
+        let state_path = dir.path().join("state.json");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("old"), b"content").unwrap();
+        let (mut watcher, _events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        watcher.save_state(&state_path).unwrap();
+        drop(watcher);
+
+        fs::rename(root.join("old"), root.join("new")).unwrap();
+        let (mut watcher, events) = Watcher::new(Duration::from_secs(1)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        watcher.restore_state(&state_path).unwrap();
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0].kind,
+            WatchEventKind::Moved { from, to } if *from == root.join("old") && *to == root.join("new")
+        ));
+    }
+
+    #[test]
+    pub fn state_saved_while_polling() {
+        use std::thread;
+
+        use crate::forest::DirectoryAddOptions;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("root");
+        let state_path = dir.path().join("state.json");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("file"), b"content").unwrap();
+        let (mut watcher, _events) = Watcher::new(Duration::from_millis(10)).unwrap();
+        let options = DirectoryAddOptions::new().skip_unchanged_writes(true);
+        watcher
+            .add_directory_with_options(&root, true, options)
+            .unwrap();
+        watcher.save_state_every(&state_path, Duration::ZERO);
+        let handle = watcher.handle();
+        let polling = thread::spawn(move || watcher.start_polling());
+
+        while !state_path.exists() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.stop();
+        polling.join().unwrap().unwrap();
+        let saved = fs::read_to_string(&state_path).unwrap();
+        // Digests are kept as hex
+        let digest = blake3::hash(b"content").to_hex();
+        assert!(saved.contains(&format!("\"{}\"", digest)), "{}", saved);
+        assert!(PathForest::<ModTimeInfo>::load(&state_path).is_ok());
+    }
+}
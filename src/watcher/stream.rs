@@ -0,0 +1,110 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::error::{TuxDriveError, TuxDriveResult};
+
+use super::handler::EventHandler;
+use super::{WatchEvent, Watcher};
+
+/// The events of a watcher, for async code. Ends once `start_polling`
+/// returns, or the watcher is dropped.
+#[derive(Debug)]
+pub struct EventStream {
+    events: UnboundedReceiver<WatchEvent>,
+}
+
+impl EventStream {
+    /// The next event, `None` once the stream ended
+    pub async fn recv(&mut self) -> Option<WatchEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for EventStream {
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WatchEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Forwards the events to an async channel. The watcher stops once the
+/// receiver is dropped.
+impl EventHandler for UnboundedSender<WatchEvent> {
+    fn on_event(&mut self, event: &WatchEvent) -> TuxDriveResult<()> {
+        if self.is_closed() {
+            return Err(TuxDriveError::ChannelClosed);
+        }
+        self.send(event.clone())
+            .map_err(|_| TuxDriveError::ChannelClosed)
+    }
+}
+
+impl Watcher {
+    /// Delivers the events to the returned stream from now on, instead of
+    /// the channel or the handler the watcher was built with. Polling
+    /// stays on the thread calling `start_polling`, and never waits for
+    /// the stream: `channel_bounded` and `overflow_policy` have no effect.
+    pub fn event_stream(&mut self) -> EventStream {
+        let (send, events) = mpsc::unbounded_channel();
+        self.sender.set_handler(Box::new(send));
+        EventStream { events }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn stream_ends_with_the_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, _) = Watcher::new(Duration::from_millis(50)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        let mut events = watcher.event_stream();
+        let handle = watcher.handle();
+        let polling = thread::spawn(move || {
+            let res = watcher.start_polling();
+            (watcher, res)
+        });
+
+        fs::write(root.join("a"), b"").unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        let mut created = Vec::new();
+        while created.len() < 2 {
+            let event = events.recv().await.unwrap();
+            if event.kind.name() == "create" {
+                created.push(event.path);
+            }
+        }
+        created.sort();
+        assert_eq!(created, vec![root.join("a"), root.join("sub")]);
+
+        handle.stop();
+        // The watcher is only dropped once joined
+        while events.recv().await.is_some() {}
+        let (_watcher, res) = polling.join().unwrap();
+        res.unwrap();
+    }
+
+    #[test]
+    pub fn dropped_stream_stops_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let (mut watcher, _) = Watcher::new(Duration::from_millis(10)).unwrap();
+        watcher.add_directory(&root, true).unwrap();
+        drop(watcher.event_stream());
+
+        fs::write(root.join("a"), b"").unwrap();
+        // Returns on its own, nothing asked it to stop
+        watcher.start_polling().unwrap();
+    }
+}
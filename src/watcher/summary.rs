@@ -0,0 +1,118 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, Metadata};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::forest::DirectoryAddOptions;
+
+/// A root watched in summary-only mode: no per-path state is kept,
+/// only a digest over the whole tree, recomputed on every poll.
+#[derive(Debug)]
+pub(crate) struct RootSummary {
+    recursive: bool,
+    options: DirectoryAddOptions,
+    digest: u64,
+}
+
+impl RootSummary {
+    pub(crate) fn new(
+        root_path: &Path,
+        recursive: bool,
+        options: DirectoryAddOptions,
+    ) -> io::Result<Self> {
+        let digest = tree_digest(root_path, recursive, &options)?;
+        Ok(Self {
+            recursive,
+            options,
+            digest,
+        })
+    }
+
+    /// Recomputes the digest, returning whether anything changed
+    pub(crate) fn update(&mut self, root_path: &Path) -> io::Result<bool> {
+        let digest = tree_digest(root_path, self.recursive, &self.options)?;
+        let changed = digest != self.digest;
+        self.digest = digest;
+        Ok(changed)
+    }
+}
+
+/// Combines path, size, mtime and ctime of everything below `root_path`.
+/// Entries are combined in an order-independent way, as directory listing
+/// order isn't stable.
+fn tree_digest(
+    root_path: &Path,
+    recursive: bool,
+    options: &DirectoryAddOptions,
+) -> io::Result<u64> {
+    let mut digest = entry_hash(root_path, &fs::metadata(root_path)?);
+    let mut dirs = vec![root_path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // Gone or unreadable since it was listed, which its parent's
+            // times show already
+            Err(err) if dir != root_path && is_transient(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        options.load_gitignore(&dir);
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if options.is_ignored(root_path, &path, metadata.is_dir()) {
+                continue;
+            }
+            if metadata.is_dir() {
+                if options.skips_dir(&path) {
+                    continue;
+                }
+                if recursive {
+                    dirs.push(path.clone());
+                }
+            } else if !metadata.is_file() || !options.tracks_file(&path) {
+                continue;
+            }
+            digest = digest.wrapping_add(entry_hash(&path, &metadata));
+        }
+    }
+    Ok(digest)
+}
+
+fn entry_hash(path: &Path, metadata: &Metadata) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    metadata.mtime().hash(&mut hasher);
+    metadata.mtime_nsec().hash(&mut hasher);
+    metadata.ctime().hash(&mut hasher);
+    metadata.ctime_nsec().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::NotFound || err.kind() == ErrorKind::PermissionDenied
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn digest_changes_with_deep_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        fs::write(root.join("a").join("b").join("file"), b"old").unwrap();
+
+        let mut summary = RootSummary::new(root, true, DirectoryAddOptions::new()).unwrap();
+        assert!(!summary.update(root).unwrap());
+        fs::write(root.join("a").join("b").join("file"), b"new content").unwrap();
+        assert!(summary.update(root).unwrap());
+        assert!(!summary.update(root).unwrap());
+    }
+}
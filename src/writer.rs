@@ -0,0 +1,165 @@
+use std::fs;
+use std::path::Path;
+
+use nix::sys::stat::{self, UtimensatFlags};
+use nix::sys::statvfs;
+use nix::sys::time::TimeSpec;
+
+use crate::error::{TuxDriveError, TuxDriveResult};
+use crate::reader::{FileMeta, Timestamp};
+
+/// Writes file contents read by the `FileReader` into a mirror.
+#[derive(Debug, Default)]
+pub struct FileWriter {
+    preserve_times: bool,
+    min_free_bytes: u64,
+}
+
+impl FileWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the source's atime and mtime after writing.
+    /// Without this every mirrored file gets the time of the write.
+    /// Default: false
+    pub fn preserve_times(mut self, preserve_times: bool) -> Self {
+        self.preserve_times = preserve_times;
+        self
+    }
+
+    /// Refuse writes that would leave less than this many bytes free
+    /// on the mirror's filesystem.
+    /// Default: 0 (no check)
+    pub fn min_free_bytes(mut self, min_free_bytes: u64) -> Self {
+        self.min_free_bytes = min_free_bytes;
+        self
+    }
+
+    /// Writes `data` to `path`, replacing any previous content.
+    /// `meta` is the source file's metadata, used when preserving times.
+    pub fn write_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        data: &[u8],
+        meta: Option<&FileMeta>,
+    ) -> TuxDriveResult<()> {
+        let path = path.as_ref();
+        if self.min_free_bytes > 0 {
+            self.check_free_space(path, data.len() as u64)?;
+        }
+        fs::write(path, data).map_err(|err| TuxDriveError::io_at(path, err))?;
+        if let (true, Some(meta)) = (self.preserve_times, meta) {
+            set_times(path, meta)?;
+        }
+        Ok(())
+    }
+
+    fn check_free_space(&self, path: &Path, len: u64) -> TuxDriveResult<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let fs_stat = statvfs::statvfs(dir).map_err(|err| TuxDriveError::nix_at(dir, err))?;
+        let available = fs_stat.blocks_available() as u64 * fs_stat.fragment_size() as u64;
+        // Replacing a file gives its old blocks back
+        let old_len = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        let available = available + old_len;
+        if available < len.saturating_add(self.min_free_bytes) {
+            return Err(TuxDriveError::InsufficientSpace(path.to_path_buf()));
+        }
+        Ok(())
+    }
+}
+
+fn set_times(path: &Path, meta: &FileMeta) -> TuxDriveResult<()> {
+    stat::utimensat(
+        None,
+        path,
+        &to_timespec(&meta.atime),
+        &to_timespec(&meta.mtime),
+        UtimensatFlags::FollowSymlink,
+    )
+    .map_err(|err| TuxDriveError::nix_at(path, err))?;
+    Ok(())
+}
+
+fn to_timespec(time: &Timestamp) -> TimeSpec {
+    TimeSpec::from(nix::libc::timespec {
+        tv_sec: time.secs,
+        tv_nsec: time.nsecs,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn times_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, b"content").unwrap();
+        let atime = to_timespec(&Timestamp {
+            secs: 1_000_000,
+            nsecs: 123,
+        });
+        let mtime = to_timespec(&Timestamp {
+            secs: 2_000_000,
+            nsecs: 456,
+        });
+        stat::utimensat(None, &src, &atime, &mtime, UtimensatFlags::FollowSymlink).unwrap();
+
+        let src_meta = FileMeta::from(&stat::stat(&src).unwrap());
+        FileWriter::new()
+            .preserve_times(true)
+            .write_file(&dst, b"content", Some(&src_meta))
+            .unwrap();
+        let dst_meta = FileMeta::from(&stat::stat(&dst).unwrap());
+        assert_eq!(dst_meta, src_meta);
+        assert_eq!(dst_meta.mtime.secs, 2_000_000);
+        assert_eq!(dst_meta.atime.nsecs, 123);
+    }
+
+    #[test]
+    pub fn failed_write_names_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("missing").join("dst");
+        let err = FileWriter::new().write_file(&dst, b"", None).unwrap_err();
+        assert!(matches!(err, TuxDriveError::IoAt { path, .. } if path == dst));
+    }
+
+    #[test]
+    pub fn times_not_preserved_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("dst");
+        let meta = FileMeta {
+            size: 7,
+            ..FileMeta::default()
+        };
+        FileWriter::new()
+            .write_file(&dst, b"content", Some(&meta))
+            .unwrap();
+        let dst_meta = FileMeta::from(&stat::stat(&dst).unwrap());
+        assert_ne!(dst_meta.mtime, meta.mtime);
+        assert_eq!(fs::read(&dst).unwrap(), b"content");
+    }
+
+    #[test]
+    pub fn refuses_write_below_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("dst");
+        let res = FileWriter::new()
+            .min_free_bytes(u64::MAX)
+            .write_file(&dst, b"content", None);
+        assert!(matches!(res, Err(TuxDriveError::InsufficientSpace(_))));
+        assert!(!dst.exists());
+
+        FileWriter::new()
+            .min_free_bytes(1)
+            .write_file(&dst, b"content", None)
+            .unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"content");
+    }
+}